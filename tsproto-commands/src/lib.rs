@@ -1,7 +1,13 @@
 extern crate chrono;
+extern crate tsproto;
 
 use chrono::{DateTime, Duration, Utc};
 
+pub use tsproto::handler_data::MoveReason;
+// The ids of clients and channels are also used in voice packets, so they are
+// defined in tsproto.
+pub use tsproto::packets::{ChannelId, ClientId};
+
 pub mod errors;
 pub mod permissions;
 pub mod structs;
@@ -10,19 +16,9 @@ pub mod structs;
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct ConnectionId(u16);
 
-/// A `ClientId` identifies a client which is connected to a server.
-///
-/// Every client that we see on a server has a `ClientId`, even our own
-/// connection.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct ClientId(u16);
-
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct ClientDbId(u64);
 
-/// A `ChannelId` identifies a channel.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct ChannelId(u64);
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct ServerGroupId(u64);
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -88,30 +84,6 @@ pub enum CodecEncryptionMode {
 	ForcedOn,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum MoveReason {
-	/// No reason data
-	None,
-	/// Has invoker
-	Moved,
-	/// No reason data
-	Subscription,
-	LostConnection,
-	/// Has invoker
-	KickChannel,
-	/// Has invoker
-	KickServer,
-	/// Has invoker, bantime
-	KickServerBan,
-	Serverstop,
-	Clientdisconnect,
-	/// No reason data
-	Channelupdate,
-	/// Has invoker
-	Channeledit,
-	ClientdisconnectServerShutdown,
-}
-
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ClientType {
 	Normal,
@@ -4,6 +4,12 @@ use std::io::{self, Cursor, Result};
 use std::io::prelude::*;
 use std::path::Path;
 
+/// The code that reads the rest of the input into a `Vec`.
+const READ_VEC: &str = "{\n\tlet mut res = Vec::new();\n\tif let Err(error) = \
+                        r.read_to_end(&mut res) \
+                        {\n\t\tErr(Error::from(error))\n\t} else \
+                        {\n\t\tOk(res)\n\t}\n}";
+
 #[derive(Debug)]
 struct Struct {
     fields: Vec<Field>,
@@ -62,6 +68,14 @@ impl Struct {
         Ok(Self { fields })
     }
 
+    /// The last field, if it takes the rest of the input as a `Vec`.
+    fn get_payload(&self) -> Option<&Field> {
+        self.fields.last().and_then(|f| match f.content {
+            FieldType::Array(ref s) if s.starts_with("Vec<") => Some(f),
+            _ => None,
+        })
+    }
+
     fn get_size(&self) -> String {
         let mut res = String::new();
         for f in &self.fields {
@@ -308,6 +322,38 @@ impl Enum {
         self.write_raw_read_impl(&mut buf, f, before)?;
         writeln!(w, "{}\t}}\n", indent(str::from_utf8(&buf).unwrap(), 2))?;
 
+        if self.has_payload() {
+            writeln!(
+                w,
+                "\t/// Read from a buffer and move the rest of the buffer into \
+                 the trailing\n\t/// `Vec`, so it does not need to be \
+                 copied.\n\t///\n\t/// Only possibilities, which end with \
+                 such a `Vec`, are tried."
+            )?;
+            write!(w, "\tpub fn read_buf(")?;
+            if let Some(before) = before {
+                write!(
+                    w,
+                    "{}: &{}, ",
+                    to_snake_case(&before.name),
+                    before.get_type()
+                )?;
+            }
+            // Only drained directly if we have an inlined struct
+            let direct = self.possibilities.iter().any(|p| match p.content {
+                FieldType::Struct(ref s) => s.get_payload().is_some(),
+                _ => false,
+            });
+            let mut_s = if direct { "mut " } else { "" };
+            writeln!(w, "{}buf: Vec<u8>) -> Result<Self> {{", mut_s)?;
+            if let Some(before) = before {
+                writeln!(w, "\t\tlet _ = {};", to_snake_case(&before.name))?;
+            }
+            let mut buf = Vec::new();
+            self.write_raw_read_buf_impl(&mut buf, f, before)?;
+            writeln!(w, "{}\t}}\n", indent(str::from_utf8(&buf).unwrap(), 2))?;
+        }
+
         writeln!(w, "\tpub fn write(&self, w: &mut Write) -> ::Result<()> {{")?;
         let mut buf = Vec::new();
         self.write_raw_write_impl(&mut buf, f)?;
@@ -352,31 +398,7 @@ impl Enum {
         for p in &self.possibilities {
             let (read, is_struct) = match p.content {
                 FieldType::Struct(_) => {
-                    let read = {
-                        let mut buf = Vec::new();
-                        p.write_raw_read_impl(&mut buf, before)?;
-                        let mut s = String::from_utf8(buf).unwrap();
-                        // Change struct creation
-                        let pos = s.rfind("Self {").unwrap();
-                        let tmp = s.split_off(pos);
-                        s.push_str(&format!("{}::{}", f.name, p.name));
-                        s.push_str(&tmp[4..]);
-                        s
-                    };
-
-                    let mut buf = Vec::new();
-                    p.call_read(&mut buf, before)?;
-                    let s = String::from_utf8(buf).unwrap();
-                    let before = if let Some(before) = before {
-                        format!("&{}, ", to_snake_case(&before.name))
-                    } else {
-                        String::new()
-                    };
-                    let s = s.replace(
-                        &format!("{}::read({}r)", p.name, before),
-                        &read,
-                    );
-                    (indent(&s[..(s.len() - 1)], 2), true)
+                    (self.get_struct_read(f, p, before, false)?, true)
                 }
                 _ => {
                     let mut buf = Vec::new();
@@ -423,6 +445,140 @@ impl Enum {
         Ok(())
     }
 
+    /// The code to read an inlined struct possibility.
+    ///
+    /// If `skip_payload` is set, a trailing `Vec` is not read but left empty.
+    fn get_struct_read(
+        &self,
+        f: &Field,
+        p: &Field,
+        before: Option<&Field>,
+        skip_payload: bool,
+    ) -> Result<String> {
+        let read = {
+            let mut buf = Vec::new();
+            p.write_raw_read_impl(&mut buf, before)?;
+            let mut s = String::from_utf8(buf).unwrap();
+            if skip_payload {
+                s = s.replace(READ_VEC, "Ok::<_, Error>(Vec::new())");
+            }
+            // Change struct creation
+            let pos = s.rfind("Self {").unwrap();
+            let tmp = s.split_off(pos);
+            s.push_str(&format!("{}::{}", f.name, p.name));
+            s.push_str(&tmp[4..]);
+            s
+        };
+
+        let mut buf = Vec::new();
+        p.call_read(&mut buf, before)?;
+        let s = String::from_utf8(buf).unwrap();
+        let before = if let Some(before) = before {
+            format!("&{}, ", to_snake_case(&before.name))
+        } else {
+            String::new()
+        };
+        let s = s.replace(&format!("{}::read({}r)", p.name, before), &read);
+        Ok(indent(&s[..(s.len() - 1)], 2))
+    }
+
+    /// If some possibility ends with a `Vec` that takes the rest of the
+    /// input. A `read_buf` function is generated for such enums.
+    fn has_payload(&self) -> bool {
+        self.possibilities.iter().any(|p| match p.content {
+            FieldType::Struct(ref s) => s.get_payload().is_some(),
+            FieldType::Enum(ref e) => e.has_payload(),
+            _ => false,
+        })
+    }
+
+    /// Like `write_raw_read_impl`, but takes the input as a `Vec` and moves
+    /// it into the trailing `Vec` of the matching possibility.
+    ///
+    /// Only possibilities with such a payload are tried.
+    fn write_raw_read_buf_impl(
+        &self,
+        w: &mut Write,
+        f: &Field,
+        before: Option<&Field>,
+    ) -> Result<()> {
+        writeln!(w, "let mut err_buf = Vec::new();")?;
+        let before_s = if let Some(before) = before {
+            format!("{}, ", to_snake_case(&before.name))
+        } else {
+            String::new()
+        };
+        for p in &self.possibilities {
+            match p.content {
+                FieldType::Struct(ref s) => {
+                    let payload = match s.get_payload() {
+                        Some(payload) => payload,
+                        None => continue,
+                    };
+                    let read = self.get_struct_read(f, p, before, true)?;
+                    // Remember how much was read, the rest is the payload
+                    writeln!(
+                        w,
+                        "let res = {{\n\tlet mut r = \
+                         io::Cursor::new(&buf);\n\tlet res = (|| -> \
+                         Result<_> {{\n\t\tlet r = &mut r;\n{}\t}})();\n\t\
+                         res.map(|res| (res, r.position() as \
+                         usize))\n}};\nmatch res {{\n\tOk((mut res, pos)) \
+                         => {{\n\t\tbuf.drain(..pos);\n\t\tif let \
+                         {}::{} {{ ref mut {}, .. }} = res {{\n\t\t\t*{} = \
+                         buf;\n\t\t}}\n\t\treturn Ok(res);\n\t}}",
+                        read,
+                        f.name,
+                        p.name,
+                        to_snake_case(&payload.name),
+                        to_snake_case(&payload.name)
+                    )?;
+                    writeln!(
+                        w,
+                        "\tErr(error) => {{\n\t\terr_buf.push(format!(\"{} \
+                         did not match: {{}}\", \
+                         error.description()));\n\t}}\n}}\n",
+                        p.name
+                    )?;
+                }
+                FieldType::Enum(ref e) if e.has_payload() => {
+                    // The first possibility with matching pre conditions
+                    // gets the buffer.
+                    let mut cond = p.get_pre_conditions();
+                    let code = p.get_pre_code();
+                    if cond.is_empty() {
+                        cond = code;
+                    } else if !code.is_empty() {
+                        cond = format!("({}) && ({})", cond, code);
+                    }
+                    if cond.is_empty() {
+                        cond = String::from("true");
+                    }
+                    writeln!(
+                        w,
+                        "if {} {{\n\treturn {}::read_buf({}buf).map({}::{});\n}}\n\
+                         err_buf.push(String::from(\"Pre condition failed \
+                         for {}\"));\n",
+                        cond,
+                        p.name,
+                        before_s,
+                        f.name,
+                        p.name,
+                        p.name
+                    )?;
+                }
+                _ => {}
+            }
+        }
+        writeln!(
+            w,
+            "Err(format!(\"No matching possibility for enum {} ({{:?}})\", \
+             err_buf).into())",
+            f.name
+        )?;
+        Ok(())
+    }
+
     fn write_raw_write_impl(&self, w: &mut Write, f: &Field) -> Result<()> {
         writeln!(w, "match *self {{")?;
         for p in &self.possibilities {
@@ -881,13 +1037,7 @@ impl Field {
                 )?;
             },
             FieldType::Array(ref s) => if s.starts_with("Vec<") {
-                write!(
-                    w,
-                    "{{\n\tlet mut res = Vec::new();\n\tif let Err(error) = \
-                     r.read_to_end(&mut res) \
-                     {{\n\t\tErr(Error::from(error))\n\t}} else \
-                     {{\n\t\tOk(res)\n\t}}\n}}"
-                )?;
+                write!(w, "{}", READ_VEC)?;
             } else {
                 write!(
                     w,
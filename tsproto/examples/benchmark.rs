@@ -1,6 +1,5 @@
 //! Benchmark the number of reconnects we can make per second.
 
-extern crate base64;
 extern crate futures;
 #[macro_use]
 extern crate slog;
@@ -11,7 +10,6 @@ extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate tokio_core;
-extern crate tomcrypt;
 extern crate tsproto;
 
 use std::cell::RefCell;
@@ -25,7 +23,6 @@ use structopt::StructOpt;
 use structopt::clap::AppSettings;
 use tokio_core::reactor::Core;
 use tsproto::*;
-use tsproto::algorithms as algs;
 use tsproto::packets::*;
 
 #[derive(StructOpt, Debug)]
@@ -41,81 +38,21 @@ struct Args {
 }
 
 fn connect(
-    logger: slog::Logger,
     client: Rc<RefCell<client::ClientData>>,
     server_addr: SocketAddr,
 ) -> Box<Future<Item = (), Error = errors::Error>> {
-    Box::new(client::connect(client.clone(), server_addr).and_then(move |()| {
-        let mut private_key = tomcrypt::EccKey::import(
-            &base64::decode("MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
-                k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
-                DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap()).unwrap();
-
-        // Compute hash cash
-        let mut time_reporter = slog_perf::TimeReporter::new_with_level(
-            "Compute public key hash cash level", logger.clone(),
-            slog::Level::Info);
-        time_reporter.start("Compute public key hash cash level");
-        let offset = algs::hash_cash(&mut private_key, 8).unwrap();
-        let omega = base64::encode(&private_key.export_public().unwrap());
-        time_reporter.finish();
-        info!(logger, "Computed hash cash level";
-            "level" => algs::get_hash_cash_level(&omega, offset),
-            "offset" => offset);
-
-        // Create clientinit packet
-        let header = Header::new(PacketType::Command);
-        let mut command = commands::Command::new("clientinit");
-        command.push("client_nickname", "Bot");
-        command.push("client_version", "3.1.6 [Build: 1502873983]");
-        command.push("client_platform", "Linux");
-        command.push("client_input_hardware", "0");
-        command.push("client_output_hardware", "0");
-        command.push("client_default_channel", "");
-        command.push("client_default_channel_password", "");
-        command.push("client_server_password", "");
-        command.push("client_meta_data", "");
-        command.push("client_version_sign", "o+l92HKfiUF+THx2rBsuNjj/S1QpxG1fd5o3Q7qtWxkviR3LI3JeWyc26eTmoQoMTgI3jjHV7dCwHsK1BVu6Aw==");
-        command.push("client_key_offset", offset.to_string());
-        command.push("client_nickname_phonetic", "");
-        command.push("client_default_token", "");
-        command.push("hwid", "123,456");
-        let p_data = packets::Data::Command(command);
-        let clientinit_packet = Packet::new(header, p_data);
-
-        let sink = client::ClientData::get_packets(client.clone());
-        sink.send((server_addr, clientinit_packet)).and_then(move |_| {
-            client::wait_until_connected(client, server_addr)
-        })
-    }))
+    client::connect(client, server_addr, client::ConnectOptions::new("Bot"))
 }
 
 fn disconnect(
-    _logger: slog::Logger,
     client: Rc<RefCell<client::ClientData>>,
     server_addr: SocketAddr,
 ) -> Box<Future<Item = (), Error = errors::Error>> {
-    let header = Header::new(PacketType::Command);
-    let mut command = commands::Command::new("clientdisconnect");
-    // Never times out
-    //let mut command = Command::new("clientinitiv");
-
-    // Reason: Disconnect
-    command.push("reasonid", "8");
-    command.push("reasonmsg", "Bye");
-    let p_data = packets::Data::Command(command);
-    let packet = Packet::new(header, p_data);
-    Box::new(client::ClientData::get_packets(client.clone())
-        .send((server_addr, packet))
-        .and_then(move |_| {
-            client::wait_for_state(client, server_addr, |state| {
-                if let client::ServerConnectionState::Disconnected = *state {
-                    true
-                } else {
-                    false
-                }
-            })
-        }),
+    client::disconnect(
+        client,
+        server_addr,
+        handler_data::MoveReason::Clientdisconnect,
+        "Bye",
     )
 }
 
@@ -134,22 +71,21 @@ fn main() {
         slog::Logger::root(drain, o!())
     };
 
-    // Create ECDH key
-    //let prng = tomcrypt::sprng();
-    //let mut private_key = tryf!(tomcrypt::EccKey::new(prng, 32));
-    let private_key = tomcrypt::EccKey::import(
-        &base64::decode("MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
-            k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
-            DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap()).unwrap();
+    // The identity of the client
+    //let identity = identity::Identity::create().unwrap();
+    let identity = identity::Identity::new_from_str(
+        "MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
+        k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
+        DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
 
     let c = client::ClientData::new(
         args.local_address,
-        private_key,
+        identity,
         core.handle(),
         true,
         logger.clone(),
     ).unwrap();
-    client::default_setup(c.clone());
+    client::default_setup(c.clone()).unwrap();
 
     // Listen for packets
     let listen = client::ClientData::get_packets(c.clone())
@@ -181,13 +117,13 @@ fn main() {
         //core.run(action).unwrap();
 
         info!(logger, "Connecting");
-        core.run(connect(logger.clone(), c.clone(), args.address))
+        core.run(connect(c.clone(), args.address))
             .unwrap();
         info!(logger, "Writing message");
         let sink = client::ClientData::get_packets(c.clone());
         core.run(sink.send((args.address, packet.clone()))).unwrap();
         info!(logger, "Disconnecting");
-        core.run(disconnect(logger.clone(), c.clone(), args.address)).unwrap();
+        core.run(disconnect(c.clone(), args.address)).unwrap();
     }
     time_reporter.finish();
     let dur = start.elapsed();
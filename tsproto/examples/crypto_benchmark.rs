@@ -0,0 +1,94 @@
+//! Benchmark the encryption of packets with and without caching the key and
+//! nonce of a generation.
+
+extern crate structopt;
+#[macro_use]
+extern crate structopt_derive;
+extern crate tsproto;
+
+use std::time::Instant;
+
+use structopt::StructOpt;
+use structopt::clap::AppSettings;
+use tsproto::algorithms as algs;
+use tsproto::packets::*;
+
+#[derive(StructOpt, Debug)]
+#[structopt(global_settings_raw = "&[AppSettings::ColoredHelp, AppSettings::VersionlessSubcommands]")]
+struct Args {
+    #[structopt(short = "n", long = "count", default_value = "100000",
+                help = "The number of packets which are encrypted")]
+    count: u32,
+    #[structopt(short = "s", long = "size", default_value = "100",
+                help = "The size of the packet content in bytes")]
+    size: usize,
+}
+
+fn create_header(i: u32) -> Header {
+    let mut header = Header::new(PacketType::Voice);
+    header.c_id = Some(0);
+    header.p_id = i as u16;
+    header
+}
+
+/// Print the average duration per packet.
+fn print_result(name: &str, start: Instant, count: u32) {
+    let time = Instant::now() - start;
+    let nanos = time.as_secs() * 1_000_000_000 + u64::from(time.subsec_nanos());
+    println!(
+        "{:<28} {:>8.0} ns/packet ({:.3} s total)",
+        name,
+        nanos as f64 / f64::from(count),
+        nanos as f64 / 1e9,
+    );
+}
+
+fn main() {
+    tsproto::init().unwrap();
+
+    // Parse command line options
+    let args = Args::from_args();
+    let iv = [0x42; 20];
+    let content = vec![0; args.size];
+    // Use the results, so the computations are not optimized away
+    let mut check = 0u8;
+
+    let start = Instant::now();
+    for i in 0..args.count {
+        let header = create_header(i);
+        let (key, nonce) = algs::create_key_nonce(&header, i >> 16, &iv);
+        check ^= key[0] ^ nonce[0];
+    }
+    print_result("Key and nonce", start, args.count);
+
+    let start = Instant::now();
+    let mut cache = algs::KeyNonceCache::default();
+    for i in 0..args.count {
+        let header = create_header(i);
+        let (key, nonce) = cache.get_key_nonce(&header, i >> 16, &iv);
+        check ^= key[0] ^ nonce[0];
+    }
+    print_result("Key and nonce (cached)", start, args.count);
+
+    let start = Instant::now();
+    for i in 0..args.count {
+        let mut header = create_header(i);
+        let mut data = content.clone();
+        algs::encrypt(&mut header, &mut data, i >> 16, &iv).unwrap();
+        check ^= header.mac[0];
+    }
+    print_result("Encryption", start, args.count);
+
+    let start = Instant::now();
+    let mut cache = algs::KeyNonceCache::default();
+    for i in 0..args.count {
+        let mut header = create_header(i);
+        let mut data = content.clone();
+        let (key, nonce) = cache.get_key_nonce(&header, i >> 16, &iv);
+        algs::encrypt_key_nonce(&mut header, &mut data, &key, &nonce).unwrap();
+        check ^= header.mac[0];
+    }
+    print_result("Encryption (cached)", start, args.count);
+
+    println!("Check: {}", check);
+}
@@ -1,13 +1,23 @@
 //! Handle packet splitting and cryptography
-use std::u64;
+use std::{cmp, thread, u64};
+use std::collections::BTreeSet;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 
-use {tomcrypt, base64};
+use base64;
 use byteorder::{NetworkEndian, WriteBytesExt};
-use num::BigUint;
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::scalar::Scalar;
+use num::{BigUint, FromPrimitive, Integer, One, Zero};
 use quicklz::CompressionLevel;
-use ring::digest;
+use rand::{self, Rng};
+use sha1::Sha1;
+use sha2::Digest;
 
 use Result;
+use crypto::{CryptoProvider, EccKey, Provider};
 use packets::*;
 
 pub fn must_encrypt(t: PacketType) -> bool {
@@ -109,49 +119,130 @@ pub fn compress_and_split(packet: &Packet) -> Vec<(Header, Vec<u8>)> {
     packets
 }
 
-fn create_key_nonce(
-    header: &Header,
+/// Write the header meta data, which is authenticated with the packet, into
+/// `buf` without allocating.
+fn write_meta<'a>(header: &Header, buf: &'a mut [u8; 5]) -> Result<&'a [u8]> {
+    let len = {
+        let mut w = &mut buf[..];
+        header.write_meta(&mut w)?;
+        5 - w.len()
+    };
+    Ok(&buf[..len])
+}
+
+/// Compute the key and nonce of a packet type and generation, without the
+/// packet id.
+///
+/// `client_to_server` is `true` if the packet is sent by a client.
+fn create_base_key_nonce(
+    client_to_server: bool,
+    p_type: u8,
     generation_id: u32,
-    iv: &[u8; 20],
+    iv: &[u8],
 ) -> ([u8; 16], [u8; 16]) {
-    let mut temp = [0; 26];
-    if header.c_id.is_some() {
+    // The iv has 20 bytes for the old and 64 bytes for the new handshake
+    let mut temp = [0; 70];
+    let temp = &mut temp[..6 + iv.len()];
+    if client_to_server {
         temp[0] = 0x31;
     } else {
         temp[0] = 0x30;
     }
-    temp[1] = header.p_type & 0xf;
+    temp[1] = p_type & 0xf;
     let mut buf = Vec::with_capacity(4);
     buf.write_u32::<NetworkEndian>(generation_id).unwrap();
     temp[2..6].copy_from_slice(&buf);
     temp[6..].copy_from_slice(iv);
 
-    let keynonce = digest::digest(&digest::SHA256, &temp);
-    let keynonce = keynonce.as_ref();
+    let keynonce = Provider.sha256(temp);
     let mut key = [0; 16];
     let mut nonce = [0; 16];
     key.copy_from_slice(&keynonce[..16]);
     nonce.copy_from_slice(&keynonce[16..]);
-    key[0] ^= (header.p_id >> 8) as u8;
-    key[1] ^= (header.p_id & 0xff) as u8;
     (key, nonce)
 }
 
+/// Mix the packet id into the base key.
+fn apply_packet_id(key: &mut [u8; 16], p_id: u16) {
+    key[0] ^= (p_id >> 8) as u8;
+    key[1] ^= (p_id & 0xff) as u8;
+}
+
+/// Compute the key and nonce to encrypt or decrypt a packet.
+///
+/// This computes a hash for every call, use a [`KeyNonceCache`] to compute it
+/// only once per generation.
+///
+/// [`KeyNonceCache`]: struct.KeyNonceCache.html
+pub fn create_key_nonce(
+    header: &Header,
+    generation_id: u32,
+    iv: &[u8],
+) -> ([u8; 16], [u8; 16]) {
+    let (mut key, nonce) = create_base_key_nonce(
+        header.c_id.is_some(),
+        header.p_type,
+        generation_id,
+        iv,
+    );
+    apply_packet_id(&mut key, header.p_id);
+    (key, nonce)
+}
+
+/// Caches the key and nonce of the current generation for each packet type
+/// and direction, so only the packet id has to be applied for each packet.
+#[derive(Debug, Default, Clone)]
+pub struct KeyNonceCache {
+    /// Indexed by the packet type and the direction, the entries contain the
+    /// generation id, the base key and the nonce.
+    cache: [[Option<(u32, [u8; 16], [u8; 16])>; 2]; 9],
+}
+
+impl KeyNonceCache {
+    /// Get the key and nonce for a packet, they are the same as the result of
+    /// [`create_key_nonce`].
+    ///
+    /// [`create_key_nonce`]: fn.create_key_nonce.html
+    pub fn get_key_nonce(
+        &mut self,
+        header: &Header,
+        generation_id: u32,
+        iv: &[u8],
+    ) -> ([u8; 16], [u8; 16]) {
+        let client_to_server = header.c_id.is_some();
+        let entry = &mut self.cache[(header.p_type & 0xf) as usize]
+            [client_to_server as usize];
+        let cached = match *entry {
+            Some((gen, key, nonce)) if gen == generation_id => Some((key, nonce)),
+            _ => None,
+        };
+        let (mut key, nonce) = if let Some(key_nonce) = cached {
+            key_nonce
+        } else {
+            let (key, nonce) = create_base_key_nonce(
+                client_to_server,
+                header.p_type,
+                generation_id,
+                iv,
+            );
+            *entry = Some((generation_id, key, nonce));
+            (key, nonce)
+        };
+        apply_packet_id(&mut key, header.p_id);
+        (key, nonce)
+    }
+}
+
 pub fn encrypt_key_nonce(
     header: &mut Header,
     data: &mut [u8],
     key: &[u8; 16],
     nonce: &[u8; 16],
 ) -> Result<()> {
-    let mut meta = Vec::with_capacity(5);
-    header.write_meta(&mut meta)?;
-
-    let mut eax =
-        tomcrypt::EaxState::new(tomcrypt::rijndael(), key, nonce, Some(&meta))?;
-
-    eax.encrypt_in_place(data)?;
-    header.mac.copy_from_slice(&eax.finish(8)?);
+    let mut meta = [0; 5];
+    let meta = write_meta(header, &mut meta)?;
 
+    header.mac = Provider.eax_encrypt(key, nonce, meta, data)?;
     Ok(())
 }
 
@@ -167,9 +258,8 @@ pub fn encrypt(
     header: &mut Header,
     data: &mut [u8],
     generation_id: u32,
-    iv: &[u8; 20],
+    iv: &[u8],
 ) -> Result<()> {
-    // TODO Cache this more efficiently for a generation
     let (key, nonce) = create_key_nonce(header, generation_id, iv);
     encrypt_key_nonce(header, data, &key, &nonce)
 }
@@ -180,18 +270,10 @@ pub fn decrypt_key_nonce(
     key: &[u8; 16],
     nonce: &[u8; 16],
 ) -> Result<()> {
-    let mut meta = Vec::with_capacity(5);
-    header.write_meta(&mut meta)?;
-
-    let mut eax =
-        tomcrypt::EaxState::new(tomcrypt::rijndael(), key, nonce, Some(&meta))?;
+    let mut meta = [0; 5];
+    let meta = write_meta(header, &mut meta)?;
 
-    eax.decrypt_in_place(data)?;
-    if !header.mac.iter().eq(&eax.finish(8)?) {
-        Err("Packet has wrong mac".into())
-    } else {
-        Ok(())
-    }
+    Provider.eax_decrypt(key, nonce, meta, data, &header.mac)
 }
 
 pub fn decrypt_fake(header: &Header, data: &mut [u8]) -> Result<()> {
@@ -206,7 +288,7 @@ pub fn decrypt(
     header: &Header,
     data: &mut [u8],
     generation_id: u32,
-    iv: &[u8; 20],
+    iv: &[u8],
 ) -> Result<()> {
     let (key, nonce) = create_key_nonce(header, generation_id, iv);
     decrypt_key_nonce(header, data, &key, &nonce)
@@ -216,14 +298,11 @@ pub fn decrypt(
 pub fn compute_iv_mac(
     alpha: &[u8; 10],
     beta: &[u8; 10],
-    our_key: &mut tomcrypt::EccKey,
-    other_key: &mut tomcrypt::EccKey,
+    our_key: &mut EccKey,
+    other_key: &mut EccKey,
 ) -> Result<([u8; 20], [u8; 8])> {
-    let shared_secret =
-        tomcrypt::EccKey::create_shared_secret(our_key, other_key, 32)?;
-    let mut shared_iv = [0; 20];
-    shared_iv.copy_from_slice(digest::digest(&digest::SHA1, &shared_secret)
-                              .as_ref());
+    let shared_secret = Provider.shared_secret(our_key, other_key)?;
+    let mut shared_iv = Provider.sha1(&shared_secret);
     for i in 0..10 {
         shared_iv[i] ^= alpha[i];
     }
@@ -231,28 +310,171 @@ pub fn compute_iv_mac(
         shared_iv[i + 10] ^= beta[i];
     }
     let mut shared_mac = [0; 8];
-    shared_mac.copy_from_slice(
-        &digest::digest(&digest::SHA1, &shared_iv).as_ref()[..8],
-    );
+    shared_mac.copy_from_slice(&Provider.sha1(&shared_iv)[..8]);
     Ok((shared_iv, shared_mac))
 }
 
-pub fn hash_cash(key: &mut tomcrypt::EccKey, level: u8) -> Result<u64> {
-    let omega = base64::encode(&key.export_public()?);
-    let mut offset = 0;
-    while offset < u64::MAX && get_hash_cash_level(&omega, offset) < level {
-        offset += 1;
+/// Generate a temporary key pair for the Ed25519 key exchange of the new
+/// handshake.
+///
+/// Returns the private key and the compressed public key.
+pub fn generate_ephemeral_key() -> ([u8; 32], [u8; 32]) {
+    let mut private = rand::thread_rng().gen::<[u8; 32]>();
+    private[0] &= 248;
+    private[31] &= 127;
+    private[31] |= 64;
+    (private, get_ephemeral_public_key(&private))
+}
+
+/// Compute the compressed public key for a private key of the Ed25519 key
+/// exchange.
+pub fn get_ephemeral_public_key(private: &[u8; 32]) -> [u8; 32] {
+    let public = &Scalar::from_bits(*private) * &ED25519_BASEPOINT_TABLE;
+    public.compress().to_bytes()
+}
+
+/// Compute shared iv and shared mac for the new handshake, which uses
+/// `initivexpand2` and `clientek`.
+///
+/// `our_key` is a private key from [`generate_ephemeral_key`] and
+/// `other_key` the key of the other side, for the server this is the key,
+/// which is derived from its license chain.
+///
+/// [`generate_ephemeral_key`]: fn.generate_ephemeral_key.html
+pub fn compute_iv_mac31(
+    alpha: &[u8; 10],
+    beta: &[u8; 54],
+    our_key: &[u8; 32],
+    other_key: &EdwardsPoint,
+) -> ([u8; 64], [u8; 8]) {
+    let mut private = *our_key;
+    private[31] &= 0x7f;
+    let shared_secret = (other_key * Scalar::from_bits(private)).compress();
+    let mut shared_iv = Provider.sha512(shared_secret.as_bytes());
+    for i in 0..10 {
+        shared_iv[i] ^= alpha[i];
+    }
+    for i in 0..54 {
+        shared_iv[i + 10] ^= beta[i];
+    }
+    let mut shared_mac = [0; 8];
+    shared_mac.copy_from_slice(&Provider.sha1(&shared_iv)[..8]);
+    (shared_iv, shared_mac)
+}
+
+/// The number of offsets, which are checked by a thread at once in
+/// [`hash_cash_search`].
+///
+/// [`hash_cash_search`]: fn.hash_cash_search.html
+const HASH_CASH_CHUNK_SIZE: u64 = 1 << 16;
+
+/// Find the smallest offset for `key`, which reaches at least the security
+/// `level`.
+pub fn hash_cash(key: &mut EccKey, level: u8) -> Result<u64> {
+    let omega = base64::encode(&Provider.export_public_key(key)?);
+    hash_cash_search(&omega, level, 0, 1, None, |_| {})
+}
+
+/// Search an offset for the public key `omega`, which reaches at least the
+/// security `level`, on multiple threads.
+///
+/// The search starts at the offset `start`. `progress` is called regularly
+/// with an offset, below which all offsets were checked without success. It
+/// can be saved and used as `start` to resume the search later.
+///
+/// The search is aborted with an error as soon as `cancel` is set to `true`.
+pub fn hash_cash_search<F: FnMut(u64)>(
+    omega: &str,
+    level: u8,
+    start: u64,
+    threads: usize,
+    cancel: Option<&AtomicBool>,
+    mut progress: F,
+) -> Result<u64> {
+    enum Message {
+        /// All offsets of this chunk were checked.
+        Checked(u64),
+        Found(u64),
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let next_chunk = Arc::new(AtomicUsize::new(0));
+    let (send, recv) = mpsc::channel();
+    for _ in 0..cmp::max(threads, 1) {
+        let omega = omega.to_string();
+        let stop = stop.clone();
+        let next_chunk = next_chunk.clone();
+        let send = send.clone();
+        thread::spawn(move || {
+            // Hash the prefix only once
+            let mut prefix = Sha1::default();
+            prefix.input(omega.as_bytes());
+            let mut buf = [0; 20];
+            while !stop.load(Ordering::Relaxed) {
+                let chunk = next_chunk.fetch_add(1, Ordering::Relaxed) as u64;
+                let begin = match chunk
+                    .checked_mul(HASH_CASH_CHUNK_SIZE)
+                    .and_then(|o| o.checked_add(start))
+                {
+                    Some(begin) => begin,
+                    // No offsets left
+                    None => break,
+                };
+                let end = begin.saturating_add(HASH_CASH_CHUNK_SIZE);
+                for offset in begin..end {
+                    let mut ctx = prefix.clone();
+                    ctx.input(write_decimal(offset, &mut buf));
+                    if count_leading_zeros(&ctx.result()) >= level {
+                        let _ = send.send(Message::Found(offset));
+                        return;
+                    }
+                }
+                if send.send(Message::Checked(chunk)).is_err() {
+                    break;
+                }
+            }
+        });
     }
-    Ok(offset)
+    drop(send);
+
+    // Chunks which are checked, but not all chunks before them
+    let mut checked = BTreeSet::new();
+    // The number of contiguously checked chunks
+    let mut checked_chunks = 0;
+    let res = loop {
+        if cancel.map(|c| c.load(Ordering::Relaxed)).unwrap_or(false) {
+            break Err("The hash cash search was canceled".into());
+        }
+        match recv.recv_timeout(Duration::from_millis(100)) {
+            Ok(Message::Checked(chunk)) => {
+                checked.insert(chunk);
+                while checked.remove(&checked_chunks) {
+                    checked_chunks += 1;
+                }
+                progress(start.saturating_add(
+                    checked_chunks.saturating_mul(HASH_CASH_CHUNK_SIZE),
+                ));
+            }
+            Ok(Message::Found(offset)) => break Ok(offset),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break Err("No offset for this hash cash level found".into());
+            }
+        }
+    };
+    stop.store(true, Ordering::Relaxed);
+    res
 }
 
 pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
-    let data = digest::digest(
-        &digest::SHA1,
-        format!("{}{}", omega, offset).as_bytes(),
-    );
+    let data = Provider.sha1(format!("{}{}", omega, offset).as_bytes());
+    count_leading_zeros(&data)
+}
+
+/// The number of leading zero bits.
+fn count_leading_zeros(data: &[u8]) -> u8 {
     let mut res = 0;
-    for &d in data.as_ref() {
+    for &d in data {
         if d == 0 {
             res += 8;
         } else {
@@ -263,6 +485,134 @@ pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
     res
 }
 
+/// Write `num` as decimal number into `buf` without allocating.
+fn write_decimal(mut num: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (num % 10) as u8;
+        num /= 10;
+        if num == 0 {
+            break;
+        }
+    }
+    &buf[i..]
+}
+
+/// Hash a server or channel password, like it is expected by the server.
+///
+/// The result is `base64(sha1(password))`.
+pub fn hash_password(password: &str) -> String {
+    base64::encode(&Provider.sha1(password.as_bytes()))
+}
+
+/// Precomputed values to multiply numbers modulo `n` in Montgomery form.
+///
+/// `R` is the smallest power of two which is greater than `n`.
+struct Montgomery {
+    n: BigUint,
+    /// `-n^-1 mod R`
+    n_inv: BigUint,
+    /// `R - 1`, used instead of a division by `R`.
+    mask: BigUint,
+    r_bits: usize,
+}
+
+impl Montgomery {
+    /// `n` has to be odd.
+    fn new(n: &BigUint) -> Self {
+        let r_bits = n.bits();
+        let one = BigUint::one();
+        let mask = (one.clone() << r_bits) - &one;
+        // R + 2, so we can compute 2 - t mod R without underflowing
+        let r_plus_2 = &mask + BigUint::from_u8(3).unwrap();
+
+        // Compute n^-1 mod R with Newton's method, every step doubles the
+        // number of correct bits.
+        let mut inv = one.clone();
+        let mut correct_bits = 1;
+        while correct_bits < r_bits {
+            let t = (n * &inv) & &mask;
+            inv = (&inv * ((&r_plus_2 - t) & &mask)) & &mask;
+            correct_bits *= 2;
+        }
+        let n_inv = ((&mask + &one) - inv) & &mask;
+
+        Self {
+            n: n.clone(),
+            n_inv,
+            mask,
+            r_bits,
+        }
+    }
+
+    /// Compute `t * R^-1 mod n` for `t < n * R`.
+    fn reduce(&self, t: BigUint) -> BigUint {
+        let m = ((&t & &self.mask) * &self.n_inv) & &self.mask;
+        let t = (t + m * &self.n) >> self.r_bits;
+        if t >= self.n {
+            t - &self.n
+        } else {
+            t
+        }
+    }
+
+    fn to_montgomery(&self, x: &BigUint) -> BigUint {
+        (x.clone() << self.r_bits).mod_floor(&self.n)
+    }
+
+    fn from_montgomery(&self, x: BigUint) -> BigUint {
+        self.reduce(x)
+    }
+
+    fn square(&self, x: &BigUint) -> BigUint {
+        self.reduce(x * x)
+    }
+}
+
+/// Solve the RSA puzzle of the `Init3` packet: `y = x ^ (2 ^ level) % n`.
+///
+/// The repeated squaring uses Montgomery reduction, so no division is needed
+/// in each step. The computation is aborted with an error as soon as `cancel`
+/// is set.
+pub fn solve_rsa_puzzle(
+    x: &BigUint,
+    n: &BigUint,
+    level: u32,
+    cancel: Option<&AtomicBool>,
+) -> Result<BigUint> {
+    /// Check for cancellation after this many squarings.
+    const CHECK_INTERVAL: u32 = 1024;
+
+    if n.is_zero() {
+        bail!("The RSA puzzle has an invalid modulus");
+    }
+    let is_canceled =
+        || cancel.map(|c| c.load(Ordering::Relaxed)).unwrap_or(false);
+
+    if n.is_even() {
+        // Montgomery reduction needs an odd modulus
+        let mut y = x.mod_floor(n);
+        for i in 0..level {
+            if i % CHECK_INTERVAL == 0 && is_canceled() {
+                bail!("Solving the RSA puzzle was canceled");
+            }
+            y = (&y * &y).mod_floor(n);
+        }
+        return Ok(y);
+    }
+
+    let mont = Montgomery::new(n);
+    let mut y = mont.to_montgomery(x);
+    for i in 0..level {
+        if i % CHECK_INTERVAL == 0 && is_canceled() {
+            bail!("Solving the RSA puzzle was canceled");
+        }
+        y = mont.square(&y);
+    }
+    Ok(mont.from_montgomery(y))
+}
+
 pub fn biguint_to_array(i: &BigUint) -> [u8; 64] {
     let mut v = i.to_bytes_le();
 
@@ -282,9 +632,183 @@ pub fn array_to_biguint(i: &[u8; 64]) -> BigUint {
 
 #[cfg(test)]
 mod tests {
+    use std::u64;
+    use std::sync::atomic::AtomicBool;
+
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+    use num::{pow, BigUint, FromPrimitive, Integer, Num};
+
     use algorithms::*;
     use packets::{Data, Header, PacketType};
 
+    fn naive_rsa_puzzle(x: &BigUint, n: &BigUint, level: u32) -> BigUint {
+        let mut y = x.mod_floor(n);
+        for _ in 0..level {
+            y = pow::pow(y, 2).mod_floor(n);
+        }
+        y
+    }
+
+    #[test]
+    fn test_rsa_puzzle() {
+        let x = BigUint::from_str_radix(
+            "1b2a9f7e4c3d5a6b8c9d0e1f2a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c5d\
+             6e7f8091a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f70819203a4b5c",
+            16,
+        ).unwrap();
+        // An odd and an even modulus
+        for n in &[
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\
+             9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a09",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\
+             9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+        ] {
+            let n = BigUint::from_str_radix(n, 16).unwrap();
+            for &level in &[0, 1, 2, 100, 2000] {
+                assert_eq!(
+                    naive_rsa_puzzle(&x, &n, level),
+                    solve_rsa_puzzle(&x, &n, level, None).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rsa_puzzle_cancel() {
+        let x = BigUint::from_u32(3).unwrap();
+        let n = BigUint::from_u32(0xffff_fffb).unwrap();
+        let cancel = AtomicBool::new(true);
+        assert!(solve_rsa_puzzle(&x, &n, 10_000, Some(&cancel)).is_err());
+    }
+
+    #[test]
+    fn test_write_decimal() {
+        let mut buf = [0; 20];
+        for &n in &[0, 7, 10, 1234567890, u64::MAX] {
+            assert_eq!(write_decimal(n, &mut buf), n.to_string().as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_hash_cash_search() {
+        let omega = "MEsDAgcAAgEgAiEAtG5rVyrNu9Ed1iqlumSdf+hQ3TeYlrdt26n9cG4FHE0C\
+                     IALPIK0qj/Ob+BNTIqmtVIu8c1/1eWxSapCAEhWeuZoM";
+        let offset = hash_cash_search(omega, 8, 0, 1, None, |_| {}).unwrap();
+        assert!(get_hash_cash_level(omega, offset) >= 8);
+        for o in 0..offset {
+            assert!(get_hash_cash_level(omega, o) < 8);
+        }
+
+        // Resume the search with multiple threads
+        let mut last = 0;
+        let offset2 = hash_cash_search(omega, 16, 0, 4, None, |p| {
+            assert!(p >= last);
+            last = p;
+        }).unwrap();
+        assert!(get_hash_cash_level(omega, offset2) >= 16);
+        let offset3 = hash_cash_search(omega, 16, last, 4, None, |_| {})
+            .unwrap();
+        assert!(get_hash_cash_level(omega, offset3) >= 16);
+        assert!(offset3 >= last);
+    }
+
+    #[test]
+    fn test_hash_cash_search_cancel() {
+        let cancel = AtomicBool::new(true);
+        assert!(hash_cash_search("a", 60, 0, 2, Some(&cancel), |_| {})
+            .is_err());
+    }
+
+    #[test]
+    fn test_hash_password() {
+        assert_eq!(hash_password("password"), "W6ph5Mm5Pz8GgiULbPgzG37mj9g=");
+    }
+
+    #[test]
+    fn test_key_nonce_cache() {
+        let iv = [7; 20];
+        let mut cache = KeyNonceCache::default();
+        for &(p_type, c_id) in &[
+            (PacketType::Voice, Some(0)),
+            (PacketType::Voice, None),
+            (PacketType::Command, Some(1)),
+        ] {
+            for &(gen, p_id) in &[(0, 0), (0, 1), (0, 0xffff), (1, 0), (0, 5)]
+            {
+                let mut header = Header::new(p_type);
+                header.c_id = c_id;
+                header.p_id = p_id;
+                assert_eq!(
+                    cache.get_key_nonce(&header, gen, &iv),
+                    create_key_nonce(&header, gen, &iv)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_nonce_new_iv() {
+        // The 64 byte iv of the new handshake works like the old one
+        let iv = [7; 64];
+        let mut cache = KeyNonceCache::default();
+        let mut header = Header::new(PacketType::Command);
+        header.c_id = Some(0);
+        header.p_id = 3;
+        let key_nonce = create_key_nonce(&header, 0, &iv);
+        assert_eq!(cache.get_key_nonce(&header, 0, &iv), key_nonce);
+        assert!(key_nonce != create_key_nonce(&header, 0, &iv[..20]));
+    }
+
+    #[test]
+    fn test_compute_iv_mac31() {
+        let alpha = [1; 10];
+        let beta = [2; 54];
+        let (client_private, client_public) = generate_ephemeral_key();
+        let (server_private, server_public) = generate_ephemeral_key();
+        let client_public = CompressedEdwardsY(client_public)
+            .decompress()
+            .unwrap();
+        let server_public = CompressedEdwardsY(server_public)
+            .decompress()
+            .unwrap();
+
+        let (client_iv, client_mac) =
+            compute_iv_mac31(&alpha, &beta, &client_private, &server_public);
+        let (server_iv, server_mac) =
+            compute_iv_mac31(&alpha, &beta, &server_private, &client_public);
+        assert_eq!(&client_iv[..], &server_iv[..]);
+        assert_eq!(client_mac, server_mac);
+    }
+
+    #[test]
+    fn test_compute_iv_mac31_vector() {
+        // The key, which is derived from the license chain in the license
+        // tests
+        let server_key = CompressedEdwardsY([
+            0x40, 0xe9, 0x50, 0xc4, 0x61, 0xba, 0x18, 0x3a, 0x1e, 0xb7, 0xcb,
+            0xb1, 0x9a, 0xc3, 0xd8, 0xd9, 0xc4, 0xd5, 0x24, 0xdb, 0x38, 0xf7,
+            0x2d, 0x3d, 0x66, 0x75, 0x77, 0x2a, 0xc5, 0x9c, 0xc5, 0xc6,
+        ]);
+        let server_key = server_key.decompress().unwrap();
+        let mut private = [0; 32];
+        for (i, p) in private.iter_mut().enumerate() {
+            *p = i as u8;
+        }
+        private[31] = 0x5f;
+
+        // The shared secret is the compressed point
+        // 42a6dc37ec312a4c45ec30a9507a904a0e19d5ff69958da7944ddae220af4865
+        let (iv, mac) =
+            compute_iv_mac31(&[1; 10], &[2; 54], &private, &server_key);
+        let expected_iv = BigUint::from_str_radix(
+            "f99c865ecc52caed781b827b56ab3831731b6acdbd438d255454a865dbc28389\
+             19581386832a38257b585760b8e9ed97c7104a8bac75741456049ca25f30990e",
+            16,
+        ).unwrap();
+        assert_eq!(&iv[..], &expected_iv.to_bytes_be()[..]);
+        assert_eq!(mac, [0x8b, 0xeb, 0xe6, 0xce, 0xa6, 0x3d, 0x75, 0xa0]);
+    }
+
     #[test]
     fn test_fake_crypt() {
         ::init().unwrap();
@@ -0,0 +1,362 @@
+//! Encode and decode voice with Opus.
+//!
+//! This module needs the `audio` feature. The audio is 16 bit PCM with a
+//! sample rate of 48 kHz, the samples of stereo audio are interleaved.
+use futures::future;
+use opus;
+
+use {BoxFuture, Error, Map, Result};
+use jitter::Frame;
+use packets::*;
+use voice::VoiceSender;
+
+/// The sample rate of the audio, which is sent and received.
+pub const SAMPLE_RATE: u32 = 48_000;
+/// The number of samples per channel in a frame of 20 ms.
+pub const FRAME_SIZE: usize = 960;
+/// The maximum number of samples per channel in a frame of 120 ms.
+const MAX_FRAME_SIZE: usize = 5760;
+/// The maximum size of an encoded frame, so the voice packet is smaller than
+/// 500 bytes.
+const MAX_ENCODED_SIZE: usize = 484;
+
+/// The number of channels, which are sent with a codec.
+pub fn codec_channels(codec_type: CodecType) -> Result<usize> {
+    match codec_type {
+        CodecType::OpusVoice => Ok(1),
+        CodecType::OpusMusic => Ok(2),
+        _ => bail!("The codec {:?} is not supported", codec_type),
+    }
+}
+
+/// Encodes mono audio with the `OpusVoice` codec and stereo audio with the
+/// `OpusMusic` codec.
+pub struct Encoder {
+    encoder: opus::Encoder,
+    codec_type: CodecType,
+}
+
+impl Encoder {
+    /// `channels` has to be `1` or `2`.
+    pub fn new(channels: usize) -> Result<Self> {
+        let (codec_type, opus_channels, application) = match channels {
+            1 => (
+                CodecType::OpusVoice,
+                opus::Channels::Mono,
+                opus::Application::Voip,
+            ),
+            2 => (
+                CodecType::OpusMusic,
+                opus::Channels::Stereo,
+                opus::Application::Audio,
+            ),
+            _ => bail!("Only mono and stereo audio can be encoded"),
+        };
+        Ok(Self {
+            encoder: opus::Encoder::new(
+                SAMPLE_RATE,
+                opus_channels,
+                application,
+            )?,
+            codec_type,
+        })
+    }
+
+    pub fn codec_type(&self) -> CodecType {
+        self.codec_type
+    }
+
+    pub fn channels(&self) -> usize {
+        codec_channels(self.codec_type).unwrap()
+    }
+
+    /// Encode a frame of audio.
+    ///
+    /// The frame has to contain 2.5, 5, 10, 20, 40 or 60 ms of audio, e.g.
+    /// [`FRAME_SIZE`] samples per channel.
+    ///
+    /// [`FRAME_SIZE`]: constant.FRAME_SIZE.html
+    pub fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>> {
+        Ok(self.encoder.encode_vec(pcm, MAX_ENCODED_SIZE)?)
+    }
+
+    /// Encode a frame and send it with a `VoiceSender`.
+    pub fn send<CS: 'static>(
+        &mut self,
+        sender: &mut VoiceSender<CS>,
+        pcm: &[i16],
+    ) -> BoxFuture<(), Error> {
+        match self.encode(pcm) {
+            Ok(frame) => sender.send(self.codec_type, frame),
+            Err(error) => Box::new(future::err(error)),
+        }
+    }
+}
+
+/// The decoder state of a talking client.
+struct ClientDecoder {
+    codec_type: CodecType,
+    decoder: opus::Decoder,
+    /// The number of samples per channel of the last frame, lost frames are
+    /// concealed with the same length.
+    frame_size: usize,
+}
+
+/// Decodes the audio of all clients, which are talking.
+///
+/// Every client has its own decoder state, which is dropped when the client
+/// stops talking.
+#[derive(Default)]
+pub struct Decoder {
+    decoders: Map<ClientId, ClientDecoder>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode a frame of a client and return the interleaved samples.
+    ///
+    /// An empty frame marks the end of a transmission, it resets the decoder
+    /// and returns no samples.
+    pub fn decode(
+        &mut self,
+        from: ClientId,
+        codec_type: CodecType,
+        frame: &[u8],
+    ) -> Result<Vec<i16>> {
+        if frame.is_empty() {
+            self.decoders.remove(&from);
+            return Ok(Vec::new());
+        }
+        let channels = codec_channels(codec_type)?;
+        let create = match self.decoders.get(&from) {
+            Some(d) => d.codec_type != codec_type,
+            None => true,
+        };
+        if create {
+            let opus_channels = if channels == 1 {
+                opus::Channels::Mono
+            } else {
+                opus::Channels::Stereo
+            };
+            let decoder = ClientDecoder {
+                codec_type,
+                decoder: opus::Decoder::new(SAMPLE_RATE, opus_channels)?,
+                frame_size: FRAME_SIZE,
+            };
+            self.decoders.insert(from, decoder);
+        }
+
+        let decoder = self.decoders.get_mut(&from).unwrap();
+        let mut pcm = vec![0; MAX_FRAME_SIZE * channels];
+        let len = decoder.decoder.decode(frame, &mut pcm, false)?;
+        decoder.frame_size = len;
+        pcm.truncate(len * channels);
+        Ok(pcm)
+    }
+
+    /// Create the samples for a lost frame of a client with the packet loss
+    /// concealment of Opus.
+    ///
+    /// Returns no samples if the client is not talking.
+    pub fn conceal(&mut self, from: ClientId) -> Result<Vec<i16>> {
+        let decoder = match self.decoders.get_mut(&from) {
+            Some(decoder) => decoder,
+            None => return Ok(Vec::new()),
+        };
+        let channels = codec_channels(decoder.codec_type)?;
+        let mut pcm = vec![0; decoder.frame_size * channels];
+        // An empty input tells Opus that the packet was lost
+        let len = decoder.decoder.decode(&[], &mut pcm, false)?;
+        pcm.truncate(len * channels);
+        Ok(pcm)
+    }
+
+    /// Decode a frame, which was returned by the [`JitterBuffer`].
+    ///
+    /// [`JitterBuffer`]: ../jitter/struct.JitterBuffer.html
+    pub fn decode_frame(
+        &mut self,
+        from: ClientId,
+        frame: &Frame,
+    ) -> Result<Vec<i16>> {
+        match *frame {
+            Frame::Voice {
+                codec_type,
+                ref data,
+                ..
+            } => self.decode(from, codec_type, data),
+            Frame::Lost { .. } => self.conceal(from),
+            Frame::End => {
+                self.remove(from);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Decode a voice or whisper packet, which was sent by the server.
+    ///
+    /// Returns the talking client and its samples or `None` if the packet
+    /// contains no voice.
+    pub fn decode_packet(
+        &mut self,
+        packet: &Packet,
+    ) -> Result<Option<(ClientId, Vec<i16>)>> {
+        match packet.data {
+            Data::S2CVoice(S2CVoice::Voice {
+                from_id,
+                codec_type,
+                ref voice_data,
+                ..
+            })
+            | Data::S2CVoice(S2CVoice::VoiceWhisper {
+                from_id,
+                codec_type,
+                ref voice_data,
+                ..
+            }) => {
+                let from = ClientId(from_id);
+                let pcm = self.decode(from, codec_type, voice_data)?;
+                Ok(Some((from, pcm)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Forget the decoder state of a client, e.g. when it left.
+    pub fn remove(&mut self, from: ClientId) {
+        self.decoders.remove(&from);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use audio::*;
+    use jitter::JitterBuffer;
+
+    /// Create `count` frames of a 440 Hz sine wave.
+    fn sine(channels: usize, count: usize) -> Vec<Vec<i16>> {
+        (0..count)
+            .map(|f| {
+                (0..FRAME_SIZE * channels)
+                    .map(|i| {
+                        let t = (f * FRAME_SIZE + i / channels) as f64
+                            / f64::from(SAMPLE_RATE);
+                        ((2.0 * PI * 440.0 * t).sin() * 10_000.0) as i16
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn energy(pcm: &[i16]) -> f64 {
+        pcm.iter().map(|s| f64::from(*s).powi(2)).sum::<f64>()
+            / pcm.len() as f64
+    }
+
+    #[test]
+    fn encode_decode() {
+        for &(channels, codec_type) in
+            &[(1, CodecType::OpusVoice), (2, CodecType::OpusMusic)]
+        {
+            let mut encoder = Encoder::new(channels).unwrap();
+            assert_eq!(encoder.codec_type(), codec_type);
+            let mut decoder = Decoder::new();
+            let input = sine(channels, 10);
+            let mut output = Vec::new();
+            for frame in &input {
+                let data = encoder.encode(frame).unwrap();
+                assert!(!data.is_empty() && data.len() <= MAX_ENCODED_SIZE);
+                let pcm = decoder
+                    .decode(ClientId(1), encoder.codec_type(), &data)
+                    .unwrap();
+                assert_eq!(pcm.len(), FRAME_SIZE * channels);
+                output.push(pcm);
+            }
+            // The codec needs a few frames to settle
+            let last = output.last().unwrap();
+            let expected = energy(input.last().unwrap());
+            assert!((energy(last) - expected).abs() < expected / 2.0);
+        }
+    }
+
+    #[test]
+    fn decode_packets() {
+        let mut encoder = Encoder::new(1).unwrap();
+        let frame = encoder.encode(&sine(1, 1)[0]).unwrap();
+        let mut decoder = Decoder::new();
+        for from_id in 1..3 {
+            let packet = Packet::new(
+                Header::new(PacketType::Voice),
+                Data::S2CVoice(S2CVoice::Voice {
+                    id: 0,
+                    from_id,
+                    codec_type: CodecType::OpusVoice,
+                    voice_data: frame.clone(),
+                }),
+            );
+            let (from, pcm) = decoder.decode_packet(&packet).unwrap().unwrap();
+            assert_eq!(from, ClientId(from_id));
+            assert_eq!(pcm.len(), FRAME_SIZE);
+        }
+        assert_eq!(decoder.decoders.len(), 2);
+
+        // Stop talking
+        assert!(decoder
+            .decode(ClientId(1), CodecType::OpusVoice, &[])
+            .unwrap()
+            .is_empty());
+        assert_eq!(decoder.decoders.len(), 1);
+
+        let packet = Packet::new(Header::new(PacketType::Ping), Data::Ping);
+        assert!(decoder.decode_packet(&packet).unwrap().is_none());
+        assert!(decoder
+            .decode(ClientId(1), CodecType::SpeexWideband, &frame)
+            .is_err());
+    }
+
+    #[test]
+    fn conceal_lost_frames() {
+        let mut encoder = Encoder::new(2).unwrap();
+        let mut buffer = JitterBuffer::new();
+        for (id, frame) in sine(2, 10).iter().enumerate() {
+            let data = encoder.encode(frame).unwrap();
+            // Lose every third packet
+            if id % 3 != 2 {
+                let codec_type = encoder.codec_type();
+                buffer.push(ClientId(1), id as u16, codec_type, data);
+            }
+        }
+        buffer.push(ClientId(1), 10, encoder.codec_type(), Vec::new());
+
+        let mut decoder = Decoder::new();
+        assert!(decoder.conceal(ClientId(1)).unwrap().is_empty());
+        let mut lost = 0;
+        loop {
+            let frames = buffer.pop();
+            if frames.is_empty() {
+                break;
+            }
+            for (from, frame) in frames {
+                let pcm = decoder.decode_frame(from, &frame).unwrap();
+                match frame {
+                    Frame::Voice { .. } => {
+                        assert_eq!(pcm.len(), FRAME_SIZE * 2)
+                    }
+                    Frame::Lost { .. } => {
+                        lost += 1;
+                        assert_eq!(pcm.len(), FRAME_SIZE * 2);
+                        assert!(energy(&pcm) > 0.0);
+                    }
+                    Frame::End => assert!(pcm.is_empty()),
+                }
+            }
+        }
+        assert_eq!(lost, 3);
+        assert!(decoder.decoders.is_empty());
+    }
+}
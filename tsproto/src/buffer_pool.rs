@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The capacity of new buffers, which is enough for every udp packet.
+const BUFFER_CAPACITY: usize = 500;
+/// The maximum number of unused buffers, which are kept in the pool.
+const MAX_POOLED_BUFFERS: usize = 256;
+
+/// A pool of byte buffers, which are reused for udp packets, so that sending
+/// and receiving packets does not need a new allocation for every packet.
+///
+/// The voice data of received packets is stored in buffers of the pool, they
+/// can be given back with [`put`] when the voice data was used.
+///
+/// Cloning a pool gives another handle to the same buffers.
+///
+/// [`put`]: #method.put
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    buffers: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take an empty buffer out of the pool or allocate a new one, if the pool
+    /// is empty.
+    pub fn get(&self) -> Vec<u8> {
+        self.buffers
+            .borrow_mut()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUFFER_CAPACITY))
+    }
+
+    /// Give a buffer back to the pool, so it can be reused.
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut buffers = self.buffers.borrow_mut();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+
+    /// The number of unused buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.borrow().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buffer_pool::*;
+
+    #[test]
+    fn reuse_buffers() {
+        let pool = BufferPool::new();
+        let mut buf = pool.get();
+        assert!(buf.capacity() >= BUFFER_CAPACITY);
+        buf.extend_from_slice(&[1, 2, 3]);
+        let ptr = buf.as_ptr();
+
+        // Clones share the buffers
+        pool.clone().put(buf);
+        assert_eq!(pool.len(), 1);
+        let buf = pool.get();
+        assert!(pool.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn limit_buffers() {
+        let pool = BufferPool::new();
+        // Empty vectors are not worth keeping
+        pool.put(Vec::new());
+        assert!(pool.is_empty());
+
+        for _ in 0..MAX_POOLED_BUFFERS + 10 {
+            pool.put(Vec::with_capacity(1));
+        }
+        assert_eq!(pool.len(), MAX_POOLED_BUFFERS);
+    }
+}
@@ -1,24 +1,35 @@
 use std::cell::RefCell;
-use std::mem;
+use std::{cmp, fmt, iter, mem};
 use std::net::SocketAddr;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
-use {tomcrypt, base64};
-use chrono::Utc;
-use futures::{self, future, Future, Sink, Stream};
+use base64;
+use chrono::{DateTime, Duration, Utc};
+use futures::{self, future, stream, Future, Sink, Stream};
 use futures::future::Either;
 use futures::task::{self, Task};
-use futures::unsync::oneshot;
-use num::{pow, BigUint, Integer, ToPrimitive};
+use futures::unsync::{mpsc, oneshot};
+use futures::sync::oneshot as sync_oneshot;
+use num::{BigUint, FromPrimitive, ToPrimitive};
 use rand::{self, Rng};
+use slog::Logger;
+use tokio_core::reactor::{Handle, Timeout};
 
-use {packets, BoxFuture, Error, Result};
+use {packets, BoxFuture, Error, ErrorKind, Result, ResultExt};
 use algorithms as algs;
-use commands::Command;
+use commands::{CanonicalCommand, Command};
+use crypto::{CryptoProvider, EccKey, Provider};
 use handler_data::*;
 use handler_data::Data;
+use identity::Identity;
+use known_servers::{KeyChange, KeyCheck, KnownServer, KnownServers};
+use license::Licenses;
 use packets::*;
-use resend::ResendFuture;
+use resend::{ResendSink, ResendState};
+use stats::StatsSnapshot;
 
 /// The data of our client.
 pub type ClientData = Data<ServerConnectionData>;
@@ -28,25 +39,427 @@ pub(crate) type ServerConnection = Connection<ServerConnectionData>;
 pub struct ServerConnectionData {
     pub state_change_listener: Vec<Box<FnMut() -> BoxFuture<(), Error>>>,
     pub state: ServerConnectionState,
+    /// The settings, which are used to connect and reconnect.
+    pub options: ConnectOptions,
+    /// The number of the current reconnect attempt or `0` if the connection
+    /// is not reconnecting.
+    pub reconnect_attempts: u32,
+    /// The state of our own client on the server.
+    pub client_state: OwnClientState,
 }
 
 #[derive(Debug)]
 pub enum ServerConnectionState {
+    /// The connection was lost and the client waits until it reconnects.
+    ///
+    /// `attempt` starts at `1`.
+    Reconnecting { attempt: u32 },
     /// After `Init0` was sent.
     Init0 { version: u32, random0: [u8; 4] },
     /// After `Init2` was sent.
     Init2 { version: u32 },
+    /// Got `Init3`, the RSA puzzle is solved on a separate thread.
+    SolvingPuzzle { version: u32 },
     /// After `Init4` was sent.
     ClientInitIv { alpha: [u8; 10] },
-    /// The initial handshake is done and the next packet has to be
-    /// `clientinit`.
-    Connecting,
+    /// The initial handshake is done and `clientinit` was sent.
+    ///
+    /// `clientinit_id` is the packet id of `clientinit`, which is
+    /// acknowledged by `initserver`.
+    Connecting { clientinit_id: u16 },
     /// Fully connected, the client id is known.
     Connected,
     /// The connection is finished, no more packets can be sent or received.
     Disconnected,
 }
 
+/// The state of our own client on a server, which is restored when
+/// reconnecting.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OwnClientState {
+    pub channel_id: Option<u64>,
+    pub nickname: Option<String>,
+    /// The away message, if the client is away.
+    pub away: Option<String>,
+    pub input_muted: bool,
+    pub output_muted: bool,
+}
+
+impl OwnClientState {
+    /// Update the state from a notification about our own client.
+    fn update(&mut self, cmd: &CanonicalCommand) {
+        if cmd.command == "notifycliententerview"
+            || cmd.command == "notifyclientmoved"
+        {
+            if let Some(c_id) = cmd.args.get("ctid").and_then(|c| c.parse().ok())
+            {
+                self.channel_id = Some(c_id);
+            }
+        }
+        if cmd.command == "notifycliententerview"
+            || cmd.command == "notifyclientupdated"
+        {
+            if let Some(name) = cmd.args.get("client_nickname") {
+                self.nickname = Some(name.to_string());
+            }
+            if let Some(away) = cmd.args.get("client_away") {
+                self.away = if *away == "1" {
+                    Some(String::new())
+                } else {
+                    None
+                };
+            }
+            if let Some(msg) = cmd.args.get("client_away_message") {
+                if self.away.is_some() {
+                    self.away = Some(msg.to_string());
+                }
+            }
+            if let Some(muted) = cmd.args.get("client_input_muted") {
+                self.input_muted = *muted == "1";
+            }
+            if let Some(muted) = cmd.args.get("client_output_muted") {
+                self.output_muted = *muted == "1";
+            }
+        }
+    }
+
+    /// Create a `clientupdate` packet, which restores the away and mute
+    /// state or `None` if nothing has to be restored.
+    fn create_restore_packet(&self) -> Option<Packet> {
+        let mut command = Command::new("clientupdate");
+        let mut changed = false;
+        if let Some(ref msg) = self.away {
+            command.push("client_away", "1");
+            command.push("client_away_message", msg.as_str());
+            changed = true;
+        }
+        if self.input_muted {
+            command.push("client_input_muted", "1");
+            changed = true;
+        }
+        if self.output_muted {
+            command.push("client_output_muted", "1");
+            changed = true;
+        }
+        if changed {
+            Some(Packet::new(
+                Header::new(PacketType::Command),
+                packets::Data::Command(command),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Configures if and how often a lost connection is reconnected.
+///
+/// The delay between attempts grows exponentially and contains a random part,
+/// so not all clients reconnect at the same time, e.g. after a server restart.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The maximum number of attempts, `None` means no limit.
+    pub max_attempts: Option<u32>,
+    /// The delay before the first attempt.
+    pub initial_delay: Duration,
+    /// The delay doubles after each attempt until it reaches this value.
+    pub max_delay: Duration,
+    /// Up to this fraction of the delay is randomly subtracted. This should be
+    /// between `0` and `1`.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(10),
+            initial_delay: Duration::seconds(1),
+            max_delay: Duration::seconds(60),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before the given attempt, which starts at `1`.
+    pub fn get_delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 1..attempt {
+            if delay >= self.max_delay {
+                break;
+            }
+            delay = delay * 2;
+        }
+        let delay = cmp::min(delay, self.max_delay);
+        let jitter = rand::thread_rng().gen::<f64>()
+            * self.jitter.max(0.0).min(1.0);
+        Duration::milliseconds(
+            (delay.num_milliseconds() as f64 * (1.0 - jitter)) as i64,
+        )
+    }
+}
+
+/// The hash cash level of the identity, which is needed by most servers.
+const DEFAULT_HASH_CASH_LEVEL: u8 = 8;
+
+/// The hardware id, which is sent if no other id is set.
+///
+/// The official client sends two hashes of its hardware, separated by a comma.
+/// Servers do not check the format, so this placeholder is used instead of
+/// information about the machine.
+const DEFAULT_HWID: &str = "123,456";
+
+/// The client version, which is sent to the server.
+///
+/// The server checks the signature, so only combinations of version, platform
+/// and signature, which are signed by TeamSpeak, work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Version {
+    /// `3.1.6 [Build: 1502873983]` on Linux.
+    Linux3_1_6,
+    Custom {
+        version: String,
+        platform: String,
+        signature: String,
+    },
+}
+
+impl Version {
+    /// Get the version string, platform and signature.
+    pub fn get_parts(&self) -> (&str, &str, &str) {
+        match *self {
+            Version::Linux3_1_6 => (
+                "3.1.6 [Build: 1502873983]",
+                "Linux",
+                "o+l92HKfiUF+THx2rBsuNjj/S1QpxG1fd5o3Q7qtWxkviR3LI3JeWyc26eTmoQoMTgI3jjHV7dCwHsK1BVu6Aw==",
+            ),
+            Version::Custom {
+                ref version,
+                ref platform,
+                ref signature,
+            } => (version.as_str(), platform.as_str(), signature.as_str()),
+        }
+    }
+}
+
+/// A server or channel password.
+///
+/// The server expects passwords as `base64(sha1(password))`. A [`Plain`]
+/// password is hashed before it is sent, a [`Hashed`] password is sent as it
+/// is.
+///
+/// [`Plain`]: #variant.Plain
+/// [`Hashed`]: #variant.Hashed
+#[derive(Clone, PartialEq, Eq)]
+pub enum Password {
+    /// The password in clear text.
+    Plain(String),
+    /// The password, which is already hashed with
+    /// [`algorithms::hash_password`].
+    ///
+    /// [`algorithms::hash_password`]: ../algorithms/fn.hash_password.html
+    Hashed(String),
+}
+
+impl Password {
+    /// Get the hashed password, which can be sent to the server.
+    ///
+    /// An empty password means no password, so it is not hashed.
+    pub fn get_hashed(&self) -> String {
+        match *self {
+            Password::Plain(ref password) if password.is_empty() => {
+                String::new()
+            }
+            Password::Plain(ref password) => algs::hash_password(password),
+            Password::Hashed(ref hash) => hash.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Do not print passwords into logs
+        match *self {
+            Password::Plain(_) => write!(f, "Plain(..)"),
+            Password::Hashed(_) => write!(f, "Hashed(..)"),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Password {
+    fn from(password: &'a str) -> Self {
+        Password::Plain(password.to_string())
+    }
+}
+
+impl From<String> for Password {
+    fn from(password: String) -> Self {
+        Password::Plain(password)
+    }
+}
+
+/// The settings of a client, which are sent to the server in the `clientinit`
+/// packet.
+///
+/// # Example
+///
+/// ```
+/// # use tsproto::client::ConnectOptions;
+/// let options = ConnectOptions::new("Bot")
+///     .channel("Lobby")
+///     .server_password("secret");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    nickname: String,
+    phonetic_nickname: String,
+    version: Version,
+    server_password: Option<Password>,
+    channel: String,
+    channel_password: Option<Password>,
+    token: String,
+    hwid: String,
+    reconnect: Option<ReconnectPolicy>,
+    answer_connection_info: bool,
+    known_servers: Option<Rc<RefCell<KnownServers>>>,
+    key_change_listener: Option<mpsc::UnboundedSender<KeyChange>>,
+}
+
+impl ConnectOptions {
+    pub fn new<S: Into<String>>(nickname: S) -> Self {
+        Self {
+            nickname: nickname.into(),
+            phonetic_nickname: String::new(),
+            version: Version::Linux3_1_6,
+            server_password: None,
+            channel: String::new(),
+            channel_password: None,
+            token: String::new(),
+            hwid: String::from(DEFAULT_HWID),
+            reconnect: None,
+            answer_connection_info: true,
+            known_servers: None,
+            key_change_listener: None,
+        }
+    }
+
+    pub fn nickname<S: Into<String>>(mut self, nickname: S) -> Self {
+        self.nickname = nickname.into();
+        self
+    }
+
+    pub fn phonetic_nickname<S: Into<String>>(mut self, nickname: S) -> Self {
+        self.phonetic_nickname = nickname.into();
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// The password of the server.
+    ///
+    /// A `&str` or `String` is hashed before it is sent, use
+    /// [`Password::Hashed`] to pass an already hashed password.
+    ///
+    /// [`Password::Hashed`]: enum.Password.html#variant.Hashed
+    pub fn server_password<P: Into<Password>>(mut self, password: P) -> Self {
+        self.server_password = Some(password.into());
+        self
+    }
+
+    /// The channel, which should be joined, either as path (e.g.
+    /// `Parent/Child`) or as id (e.g. `/5`).
+    pub fn channel<S: Into<String>>(mut self, channel: S) -> Self {
+        self.channel = channel.into();
+        self
+    }
+
+    /// The password of the default channel, it is hashed like the
+    /// [`server_password`].
+    ///
+    /// [`server_password`]: #method.server_password
+    pub fn channel_password<P: Into<Password>>(mut self, password: P) -> Self {
+        self.channel_password = Some(password.into());
+        self
+    }
+
+    /// A privilege key, which is used when connecting.
+    pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = token.into();
+        self
+    }
+
+    /// The hardware id of this client.
+    ///
+    /// Servers can use it to recognize a client, by default a fixed
+    /// placeholder is sent.
+    pub fn hwid<S: Into<String>>(mut self, hwid: S) -> Self {
+        self.hwid = hwid.into();
+        self
+    }
+
+    /// Reconnect automatically if the connection is lost, e.g. because of a
+    /// timeout or a server shutdown.
+    ///
+    /// After reconnecting, the previous channel, nickname, away and mute state
+    /// are restored. Reconnecting is disabled by default.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Answer the `notifyconnectioninforequest` of the server with the ping,
+    /// packet loss and traffic statistics of this connection.
+    ///
+    /// This is enabled by default.
+    pub fn answer_connection_info(mut self, answer: bool) -> Self {
+        self.answer_connection_info = answer;
+        self
+    }
+
+    /// Check the public key of the server against a store of known servers.
+    ///
+    /// The key is remembered when connecting to a server for the first time.
+    /// If it changes later, the connection fails with
+    /// [`DisconnectReason::ServerKeyChanged`] or the new key is accepted and
+    /// sent to the [`key_change_listener`], depending on the
+    /// [`KeyChangeAction`] of the store.
+    ///
+    /// [`DisconnectReason::ServerKeyChanged`]: ../handler_data/enum.DisconnectReason.html#variant.ServerKeyChanged
+    /// [`key_change_listener`]: #method.key_change_listener
+    /// [`KeyChangeAction`]: ../known_servers/enum.KeyChangeAction.html
+    pub fn known_servers(
+        mut self,
+        known_servers: Rc<RefCell<KnownServers>>,
+    ) -> Self {
+        self.known_servers = Some(known_servers);
+        self
+    }
+
+    /// Get notified when the key of a known server changed and the new key
+    /// was accepted because of [`KeyChangeAction::Warn`].
+    ///
+    /// [`KeyChangeAction::Warn`]: ../known_servers/enum.KeyChangeAction.html#variant.Warn
+    pub fn key_change_listener(
+        mut self,
+        listener: mpsc::UnboundedSender<KeyChange>,
+    ) -> Self {
+        self.key_change_listener = Some(listener);
+        self
+    }
+}
+
+/// The content of an `Init3` packet, which is needed to answer with `Init4`.
+struct RsaPuzzle {
+    version: u32,
+    x: [u8; 64],
+    n: [u8; 64],
+    level: u32,
+    random2: [u8; 100],
+}
+
 fn create_init_header() -> Header {
     let mut mac = [0; 8];
     mac.copy_from_slice(b"TS3INIT1");
@@ -63,7 +476,7 @@ fn create_init_header() -> Header {
 
 /// Configures the default setup chain, including logging and decoding
 /// of packets.
-pub fn default_setup(data: Rc<RefCell<ClientData>>) {
+pub fn default_setup(data: Rc<RefCell<ClientData>>) -> Result<()> {
     // Packet encoding
     ::packet_codec::PacketCodecSink::apply(data.clone());
     ::packet_codec::PacketCodecStream::apply(data.clone(), true);
@@ -71,27 +484,25 @@ pub fn default_setup(data: Rc<RefCell<ClientData>>) {
     ::log::apply_udp_packet_logger(data.clone());
     ::log::apply_packet_logger(data.clone());
 
-    // Default handlers
-    DefaultPacketHandler::apply(data.clone(), true);
-
     // Resend packets
-    let resend_future = ResendFuture::new(
-        data.clone(),
-        Box::new(Data::get_udp_packets(data.clone())),
-    );
-    let (handle, logger) = {
-        let data = data.borrow();
-        (data.handle.clone(), data.logger.clone())
-    };
-    handle.spawn(resend_future.map_err(move |e| {
-        error!(logger, "Resend"; "error" => ?e);
-    }));
+    ResendSink::apply(data.clone())?;
+    // Keepalive pings
+    ::ping::PingFuture::apply(data.clone())?;
+
+    // Default handlers
+    DefaultPacketHandler::apply(data);
+    Ok(())
 }
 
 /// Wait until a client reaches a certain state.
 ///
 /// `is_state` should return `true`, if the state is reached and `false` if this
 /// function should continue waiting.
+///
+/// If the connection is closed before the state is reached, the returned
+/// future fails with [`ErrorKind::Disconnected`], which contains the reason.
+///
+/// [`ErrorKind::Disconnected`]: ../errors/enum.ErrorKind.html
 pub fn wait_for_state<F: Fn(&ServerConnectionState) -> bool + 'static>(
     data: Rc<RefCell<ClientData>>,
     server_addr: SocketAddr,
@@ -110,12 +521,39 @@ pub fn wait_for_state<F: Fn(&ServerConnectionState) -> bool + 'static>(
             send.take().unwrap().send(()).unwrap();
             Box::new(future::ok(()))
         }));
-        Box::new(
-            recv.map_err(|e| e.into())
-                .and_then(move |_| wait_for_state(data2, server_addr, f)),
-        )
-    } else {
+        // Or until the connection is closed
+        let (disconnect_send, disconnect_recv) = oneshot::channel();
+        con.disconnect_listener.retain(|l| !l.is_canceled());
+        con.disconnect_listener.push(disconnect_send);
+
+        // The disconnect reason has to be checked first, because the state
+        // change listener is dropped when the connection is removed.
+        Box::new(disconnect_recv.select2(recv).then(
+            move |res| -> BoxFuture<(), Error> {
+                match res {
+                    Ok(Either::A((reason, _))) => {
+                        if reason == DisconnectReason::Disconnected
+                            && f(&ServerConnectionState::Disconnected)
+                        {
+                            Box::new(future::ok(()))
+                        } else {
+                            Box::new(future::err(
+                                ErrorKind::Disconnected(reason).into(),
+                            ))
+                        }
+                    }
+                    Ok(Either::B(_)) => wait_for_state(data2, server_addr, f),
+                    Err(Either::A((error, _)))
+                    | Err(Either::B((error, _))) => {
+                        Box::new(future::err(error.into()))
+                    }
+                }
+            },
+        ))
+    } else if f(&ServerConnectionState::Disconnected) {
         Box::new(future::ok(()))
+    } else {
+        Box::new(future::err("The connection does not exist".into()))
     }
 }
 
@@ -134,17 +572,101 @@ pub fn wait_until_connected(
 
 /// Connect to a server.
 ///
-/// This function returns, when the client reached the
-/// [`ServerConnectionState::Connecting`] state. Then the client should send the
-/// `clientinit` packet and call [`wait_until_connected`].
+/// If the security level of the identity is too low for most servers, it is
+/// increased first on a separate thread. Then this function performs the
+/// handshake, sends the `clientinit` packet, which
+/// is created from the `options`, and returns when the client reached the
+/// [`ServerConnectionState::Connected`] state.
+///
+/// The init packets are resent until the server answers. The returned future
+/// fails with [`DisconnectReason::HandshakeFailed`] if the handshake fails,
+/// e.g. when the server does not answer in the `connecting_timeout` or the
+/// RSA puzzle cannot be solved in time.
 ///
-/// [`ServerConnectionState::Connecting`]:
-/// [`wait_until_connected`]:
+/// [`ServerConnectionState::Connected`]: enum.ServerConnectionState.html
+/// [`DisconnectReason::HandshakeFailed`]: ../handler_data/enum.DisconnectReason.html
 pub fn connect(
     data: Rc<RefCell<ClientData>>,
     server_addr: SocketAddr,
+    options: ConnectOptions,
 ) -> BoxFuture<(), Error> {
-    // Send the first init packet
+    Box::new(
+        upgrade_identity(data.clone(), DEFAULT_HASH_CASH_LEVEL).and_then(
+            move |_| {
+                let packet = start_connection(
+                    &mut data.borrow_mut(),
+                    server_addr,
+                    options,
+                );
+                ClientData::get_packets(data.clone())
+                    .send((server_addr, packet))
+                    .and_then(move |_| wait_until_connected(data, server_addr))
+            },
+        ),
+    )
+}
+
+/// Increase the security level of the identity to at least `level`.
+///
+/// The key offset is searched on a separate thread, so the reactor is not
+/// blocked.
+fn upgrade_identity(
+    data: Rc<RefCell<ClientData>>,
+    level: u8,
+) -> BoxFuture<(), Error> {
+    let (omega, start) = {
+        let mut data = data.borrow_mut();
+        let identity = &mut data.identity;
+        let res = identity.level().and_then(|cur_level| {
+            if cur_level >= level {
+                Ok(None)
+            } else {
+                Ok(Some(identity.get_omega()?))
+            }
+        });
+        match res {
+            Ok(Some(omega)) => (omega, identity.key_offset()),
+            Ok(None) => return Box::new(future::ok(())),
+            Err(error) => return Box::new(future::err(error)),
+        }
+    };
+
+    let (send, recv) = sync_oneshot::channel();
+    {
+        let omega = omega.clone();
+        thread::spawn(move || {
+            let res =
+                algs::hash_cash_search(&omega, level, start, 1, None, |_| {});
+            // The receiver is gone if the connect future was dropped
+            let _ = send.send(res);
+        });
+    }
+
+    let data = Rc::downgrade(&data);
+    Box::new(recv.map_err(|e| e.into()).and_then(|res| res).and_then(
+        move |key_offset| -> Result<()> {
+            if let Some(data) = data.upgrade() {
+                let mut data = data.borrow_mut();
+                // Check that the identity was not replaced in the meantime
+                if data.identity.get_omega()? == omega {
+                    data.identity.set_key_offset(key_offset);
+                }
+            }
+            Ok(())
+        },
+    ))
+}
+
+/// Add a new connection and create the `Init0` packet, which has to be sent
+/// to start the handshake.
+///
+/// The listeners and the client state of an existing connection to this
+/// server, e.g. when reconnecting, are kept.
+fn start_connection(
+    data: &mut ClientData,
+    server_addr: SocketAddr,
+    options: ConnectOptions,
+) -> Packet {
     // Get the current timestamp
     let now = Utc::now();
     let timestamp = now.timestamp() as u32;
@@ -158,38 +680,629 @@ pub fn connect(
         random0,
     };
 
-    let cheader = create_init_header();
+    let mut con = ServerConnection::new(ServerConnectionData {
+        state_change_listener: Vec::new(),
+        state: ServerConnectionState::Init0 {
+            version: timestamp,
+            random0,
+        },
+        options,
+        reconnect_attempts: 0,
+        client_state: OwnClientState::default(),
+    });
+    if let Some(old) = data.connections.remove(&server_addr) {
+        con.state.state_change_listener = old.state.state_change_listener;
+        con.state.reconnect_attempts = old.state.reconnect_attempts;
+        con.state.client_state = old.state.client_state;
+        con.disconnect_listener = old.disconnect_listener;
+    }
     // Add the connection to the connection list
+    data.connections.insert(server_addr, con);
+
+    Packet::new(create_init_header(), packets::Data::C2SInit(packet_data))
+}
+
+/// Get the options for the `clientinit` packet.
+///
+/// When reconnecting, the last nickname and channel are used.
+fn get_connect_options(con: &ServerConnectionData) -> ConnectOptions {
+    let mut options = con.options.clone();
+    if con.reconnect_attempts > 0 {
+        if let Some(ref nickname) = con.client_state.nickname {
+            options.nickname = nickname.clone();
+        }
+        if let Some(channel_id) = con.client_state.channel_id {
+            options.channel = format!("/{}", channel_id);
+        }
+    }
+    options
+}
+
+/// Replace a lost connection with a connection in the `Reconnecting` state
+/// and schedule the next attempt, if the connection should be reconnected.
+fn handle_disconnect(
+    data: &Weak<RefCell<ClientData>>,
+    handle: &Handle,
+    logger: &Logger,
+    server_addr: SocketAddr,
+    con: &mut ServerConnection,
+    reason: &DisconnectReason,
+) -> Option<ServerConnection> {
+    let policy = if let Some(ref policy) = con.state.options.reconnect {
+        policy.clone()
+    } else {
+        return None;
+    };
+    // Only reconnect if we were connected before
+    let was_connected =
+        if let ServerConnectionState::Connected = con.state.state {
+            true
+        } else {
+            false
+        };
+    if !was_connected && con.state.reconnect_attempts == 0 {
+        return None;
+    }
+    match *reason {
+        DisconnectReason::TimedOut
+        | DisconnectReason::ServerShutdown { .. }
+        | DisconnectReason::HandshakeFailed(_) => {}
+        _ => return None,
+    }
+    let attempt = con.state.reconnect_attempts + 1;
+    if let Some(max) = policy.max_attempts {
+        if attempt > max {
+            warn!(logger, "Giving up reconnecting"; "addr" => %server_addr);
+            return None;
+        }
+    }
+
+    let delay = policy.get_delay(attempt);
+    let timeout = match Timeout::new(delay.to_std().unwrap_or_default(), handle)
     {
-        let mut data = data.borrow_mut();
-        data.connections.insert(
-            server_addr,
-            ServerConnection::new(ServerConnectionData {
-                state_change_listener: Vec::new(),
-                state: ServerConnectionState::Init0 {
-                    version: timestamp,
-                    random0,
-                },
+        Ok(timeout) => timeout,
+        Err(error) => {
+            error!(logger, "Reconnect"; "error" => ?error);
+            return None;
+        }
+    };
+    info!(logger, "Reconnecting"; "addr" => %server_addr,
+        "attempt" => attempt, "delay" => %delay, "reason" => %reason);
+
+    let mut new_con = ServerConnection::new(ServerConnectionData {
+        state_change_listener: Vec::new(),
+        state: ServerConnectionState::Reconnecting { attempt },
+        options: con.state.options.clone(),
+        reconnect_attempts: attempt,
+        client_state: con.state.client_state.clone(),
+    });
+    // Nothing is sent until the next attempt, so this cannot time out
+    new_con.set_resend_state(ResendState::Normal);
+
+    let mut listeners =
+        mem::replace(&mut con.state.state_change_listener, Vec::new());
+    let data = data.clone();
+    let logger = logger.clone();
+    handle.spawn(
+        future::lazy(move || {
+            // Notify state change listeners
+            future::join_all(
+                listeners.drain(..).map(|mut l| l()).collect::<Vec<_>>(),
+            )
+        }).and_then(move |_| timeout.map_err(Error::from))
+            .and_then(move |_| -> BoxFuture<(), Error> {
+                let data = if let Some(data) = data.upgrade() {
+                    data
+                } else {
+                    return Box::new(future::ok(()));
+                };
+                let packet = {
+                    let mut data = data.borrow_mut();
+                    // Check if we still wait for this attempt
+                    let options = match data.connections.get(&server_addr) {
+                        Some(con) => match con.state.state {
+                            ServerConnectionState::Reconnecting {
+                                attempt: a,
+                            } if a == attempt => con.state.options.clone(),
+                            _ => return Box::new(future::ok(())),
+                        },
+                        None => return Box::new(future::ok(())),
+                    };
+                    start_connection(&mut data, server_addr, options)
+                };
+                Box::new(
+                    ClientData::get_packets(data)
+                        .send((server_addr, packet))
+                        .map(|_| ()),
+                )
+            })
+            .map_err(move |error| {
+                error!(logger, "Reconnect"; "error" => ?error);
             }),
-        );
+    );
+    Some(new_con)
+}
+
+/// Create the `clientinit` packet, which is sent after the handshake.
+///
+/// The security level of the `identity` was already increased in [`connect`].
+///
+/// [`connect`]: fn.connect.html
+fn create_clientinit(identity: &Identity, options: &ConnectOptions) -> Packet {
+    let (version, platform, signature) = options.version.get_parts();
+
+    let header = Header::new(PacketType::Command);
+    let mut command = Command::new("clientinit");
+    command.push("client_nickname", options.nickname.as_str());
+    command.push("client_version", version);
+    command.push("client_platform", platform);
+    command.push("client_input_hardware", "0");
+    command.push("client_output_hardware", "0");
+    command.push("client_default_channel", options.channel.as_str());
+    command.push(
+        "client_default_channel_password",
+        options.channel_password.as_ref()
+            .map(Password::get_hashed)
+            .unwrap_or_default(),
+    );
+    command.push(
+        "client_server_password",
+        options.server_password.as_ref()
+            .map(Password::get_hashed)
+            .unwrap_or_default(),
+    );
+    command.push("client_meta_data", "");
+    command.push("client_version_sign", signature);
+    command.push("client_key_offset", identity.key_offset().to_string());
+    command.push(
+        "client_nickname_phonetic",
+        options.phonetic_nickname.as_str(),
+    );
+    command.push("client_default_token", options.token.as_str());
+    command.push("hwid", options.hwid.as_str());
+    Packet::new(header, packets::Data::Command(command))
+}
+
+/// Disconnect from a server.
+///
+/// This sends a `clientdisconnect` packet with the given `reason` and
+/// `message`. Outstanding commands are still sent, until the server confirms
+/// the disconnect or the `disconnect_timeout` is reached. Then the connection
+/// is removed.
+///
+/// The returned future resolves in both cases, also if the connection does not
+/// exist anymore.
+pub fn disconnect(
+    data: Rc<RefCell<ClientData>>,
+    server_addr: SocketAddr,
+    reason: MoveReason,
+    message: &str,
+) -> BoxFuture<(), Error> {
+    if let Some(con) = data.borrow_mut().connections.get_mut(&server_addr) {
+        con.set_resend_state(ResendState::Disconnecting);
+    } else {
+        return Box::new(future::ok(()));
     }
 
-    let packet = Packet::new(cheader, packets::Data::C2SInit(packet_data));
+    let mut command = Command::new("clientdisconnect");
+    command.push("reasonid", (reason as u8).to_string());
+    command.push("reasonmsg", message);
+    let packet = Packet::new(
+        Header::new(PacketType::Command),
+        packets::Data::Command(command),
+    );
     Box::new(
         ClientData::get_packets(data.clone())
             .send((server_addr, packet))
             .and_then(move |_| {
                 wait_for_state(data, server_addr, |state| {
-                    if let ServerConnectionState::Connecting = *state {
+                    if let ServerConnectionState::Disconnected = *state {
                         true
                     } else {
                         false
                     }
                 })
+            })
+            .or_else(|error| match *error.kind() {
+                // The connection is gone, no matter why
+                ErrorKind::Disconnected(_) => Ok(()),
+                _ => Err(error),
             }),
     )
 }
 
+/// Move our client into another channel.
+///
+/// If the channel has a password, it has to be given, it gets hashed like
+/// described in [`Password`].
+///
+/// [`Password`]: enum.Password.html
+pub fn join_channel(
+    data: Rc<RefCell<ClientData>>,
+    server_addr: SocketAddr,
+    channel_id: u64,
+    password: Option<Password>,
+) -> BoxFuture<(), Error> {
+    let c_id = match data.borrow()
+        .connections
+        .get(&server_addr)
+        .and_then(|con| con.params.as_ref())
+    {
+        Some(params) => params.c_id,
+        None => return Box::new(future::err("Not connected".into())),
+    };
+
+    let mut command = Command::new("clientmove");
+    command.push("clid", c_id.to_string());
+    command.push("cid", channel_id.to_string());
+    if let Some(password) = password {
+        command.push("cpw", password.get_hashed());
+    }
+    let packet = Packet::new(
+        Header::new(PacketType::Command),
+        packets::Data::Command(command),
+    );
+    Box::new(
+        ClientData::get_packets(data)
+            .send((server_addr, packet))
+            .map(|_| ()),
+    )
+}
+
+/// Solve the RSA puzzle of an `Init3` packet on a separate thread, so the
+/// reactor is not blocked.
+///
+/// The `Init4` packet is sent when the puzzle is solved. If this takes longer
+/// than the [`rsa_puzzle_timeout`], the connection fails.
+///
+/// [`rsa_puzzle_timeout`]: ../resend/struct.TimeoutConfig.html#structfield.rsa_puzzle_timeout
+fn solve_rsa_puzzle(
+    data: Rc<RefCell<ClientData>>,
+    server_addr: SocketAddr,
+    puzzle: RsaPuzzle,
+) {
+    let (handle, logger, timeout) = {
+        let data = data.borrow();
+        (
+            data.handle.clone(),
+            data.logger.clone(),
+            data.timeout_config.rsa_puzzle_timeout,
+        )
+    };
+    let timeout = match Timeout::new(timeout.to_std().unwrap_or_default(), &handle) {
+        Ok(timeout) => timeout,
+        Err(error) => {
+            data.borrow_mut().remove_connection(
+                server_addr,
+                DisconnectReason::HandshakeFailed(error.to_string()),
+            );
+            return;
+        }
+    };
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (send, recv) = sync_oneshot::channel();
+    {
+        let xi = BigUint::from_bytes_be(&puzzle.x);
+        let ni = BigUint::from_bytes_be(&puzzle.n);
+        let level = puzzle.level;
+        let cancel = cancel.clone();
+        let logger = logger.clone();
+        thread::spawn(move || {
+            let mut time_reporter = ::slog_perf::TimeReporter::new_with_level(
+                "Solve RSA puzzle", logger.clone(),
+                ::slog::Level::Info);
+            time_reporter.start("");
+            let res = algs::solve_rsa_puzzle(&xi, &ni, level, Some(&cancel));
+            time_reporter.finish();
+            if let Ok(ref yi) = res {
+                info!(logger, "Solve RSA puzzle";
+                      "level" => level, "x" => %xi, "n" => %ni,
+                      "y" => %yi);
+            }
+            // The receiver is gone if the timeout was reached
+            let _ = send.send(res);
+        });
+    }
+
+    let data = Rc::downgrade(&data);
+    let fut = recv
+        .map_err(|e| e.into())
+        .and_then(|res| res)
+        .select2(timeout)
+        .then(move |res| -> Result<BigUint> {
+            match res {
+                Ok(Either::A((yi, _))) => Ok(yi),
+                Ok(Either::B(_)) => {
+                    cancel.store(true, Ordering::Relaxed);
+                    bail!("Solving the RSA puzzle timed out")
+                }
+                Err(Either::A((error, _))) => Err(error),
+                Err(Either::B((error, _))) => Err(error.into()),
+            }
+        })
+        .then(move |res| -> BoxFuture<(), ()> {
+            let data = if let Some(data) = data.upgrade() {
+                data
+            } else {
+                return Box::new(future::ok(()));
+            };
+            let res = res.and_then(|yi| {
+                create_init4(&mut *data.borrow_mut(), server_addr, &puzzle, &yi)
+            });
+            match res {
+                Ok(Some((packet, mut listeners))) => {
+                    // Notify state changed listeners
+                    let l_fut = future::join_all(listeners.drain(..)
+                        .map(|mut l| l()).collect::<Vec<_>>());
+                    Box::new(l_fut.and_then(move |_| {
+                        ClientData::get_packets(data).send((server_addr, packet))
+                    }).map(|_| ()).map_err(move |error| {
+                        error!(logger, "Send Init4"; "error" => ?error);
+                    }))
+                }
+                // The connection is not waiting for the puzzle anymore
+                Ok(None) => Box::new(future::ok(())),
+                Err(error) => {
+                    error!(logger, "Solve RSA puzzle"; "error" => ?error);
+                    data.borrow_mut().remove_connection(
+                        server_addr,
+                        DisconnectReason::HandshakeFailed(error.to_string()),
+                    );
+                    Box::new(future::ok(()))
+                }
+            }
+        });
+    handle.spawn(fut);
+}
+
+/// Create the `Init4` packet from the solved puzzle and switch to the
+/// `ClientInitIv` state.
+///
+/// Returns the packet and the state change listeners, which should be
+/// notified or `None` if the connection does not wait for the puzzle anymore.
+fn create_init4(
+    data: &mut ClientData,
+    server_addr: SocketAddr,
+    puzzle: &RsaPuzzle,
+    yi: &BigUint,
+) -> Result<Option<(Packet, Vec<Box<FnMut() -> BoxFuture<(), Error>>>)>> {
+    let con = if let Some(con) = data.connections.get_mut(&server_addr) {
+        con
+    } else {
+        return Ok(None);
+    };
+    if let ServerConnectionState::SolvingPuzzle { .. } = con.state.state {
+    } else {
+        return Ok(None);
+    }
+    let y = algs::biguint_to_array(yi);
+
+    let omega = Provider.export_public_key(data.identity.key_mut())?;
+
+    // Create the command string
+    let mut rng = rand::thread_rng();
+    let alpha = rng.gen::<[u8; 10]>();
+    // omega is an ASN.1-DER encoded public key from the
+    // ECDH parameters.
+    let alpha_s = base64::encode(&alpha);
+    let omega_s = base64::encode(&omega);
+    let mut command = Command::new("clientinitiv");
+    command.push("alpha", alpha_s);
+    command.push("omega", omega_s);
+    command.push("ot", "1");
+    command.push("ip", "");
+
+    let cheader = create_init_header();
+    let init4 = C2SInit::Init4 {
+        version: puzzle.version,
+        x: puzzle.x,
+        n: puzzle.n,
+        level: puzzle.level,
+        random2: puzzle.random2,
+        y,
+        command,
+    };
+
+    con.state.state = ServerConnectionState::ClientInitIv { alpha };
+    let listeners = mem::replace(&mut con.state.state_change_listener, Vec::new());
+    Ok(Some((Packet::new(cheader, packets::Data::C2SInit(init4)), listeners)))
+}
+
+/// Create the connection parameters after the shared iv is known.
+fn create_connected_params(
+    server_key: EccKey,
+    iv: Vec<u8>,
+    mac: [u8; 8],
+) -> ConnectedParams {
+    let mut params = ConnectedParams::new(server_key, iv, mac);
+    // We already sent a command packet.
+    params.outgoing_p_ids[PacketType::Command.to_usize().unwrap()].1 = 1;
+    // We received a command packet.
+    params.incoming_p_ids[PacketType::Command.to_usize().unwrap()].1 = 1;
+    // And we sent an ack.
+    params.incoming_p_ids[PacketType::Ack.to_usize().unwrap()].1 = 1;
+    params
+}
+
+/// Handle the `initivexpand` command of the old handshake.
+fn handle_initivexpand(
+    alpha: &[u8; 10],
+    cmd: &CanonicalCommand,
+    private_key: &mut EccKey,
+) -> Result<ConnectedParams> {
+    if cmd.command != "initivexpand"
+        || !cmd.has_arg("alpha")
+        || !cmd.has_arg("beta")
+        || !cmd.has_arg("omega")
+        || base64::decode(cmd.args["alpha"])
+            .map(|a| a != alpha)
+            .unwrap_or(true)
+    {
+        bail!("initivexpand command has wrong arguments");
+    }
+    let beta_vec = base64::decode(cmd.args["beta"])?;
+    if beta_vec.len() != 10 {
+        bail!("Incorrect beta length");
+    }
+    let omega = base64::decode(cmd.args["omega"])?;
+    let mut beta = [0; 10];
+    beta.copy_from_slice(&beta_vec);
+    let mut server_key = Provider.import_key(&omega)?;
+
+    let (iv, mac) =
+        algs::compute_iv_mac(alpha, &beta, private_key, &mut server_key)?;
+    Ok(create_connected_params(server_key, iv.to_vec(), mac))
+}
+
+/// Handle the `initivexpand2` command of the new handshake, which is used by
+/// servers since TeamSpeak 3.1.
+///
+/// Returns the connection parameters and the `clientek` packet, which has to
+/// be sent before `clientinit`.
+fn handle_initivexpand2(
+    alpha: &[u8; 10],
+    cmd: &CanonicalCommand,
+    identity: &mut Identity,
+) -> Result<(ConnectedParams, Packet)> {
+    let (ek_private, _) = algs::generate_ephemeral_key();
+    handle_initivexpand2_with(alpha, cmd, identity, &ek_private, Utc::now())
+}
+
+/// Like [`handle_initivexpand2`], but uses the given temporary key and checks
+/// the validity of the license chain at the time `now`.
+///
+/// [`handle_initivexpand2`]: fn.handle_initivexpand2.html
+fn handle_initivexpand2_with(
+    alpha: &[u8; 10],
+    cmd: &CanonicalCommand,
+    identity: &mut Identity,
+    ek_private: &[u8; 32],
+    now: DateTime<Utc>,
+) -> Result<(ConnectedParams, Packet)> {
+    if ["l", "beta", "omega", "proof"].iter().any(|a| !cmd.has_arg(a)) {
+        bail!("initivexpand2 command has wrong arguments");
+    }
+    let beta_vec = base64::decode(cmd.args["beta"])?;
+    if beta_vec.len() != 54 {
+        bail!("Incorrect beta length");
+    }
+    let mut beta = [0; 54];
+    beta.copy_from_slice(&beta_vec);
+    let omega = base64::decode(cmd.args["omega"])?;
+    let mut server_key = Provider.import_key(&omega)?;
+
+    // The server signs the license chain with its identity
+    let l = base64::decode(cmd.args["l"])?;
+    let proof = base64::decode(cmd.args["proof"])?;
+    Provider
+        .verify(&mut server_key, &l, &proof)
+        .chain_err(|| "Wrong signature of the license chain")?;
+    let licenses = Licenses::parse(&l)?;
+    licenses.verify(now)?;
+    let server_ek = licenses.derive_public_key()?;
+
+    let ek_public = algs::get_ephemeral_public_key(ek_private);
+    let (iv, mac) =
+        algs::compute_iv_mac31(alpha, &beta, ek_private, &server_ek);
+
+    // Prove that the temporary key belongs to our identity
+    let mut sign_data = ek_public.to_vec();
+    sign_data.extend_from_slice(&beta);
+    let ek_proof = Provider.sign(identity.key_mut(), &sign_data)?;
+
+    let mut command = Command::new("clientek");
+    command.push("ek", base64::encode(&ek_public));
+    command.push("proof", base64::encode(&ek_proof));
+    let clientek = Packet::new(
+        Header::new(PacketType::Command),
+        packets::Data::Command(command),
+    );
+    Ok((create_connected_params(server_key, iv.to_vec(), mac), clientek))
+}
+
+/// Get the reason from a `notifyclientleftview` for our own client.
+fn get_disconnect_reason(cmd: &CanonicalCommand) -> DisconnectReason {
+    let reason = cmd.args.get("reasonid")
+        .and_then(|r| r.parse().ok())
+        .and_then(MoveReason::from_u8)
+        .unwrap_or(MoveReason::None);
+    let message = match cmd.args.get("reasonmsg") {
+        Some(m) if !m.is_empty() => Some(m.to_string()),
+        _ => None,
+    };
+    match reason {
+        MoveReason::Clientdisconnect => DisconnectReason::Disconnected,
+        MoveReason::Serverstop | MoveReason::ClientdisconnectServerShutdown => {
+            DisconnectReason::ServerShutdown { message }
+        }
+        MoveReason::LostConnection => DisconnectReason::TimedOut,
+        _ => {
+            let ban_time = if reason == MoveReason::KickServerBan {
+                cmd.args.get("bantime")
+                    .and_then(|t| t.parse().ok())
+                    .map(Duration::seconds)
+            } else {
+                None
+            };
+            DisconnectReason::Kicked { reason, message, ban_time }
+        }
+    }
+}
+
+/// Create the `setconnectioninfo` packet, which answers a
+/// `notifyconnectioninforequest` of the server.
+fn create_connection_info(stats: &StatsSnapshot) -> Packet {
+    fn millis(d: Duration) -> String {
+        // The ping is sent as floating point number in milliseconds
+        let micros = d.num_microseconds().unwrap_or(i64::max_value());
+        format!("{:.4}", micros as f64 / 1000.0)
+    }
+
+    let mut command = Command::new("setconnectioninfo");
+    command.push("connection_ping", millis(stats.ping));
+    command.push("connection_ping_deviation", millis(stats.ping_deviation));
+    let classes = [
+        ("speech", stats.sent.speech, stats.received.speech),
+        ("keepalive", stats.sent.keepalive, stats.received.keepalive),
+        ("control", stats.sent.control, stats.received.control),
+    ];
+    for &(name, sent, received) in &classes {
+        command.push(
+            format!("connection_packets_sent_{}", name),
+            sent.packets.to_string(),
+        );
+        command.push(
+            format!("connection_bytes_sent_{}", name),
+            sent.bytes.to_string(),
+        );
+        command.push(
+            format!("connection_packets_received_{}", name),
+            received.packets.to_string(),
+        );
+        command.push(
+            format!("connection_bytes_received_{}", name),
+            received.bytes.to_string(),
+        );
+    }
+    let losses = [
+        ("speech", stats.incoming_loss.speech),
+        ("keepalive", stats.incoming_loss.keepalive),
+        ("control", stats.incoming_loss.control),
+        ("total", stats.incoming_loss_total),
+    ];
+    for &(name, loss) in &losses {
+        command.push(
+            format!("connection_server2client_packetloss_{}", name),
+            format!("{:.4}", loss),
+        );
+    }
+    Packet::new(
+        Header::new(PacketType::Command),
+        packets::Data::Command(command),
+    )
+}
+
 pub struct DefaultPacketHandlerStream {
     inner_stream: Box<Stream<Item = (SocketAddr, Packet), Error = Error>>,
 }
@@ -202,7 +1315,6 @@ impl DefaultPacketHandlerStream {
         data: Rc<RefCell<ClientData>>,
         inner_stream: InnerStream,
         inner_sink: InnerSink,
-        send_clientinit: bool,
     ) -> (Self, Rc<RefCell<Either<InnerSink, Option<Task>>>>) {
         let sink = Rc::new(RefCell::new(Either::A(inner_sink)));
         let sink2 = sink.clone();
@@ -210,8 +1322,15 @@ impl DefaultPacketHandlerStream {
         let inner_stream = Box::new(inner_stream.and_then(move |(addr, packet)| -> BoxFuture<_, _> {
             // true, if the packet should not be handled further.
             let mut ignore_packet = false;
+            // An RSA puzzle which should be solved
+            let mut puzzle = None;
             // If the connection should be removed
-            let mut is_end = false;
+            let mut is_end = None;
+            // A packet which should be sent without changing the state
+            let mut answer = None;
+            // A packet which is sent directly after the packet of the state
+            // change
+            let mut next_packet = None;
             // Check if we have a connection for this server
             let packet_res = {
                 let data = data.upgrade().unwrap();
@@ -219,8 +1338,12 @@ impl DefaultPacketHandlerStream {
                 let logger = data.logger.clone();
                 let data = &mut *data;
                 if let Some(con) = data.connections.get_mut(&addr) {
-                    let mut update_rtt = None;
+                    // An acknowledged packet
+                    let mut acked = None;
+                    // If the answer to our last init packet was received
+                    let mut acked_init = false;
                     let handle_res = match con.state.state {
+                        ServerConnectionState::Reconnecting { .. } => None,
                         ServerConnectionState::Init0 { version, ref random0 } => {
                             // Handle an Init1
                             if let Packet { data: packets::Data::S2CInit(
@@ -241,6 +1364,7 @@ impl DefaultPacketHandlerStream {
                                     };
 
                                     ignore_packet = true;
+                                    acked_init = true;
                                     Some((state, Some(Packet::new(cheader,
                                         packets::Data::C2SInit(data)))))
                                 } else {
@@ -256,115 +1380,119 @@ impl DefaultPacketHandlerStream {
                             // Handle an Init3
                             if let Packet { data: packets::Data::S2CInit(
                                 S2CInit::Init3 { ref x, ref n, level, ref random2 }), .. } = packet {
-                                // Solve RSA puzzle: y = x ^ (2 ^ level) % n
-                                // Use Montgomery Reduction
-                                let xi = BigUint::from_bytes_be(x);
-                                let ni = BigUint::from_bytes_be(n);
-                                // TODO implement Montgomery Reduction, use another thread + timeout after 5s
-                                fn pow_mod(mut x: BigUint, level: u32, n: &BigUint) -> BigUint {
-                                    for _ in 0..level {
-                                        x = pow::pow(x, 2).mod_floor(n);
-                                    }
-                                    x
-                                }
-                                let mut time_reporter = ::slog_perf::TimeReporter::new_with_level(
-                                    "Solve RSA puzzle", logger.clone(),
-                                    ::slog::Level::Info);
-                                time_reporter.start("");
-                                let yi = pow_mod(xi.clone(), level, &ni);
-                                time_reporter.finish();
-                                info!(logger, "Solve RSA puzzle";
-                                      "level" => level, "x" => %xi, "n" => %ni,
-                                      "y" => %yi);
-                                let y = algs::biguint_to_array(&yi);
-
-                                let omega = tryf!(data.private_key.export_public());
-
-                                // Create the command string
-                                let mut rng = rand::thread_rng();
-                                let alpha = rng.gen::<[u8; 10]>();
-                                // omega is an ASN.1-DER encoded public key from the
-                                // ECDH parameters.
-                                let alpha_s = base64::encode(&alpha);
-                                let omega_s = base64::encode(&omega);
-                                let mut command = Command::new("clientinitiv");
-                                command.push("alpha", alpha_s);
-                                command.push("omega", omega_s);
-                                command.push("ot", "1");
-                                command.push("ip", "");
-
-                                let cheader = create_init_header();
-                                let data = C2SInit::Init4 {
+                                // The puzzle is solved on another thread, which
+                                // sends the Init4 packet afterwards.
+                                puzzle = Some(RsaPuzzle {
                                     version,
                                     x: *x,
                                     n: *n,
                                     level,
                                     random2: *random2,
-                                    y,
-                                    command: command.clone(),
-                                };
+                                });
 
-                                let state = ServerConnectionState::ClientInitIv {
-                                    alpha,
+                                let state = ServerConnectionState::SolvingPuzzle {
+                                    version,
                                 };
 
                                 ignore_packet = true;
-                                Some((state, Some(Packet::new(cheader,
-                                    packets::Data::C2SInit(data)))))
+                                acked_init = true;
+                                Some((state, None))
                             } else {
                                 None
                             }
                         }
+                        ServerConnectionState::SolvingPuzzle { .. } => None,
                         ServerConnectionState::ClientInitIv { ref alpha } => {
-                            let private_key = &mut data.private_key;
-                            let res = (|con_params: &mut Option<ConnectedParams>| -> Result<()> {
-                                if let Packet { data: packets::Data::Command(ref command), .. } = packet {
-                                    let cmd = command.get_commands().remove(0);
-                                    if cmd.command != "initivexpand"
-                                        || !cmd.has_arg("alpha")
-                                        || !cmd.has_arg("beta")
-                                        || !cmd.has_arg("omega")
-                                        || base64::decode(cmd.args["alpha"])
-                                        .map(|a| a != alpha).unwrap_or(true) {
-                                        bail!("initivexpand command has wrong arguments");
-                                    } else {
-                                        let beta_vec = base64::decode(cmd.args["beta"])?;
-                                        if beta_vec.len() != 10 {
-                                            bail!("Incorrect beta length");
+                            let res = {
+                                let identity = &mut data.identity;
+                                let known_servers = con.state.options.known_servers.clone();
+                                let key_change_listener = con.state.options.key_change_listener.clone();
+                                (|con_params: &mut Option<ConnectedParams>| -> Result<Option<Packet>> {
+                                    if let Packet { data: packets::Data::Command(ref command), .. } = packet {
+                                        let cmd = command.get_commands().remove(0);
+                                        let (mut params, clientek) = if cmd.command == "initivexpand2" {
+                                            let (params, clientek) =
+                                                handle_initivexpand2(alpha, &cmd, identity)?;
+                                            (params, Some(clientek))
+                                        } else {
+                                            // Fall back to the old handshake
+                                            (handle_initivexpand(alpha, &cmd, identity.key_mut())?,
+                                                None)
+                                        };
+                                        if let Some(known_servers) = known_servers {
+                                            let omega = Provider.export_public_key(&mut params.public_key)?;
+                                            let check = known_servers.borrow_mut()
+                                                .check(addr, &omega)?;
+                                            match check {
+                                                KeyCheck::Changed { old } => {
+                                                    let change = KeyChange {
+                                                        addr,
+                                                        old,
+                                                        new: KnownServer::new(omega),
+                                                    };
+                                                    warn!(logger, "The key of the server changed";
+                                                        "addr" => %addr,
+                                                        "old_uid" => &change.old.uid,
+                                                        "uid" => &change.new.uid);
+                                                    if let Some(listener) = key_change_listener {
+                                                        // Ignore closed listeners
+                                                        let _ = listener.unbounded_send(change);
+                                                    }
+                                                }
+                                                KeyCheck::Moved { old_addr } => {
+                                                    info!(logger, "The server moved";
+                                                        "old_addr" => %old_addr,
+                                                        "addr" => %addr);
+                                                }
+                                                KeyCheck::New | KeyCheck::Known => {}
+                                            }
                                         }
-                                        let omega = base64::decode(cmd.args["omega"])?;
-                                        let mut beta = [0; 10];
-                                        beta.copy_from_slice(&beta_vec);
-                                        let mut server_key = tomcrypt::EccKey::import(&omega)?;
-
-                                        let (iv, mac) = algs::compute_iv_mac(
-                                            alpha, &beta, private_key, &mut server_key)?;
-                                        let mut params = ConnectedParams::new(
-                                            server_key, iv, mac);
-                                        // We already sent a command packet.
-                                        params.outgoing_p_ids[PacketType::Command.to_usize().unwrap()]
-                                            .1 = 1;
-                                        // We received a command packet.
-                                        params.incoming_p_ids[PacketType::Command.to_usize().unwrap()]
-                                            .1 = 1;
-                                        // And we sent an ack.
-                                        params.incoming_p_ids[PacketType::Ack.to_usize().unwrap()]
-                                            .1 = 1;
                                         *con_params = Some(params);
+                                        Ok(clientek)
+                                    } else {
+                                        Ok(None)
+                                    }})(&mut con.params)
+                            };
+                            // Send clientinit
+                            let res = match res {
+                                Ok(clientek) => Ok((clientek, create_clientinit(
+                                    &data.identity,
+                                    &get_connect_options(&con.state)))),
+                                Err(error) => Err(error),
+                            };
+                            match res {
+                                Ok((clientek, clientinit)) => {
+                                    ignore_packet = true;
+                                    acked_init = true;
+                                    if let Some(clientek) = clientek {
+                                        // clientek has to be sent before
+                                        // clientinit
+                                        next_packet = Some(clientinit);
+                                        Some((ServerConnectionState::Connecting {
+                                            clientinit_id: 2,
+                                        }, Some(clientek)))
+                                    } else {
+                                        Some((ServerConnectionState::Connecting {
+                                            clientinit_id: 1,
+                                        }, Some(clientinit)))
                                     }
-                                    Ok(())
-                                } else {
-                                    Ok(())
-                                }})(&mut con.params);
-                            if let Err(error) = res {
-                                error!(logger, "Handle udp init packet"; "error" => ?error);
-                                None
-                            } else {
-                                ignore_packet = true;
-                                Some((ServerConnectionState::Connecting, None))
+                                }
+                                Err(error) => {
+                                    error!(logger, "Handle udp init packet"; "error" => ?error);
+                                    is_end = Some(match *error.kind() {
+                                        ErrorKind::ServerKeyChanged(_, ref known_uid, ref uid) =>
+                                            DisconnectReason::ServerKeyChanged {
+                                                known_uid: known_uid.clone(),
+                                                uid: uid.clone(),
+                                            },
+                                        _ => DisconnectReason::HandshakeFailed(
+                                            error.to_string()),
+                                    });
+                                    Some((ServerConnectionState::Disconnected, None))
+                                }
                             }
                         }
-                        ServerConnectionState::Connecting => {
+                        ServerConnectionState::Connecting { clientinit_id } => {
                             let mut res = None;
                             if let Packet { data: packets::Data::Command(ref cmd), .. } = packet {
                                 let cmd = cmd.get_commands().remove(0);
@@ -377,28 +1505,15 @@ impl DefaultPacketHandlerStream {
                                     }
                                     // initserver is the ack for clientinit
                                     // Remove from send queue
-                                    let p_type = PacketType::Command;
-                                    let p_id = 1;
-                                    let mut rec = None;
-                                    let mut items = data.send_queue.drain()
-                                        .filter_map(|r| if r.p_type == p_type && r.p_id == p_id {
-                                            rec = Some(r);
-                                            None
-                                        } else {
-                                            Some(r)
-                                        })
-                                        .collect();
-                                    mem::swap(&mut items, &mut data.send_queue);
-                                    // Update smoothed round trip time
-                                    if let Some(rec) = rec {
-                                        // Only if it was not resent
-                                        if rec.tries == 1 {
-                                            let now = Utc::now();
-                                            let diff = now.naive_utc().signed_duration_since(rec.sent.naive_utc());
-                                            update_rtt = Some(diff);
-                                        }
-                                    }
-                                    res = Some((ServerConnectionState::Connected, None));
+                                    acked = Some((PacketType::Command, clientinit_id));
+                                    // Restore the state after reconnecting
+                                    let packet = if con.state.reconnect_attempts > 0 {
+                                        con.state.reconnect_attempts = 0;
+                                        con.state.client_state.create_restore_packet()
+                                    } else {
+                                        None
+                                    };
+                                    res = Some((ServerConnectionState::Connected, packet));
                                 }
                             }
                             res
@@ -406,14 +1521,27 @@ impl DefaultPacketHandlerStream {
                         ServerConnectionState::Connected => {
                             let mut res = None;
                             if let Packet { data: packets::Data::Command(ref cmd), .. } = packet {
-                                let cmd = cmd.get_commands().remove(0);
-                                if cmd.command == "notifyclientleftview" && cmd.has_arg("clid") {
-                                    // Handle a disconnect
-                                    if let Some(ref mut params) = con.params {
-                                        if cmd.args["clid"].parse() == Ok(params.c_id) {
-                                            is_end = true;
-                                            res = Some((ServerConnectionState::Disconnected, None));
+                                let own_id = con.params.as_ref().map(|p| p.c_id);
+                                for cmd in cmd.get_commands() {
+                                    if cmd.command == "notifyconnectioninforequest" {
+                                        if con.state.options.answer_connection_info {
+                                            answer = Some(create_connection_info(
+                                                &con.stats_snapshot()));
                                         }
+                                        continue;
+                                    }
+                                    // Only handle notifications about our client
+                                    let c_id = cmd.args.get("clid")
+                                        .and_then(|c| c.parse().ok());
+                                    if c_id.is_none() || c_id != own_id {
+                                        continue;
+                                    }
+                                    if cmd.command == "notifyclientleftview" {
+                                        // Handle a disconnect
+                                        is_end = Some(get_disconnect_reason(&cmd));
+                                        res = Some((ServerConnectionState::Disconnected, None));
+                                    } else {
+                                        con.state.client_state.update(&cmd);
                                     }
                                 }
                             }
@@ -424,11 +1552,18 @@ impl DefaultPacketHandlerStream {
                             return Box::new(future::ok(None));
                         }
                     };
+                    if acked_init {
+                        con.ack_init_packet();
+                    }
+                    if let Some((p_type, p_id)) = acked {
+                        con.ack_packet(p_type, p_id);
+                    }
                     if let Some((state, packet)) = handle_res {
-                        if let Some(rtt) = update_rtt {
-                            con.update_srtt(rtt);
+                        // A closed connection keeps its last state, so the
+                        // disconnect handler knows if it was connected.
+                        if is_end.is_none() {
+                            con.state.state = state;
                         }
-                        con.state.state = state;
                         let listeners = mem::replace(&mut con.state.state_change_listener, Vec::new());
                         Some((listeners, packet))
                     } else {
@@ -439,11 +1574,30 @@ impl DefaultPacketHandlerStream {
                 }
             };
 
-            if is_end {
+            if let Some(reason) = is_end {
                 // Remove the connection
                 let data = data.upgrade().unwrap();
                 let mut data = data.borrow_mut();
-                data.connections.remove(&addr);
+                data.remove_connection(addr, reason);
+            }
+
+            if let Some(puzzle) = puzzle {
+                solve_rsa_puzzle(data.upgrade().unwrap(), addr, puzzle);
+            }
+
+            if let Some(answer) = answer {
+                let data = data.upgrade().unwrap();
+                let (handle, logger) = {
+                    let data = data.borrow();
+                    (data.handle.clone(), data.logger.clone())
+                };
+                handle.spawn(ClientData::get_packets(data)
+                    .send((addr, answer))
+                    .map(|_| ())
+                    .map_err(move |e| {
+                        error!(logger, "Failed to send connection info";
+                            "error" => ?e);
+                    }));
             }
 
             if let Some((mut listeners, p)) = packet_res {
@@ -451,13 +1605,6 @@ impl DefaultPacketHandlerStream {
                 let l_fut = future::join_all(listeners.drain(..).map(|mut l| l()).collect::<Vec<_>>());
 
                 if let Some(p) = p {
-                    if let packets::Data::Command(ref cmd) = p.data {
-                        if cmd.command == "clientinit" {
-                            if !send_clientinit {
-                                return Box::new(l_fut.and_then(move |_| future::ok(None)));
-                            }
-                        }
-                    }
                     // Take sink
                     let tmp_sink = mem::replace(&mut *sink.borrow_mut(), Either::B(None));
                     let tmp_sink = if let Either::A(sink) = tmp_sink {
@@ -465,9 +1612,13 @@ impl DefaultPacketHandlerStream {
                     } else {
                         unreachable!("Sink is not available");
                     };
-                    // Send the packet
+                    // Send the packets
+                    let packets = iter::once(p).chain(next_packet)
+                        .map(|p| (addr, p)).collect::<Vec<_>>();
                     let sink = sink.clone();
-                    Box::new(l_fut.and_then(move |_| tmp_sink.send((addr, p)).map(move |tmp_sink| {
+                    Box::new(l_fut.and_then(move |_| tmp_sink
+                        .send_all(stream::iter_ok::<_, Error>(packets))
+                        .map(move |(tmp_sink, _)| {
                         let s: Either<InnerSink, Option<Task>> = mem::replace(&mut *sink.borrow_mut(), Either::A(tmp_sink));
                         if let Either::B(Some(task)) = s {
                             // Notify the task, that the sink is available
@@ -578,13 +1729,11 @@ impl<
         data: Rc<RefCell<ClientData>>,
         inner_stream: InnerStream,
         inner_sink: InnerSink,
-        send_clientinit: bool,
     ) -> Self {
         let (inner_stream, inner_sink) = DefaultPacketHandlerStream::new(
             data,
             inner_stream,
             inner_sink,
-            send_clientinit,
         );
         let inner_sink = DefaultPacketHandlerSink::new(inner_sink);
         Self {
@@ -607,7 +1756,7 @@ impl
     DefaultPacketHandler<
         Box<Sink<SinkItem = (SocketAddr, Packet), SinkError = Error>>,
     > {
-    pub fn apply(data: Rc<RefCell<ClientData>>, send_clientinit: bool) {
+    pub fn apply(data: Rc<RefCell<ClientData>>) {
         let (stream, sink) = {
             let mut data = data.borrow_mut();
             (
@@ -615,10 +1764,822 @@ impl
                 data.packet_sink.take().unwrap(),
             )
         };
-        let handler = Self::new(data.clone(), stream, sink, send_clientinit);
+        let handler = Self::new(data.clone(), stream, sink);
         let (sink, stream) = handler.split();
+        let weak_data = Rc::downgrade(&data);
         let mut data = data.borrow_mut();
         data.packet_stream = Some(Box::new(stream));
         data.packet_sink = Some(Box::new(sink));
+
+        // Reconnect lost connections
+        let handle = data.handle.clone();
+        let logger = data.logger.clone();
+        data.disconnect_handler = Some(Box::new(
+            move |addr: SocketAddr,
+                  con: &mut ServerConnection,
+                  reason: &DisconnectReason| {
+                handle_disconnect(&weak_data, &handle, &logger, addr, con, reason)
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use futures::{future, Future, Sink, Stream};
+    use futures::unsync::{mpsc, oneshot};
+    use slog;
+    use tokio_core::reactor::Core;
+
+    use {base64, ErrorKind, Result};
+    use algorithms as algs;
+    use client::*;
+    use commands::Command;
+    use crypto::{CryptoProvider, Provider};
+    use handler_data::{ConnectedParams, DisconnectReason, MoveReason};
+    use identity::Identity;
+    use known_servers::{KeyChange, KeyChangeAction, KnownServer, KnownServers};
+    use packets::{self, *};
+    use resend::TimeoutConfig;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:9987".parse().unwrap()
+    }
+
+    /// Create a client, which is connected to `addr()` with the client id
+    /// `1`, and return the sender for received packets.
+    fn setup(
+        core: &Core,
+    ) -> (
+        Rc<RefCell<ClientData>>,
+        mpsc::UnboundedSender<(SocketAddr, Packet)>,
+    ) {
+        let (data, recv_send, _sent) = setup_with_sent(core);
+        (data, recv_send)
+    }
+
+    /// Like [`setup`], but also return the receiver for the packets, which
+    /// are sent by the client.
+    ///
+    /// [`setup`]: fn.setup.html
+    fn setup_with_sent(
+        core: &Core,
+    ) -> (
+        Rc<RefCell<ClientData>>,
+        mpsc::UnboundedSender<(SocketAddr, Packet)>,
+        mpsc::UnboundedReceiver<(SocketAddr, Packet)>,
+    ) {
+        ::init().unwrap();
+        let (_udp_send, udp_recv) = mpsc::unbounded();
+        let (udp_send, _udp_recv) = mpsc::unbounded();
+        let data = ClientData::with_transport(
+            "127.0.0.1:0".parse().unwrap(),
+            Identity::create().unwrap(),
+            core.handle(),
+            true,
+            slog::Logger::root(slog::Discard, o!()),
+            udp_recv.map_err(|_| "Channel closed".into()),
+            udp_send.sink_map_err(|_| "Channel closed".into()),
+        );
+        // Bypass the codec and resend layers
+        let (recv_send, recv) = mpsc::unbounded();
+        let (send, sent) = mpsc::unbounded();
+        {
+            let mut data = data.borrow_mut();
+            data.packet_stream =
+                Some(Box::new(recv.map_err(|_| "Channel closed".into())));
+            data.packet_sink =
+                Some(Box::new(send.sink_map_err(|_| "Channel closed".into())));
+        }
+        DefaultPacketHandler::apply(data.clone());
+
+        let mut con = ServerConnection::new(ServerConnectionData {
+            state_change_listener: Vec::new(),
+            state: ServerConnectionState::Connected,
+            options: ConnectOptions::new("Bot")
+                .reconnect(ReconnectPolicy::default()),
+            reconnect_attempts: 0,
+            client_state: OwnClientState::default(),
+        });
+        let key = Provider.generate_key().unwrap();
+        let mut params = ConnectedParams::new(key, vec![0; 20], [0; 8]);
+        params.c_id = 1;
+        con.params = Some(params);
+        data.borrow_mut().connections.insert(addr(), con);
+        (data, recv_send, sent)
+    }
+
+    /// Receive a `notifyclientleftview` for our client.
+    fn receive_left_view(
+        core: &mut Core,
+        data: &Rc<RefCell<ClientData>>,
+        send: &mpsc::UnboundedSender<(SocketAddr, Packet)>,
+        reason: MoveReason,
+    ) {
+        let mut command = Command::new("notifyclientleftview");
+        command.push("clid", "1");
+        command.push("reasonid", (reason as u8).to_string());
+        let packet = Packet::new(
+            Header::new(PacketType::Command),
+            packets::Data::Command(command),
+        );
+        send.unbounded_send((addr(), packet)).unwrap();
+        let (res, _) = core
+            .run(ClientData::get_packets(data.clone()).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert!(res.is_some());
+    }
+
+    #[test]
+    fn reconnect_after_server_shutdown() {
+        let mut core = Core::new().unwrap();
+        let (data, send) = setup(&core);
+        let (disconnect_send, disconnect_recv) = oneshot::channel();
+        data.borrow_mut()
+            .connections
+            .get_mut(&addr())
+            .unwrap()
+            .disconnect_listener
+            .push(disconnect_send);
+
+        receive_left_view(
+            &mut core,
+            &data,
+            &send,
+            MoveReason::ClientdisconnectServerShutdown,
+        );
+        {
+            let data = data.borrow();
+            let con = &data.connections[&addr()];
+            match con.state.state {
+                ServerConnectionState::Reconnecting { attempt: 1 } => {}
+                ref state => panic!("Unexpected state {:?}", state),
+            }
+            assert_eq!(con.state.reconnect_attempts, 1);
+            assert_eq!(con.disconnect_listener.len(), 1);
+        }
+        // The listener waits for the reconnected connection
+        let mut disconnect_recv = disconnect_recv;
+        let res = core.run(future::lazy(move || {
+            Ok::<_, ()>(disconnect_recv.poll())
+        })).unwrap();
+        assert!(res.unwrap().is_not_ready());
+    }
+
+    #[test]
+    fn no_reconnect_after_kick() {
+        let mut core = Core::new().unwrap();
+        let (data, send) = setup(&core);
+        receive_left_view(&mut core, &data, &send, MoveReason::KickServer);
+        assert!(data.borrow().connections.is_empty());
+    }
+
+    /// Create a client with the default setup, which sends and receives udp
+    /// packets through channels, and start to connect to `addr()`.
+    ///
+    /// Returns the receiver for the sent udp packets, the sender for received
+    /// udp packets and the result of the connect future.
+    fn start_connect(
+        core: &Core,
+        config: TimeoutConfig,
+    ) -> (
+        Rc<RefCell<ClientData>>,
+        mpsc::UnboundedReceiver<(SocketAddr, UdpPacket)>,
+        mpsc::UnboundedSender<(SocketAddr, UdpPacket)>,
+        oneshot::Receiver<Result<()>>,
+    ) {
+        ::init().unwrap();
+        let (recv_send, recv) = mpsc::unbounded();
+        let (send, sent) = mpsc::unbounded();
+        let data = ClientData::with_transport(
+            "127.0.0.1:0".parse().unwrap(),
+            Identity::create().unwrap(),
+            core.handle(),
+            true,
+            slog::Logger::root(slog::Discard, o!()),
+            recv.map_err(|_| "Channel closed".into()),
+            send.sink_map_err(|_| "Channel closed".into()),
+        );
+        data.borrow_mut().timeout_config = config;
+        default_setup(data.clone()).unwrap();
+
+        let handle = core.handle();
+        handle.spawn(
+            ClientData::get_packets(data.clone())
+                .for_each(|_| future::ok(()))
+                .map_err(|_| ()),
+        );
+        let (res_send, res_recv) = oneshot::channel();
+        handle.spawn(
+            connect(data.clone(), addr(), ConnectOptions::new("Bot")).then(
+                move |res| {
+                    let _ = res_send.send(res);
+                    Ok(())
+                },
+            ),
+        );
+        (data, sent, recv_send, res_recv)
+    }
+
+    /// Wait for the next udp packet of the client, which has to be an init
+    /// packet.
+    fn next_init(
+        core: &mut Core,
+        sent: &mut mpsc::UnboundedReceiver<(SocketAddr, UdpPacket)>,
+    ) -> (Vec<u8>, C2SInit) {
+        let (res, _) = core.run(sent.by_ref().into_future())
+            .map_err(|_| "Channel closed")
+            .unwrap();
+        let (to, UdpPacket(buf)) = res.unwrap();
+        assert_eq!(to, addr());
+        let packet = Packet::read(&true, &mut Cursor::new(&buf)).unwrap();
+        match packet.data {
+            packets::Data::C2SInit(init) => (buf, init),
+            _ => panic!("Expected an init packet"),
+        }
+    }
+
+    /// Send an init packet from the server to the client.
+    fn send_init(
+        send: &mpsc::UnboundedSender<(SocketAddr, UdpPacket)>,
+        init: S2CInit,
+    ) {
+        let mut header = Header::new(PacketType::Init);
+        header.mac.copy_from_slice(b"TS3INIT1");
+        header.p_id = 0x65;
+        header.set_unencrypted(true);
+        let packet = Packet::new(header, packets::Data::S2CInit(init));
+        let mut buf = Vec::new();
+        packet.write(&mut buf).unwrap();
+        send.unbounded_send((addr(), UdpPacket(buf))).unwrap();
+    }
+
+    /// The number of stored init packets, which wait for an answer.
+    fn queued_inits(data: &Rc<RefCell<ClientData>>) -> usize {
+        data.borrow().connections[&addr()]
+            .send_queue
+            .iter()
+            .filter(|r| r.p_type == PacketType::Init)
+            .count()
+    }
+
+    #[test]
+    fn resend_init_packets() {
+        let mut core = Core::new().unwrap();
+        let config = TimeoutConfig {
+            connecting_interval: Duration::milliseconds(100),
+            connecting_timeout: Duration::milliseconds(500),
+            ..TimeoutConfig::default()
+        };
+        let interval = config.connecting_interval.to_std().unwrap();
+        let (data, mut sent, send, res) = start_connect(&core, config);
+
+        // Init0 is resent until the server answers
+        let (init0, packet) = next_init(&mut core, &mut sent);
+        let start = Instant::now();
+        let random0 = match packet {
+            C2SInit::Init0 { random0, .. } => random0,
+            _ => panic!("Expected Init0"),
+        };
+        let (resent, _) = next_init(&mut core, &mut sent);
+        assert!(start.elapsed() >= interval / 2);
+        assert_eq!(resent, init0);
+        assert_eq!(queued_inits(&data), 1);
+
+        // Init1 replaces Init0 with Init2
+        let mut random0_r = random0;
+        random0_r.reverse();
+        send_init(
+            &send,
+            S2CInit::Init1 {
+                random1: [1; 16],
+                random0_r,
+            },
+        );
+        let (init2, packet) = next_init(&mut core, &mut sent);
+        let start = Instant::now();
+        match packet {
+            C2SInit::Init2 { random1, .. } => assert_eq!(random1, [1; 16]),
+            _ => panic!("Expected Init2"),
+        }
+        assert_eq!(queued_inits(&data), 1);
+        let (resent, _) = next_init(&mut core, &mut sent);
+        assert!(start.elapsed() >= interval / 2);
+        assert_eq!(resent, init2);
+
+        // Init3 replaces Init2 with Init4, after the puzzle is solved
+        let mut n = [0; 64];
+        n[63] = 7;
+        let mut x = [0; 64];
+        x[63] = 3;
+        send_init(
+            &send,
+            S2CInit::Init3 {
+                x,
+                n,
+                level: 1,
+                random2: [2; 100],
+            },
+        );
+        let (init4, packet) = next_init(&mut core, &mut sent);
+        let start = Instant::now();
+        match packet {
+            // 3 ^ 2 % 7
+            C2SInit::Init4 { y, .. } => assert_eq!(y[63], 2),
+            _ => panic!("Expected Init4"),
+        }
+        assert_eq!(queued_inits(&data), 1);
+        let (resent, _) = next_init(&mut core, &mut sent);
+        assert!(start.elapsed() >= interval / 2);
+        assert_eq!(resent, init4);
+
+        // The server does not answer the Init4
+        match *core.run(res).unwrap().unwrap_err().kind() {
+            ErrorKind::Disconnected(DisconnectReason::HandshakeFailed(_)) => {}
+            ref e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(data.borrow().connections.is_empty());
+    }
+
+    #[test]
+    fn connecting_timeout() {
+        let mut core = Core::new().unwrap();
+        let config = TimeoutConfig {
+            connecting_interval: Duration::milliseconds(100),
+            connecting_timeout: Duration::milliseconds(300),
+            ..TimeoutConfig::default()
+        };
+        let timeout = config.connecting_timeout.to_std().unwrap();
+        let start = Instant::now();
+        let (data, _sent, _send, res) = start_connect(&core, config);
+
+        // The server never answers
+        match *core.run(res).unwrap().unwrap_err().kind() {
+            ErrorKind::Disconnected(DisconnectReason::HandshakeFailed(_)) => {}
+            ref e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(start.elapsed() >= timeout);
+        assert!(data.borrow().connections.is_empty());
+    }
+
+    #[test]
+    fn hash_passwords() {
+        assert_eq!(Password::from("").get_hashed(), "");
+        assert_eq!(
+            Password::from("secret").get_hashed(),
+            "5en6G6MezRroT3XKqkdPOmY/BfQ="
+        );
+        assert_eq!(
+            Password::from(String::from("secret")),
+            Password::Plain("secret".into())
+        );
+        assert_eq!(Password::Hashed("hash".into()).get_hashed(), "hash");
+        // Passwords are not logged
+        assert_eq!(format!("{:?}", Password::from("secret")), "Plain(..)");
+        assert_eq!(format!("{:?}", Password::Hashed("h".into())), "Hashed(..)");
+    }
+
+    #[test]
+    fn clientinit_from_options() {
+        ::init().unwrap();
+        let identity = Identity::new(Provider.generate_key().unwrap(), 42);
+        let options = ConnectOptions::new("Bot")
+            .nickname("Other bot")
+            .phonetic_nickname("bot")
+            .channel("Lobby/Sub")
+            .channel_password("channel")
+            .server_password(Password::Hashed("hash".into()))
+            .token("token")
+            .hwid("hw,id");
+        let packet = create_clientinit(&identity, &options);
+        assert_eq!(packet.header.get_type(), PacketType::Command);
+        let command = match packet.data {
+            packets::Data::Command(command) => command,
+            _ => panic!("Expected a command"),
+        };
+        let cmds = command.get_commands();
+        let cmd = &cmds[0];
+        let (version, platform, signature) = Version::Linux3_1_6.get_parts();
+        assert_eq!(cmd.command, "clientinit");
+        assert_eq!(cmd.args["client_nickname"], "Other bot");
+        assert_eq!(cmd.args["client_nickname_phonetic"], "bot");
+        assert_eq!(cmd.args["client_version"], version);
+        assert_eq!(cmd.args["client_platform"], platform);
+        assert_eq!(cmd.args["client_version_sign"], signature);
+        assert_eq!(cmd.args["client_default_channel"], "Lobby/Sub");
+        assert_eq!(
+            cmd.args["client_default_channel_password"],
+            algs::hash_password("channel")
+        );
+        assert_eq!(cmd.args["client_server_password"], "hash");
+        assert_eq!(cmd.args["client_key_offset"], "42");
+        assert_eq!(cmd.args["client_default_token"], "token");
+        assert_eq!(cmd.args["hwid"], "hw,id");
+
+        // Defaults
+        let packet = create_clientinit(&identity, &ConnectOptions::new("Bot"));
+        let command = match packet.data {
+            packets::Data::Command(command) => command,
+            _ => panic!("Expected a command"),
+        };
+        let cmds = command.get_commands();
+        let cmd = &cmds[0];
+        assert_eq!(cmd.args["client_server_password"], "");
+        assert_eq!(cmd.args["client_default_channel"], "");
+        assert_eq!(cmd.args["hwid"], DEFAULT_HWID);
+    }
+
+    #[test]
+    fn reconnect_options() {
+        let mut state = ServerConnectionData {
+            state_change_listener: Vec::new(),
+            state: ServerConnectionState::Connected,
+            options: ConnectOptions::new("Bot").channel("Lobby"),
+            reconnect_attempts: 0,
+            client_state: OwnClientState {
+                channel_id: Some(5),
+                nickname: Some("Bot1".into()),
+                ..OwnClientState::default()
+            },
+        };
+        let options = get_connect_options(&state);
+        assert_eq!(options.nickname, "Bot");
+        assert_eq!(options.channel, "Lobby");
+
+        // Use the last nickname and channel when reconnecting
+        state.reconnect_attempts = 1;
+        let options = get_connect_options(&state);
+        assert_eq!(options.nickname, "Bot1");
+        assert_eq!(options.channel, "/5");
+    }
+
+    #[test]
+    fn send_clientinit_after_handshake() {
+        let mut core = Core::new().unwrap();
+        let (data, send, sent) = setup_with_sent(&core);
+        let alpha = [1; 10];
+        {
+            let mut data = data.borrow_mut();
+            let con = data.connections.get_mut(&addr()).unwrap();
+            con.state.state = ServerConnectionState::ClientInitIv { alpha };
+            con.state.options =
+                ConnectOptions::new("Bot").server_password("secret");
+            con.params = None;
+        }
+        core.handle().spawn(
+            ClientData::get_packets(data.clone())
+                .for_each(|_| future::ok(()))
+                .map_err(|_| ()),
+        );
+
+        // Answer with the old handshake
+        let mut server_key = Provider.generate_key().unwrap();
+        let omega = Provider.export_public_key(&mut server_key).unwrap();
+        let mut command = Command::new("initivexpand");
+        command.push("alpha", base64::encode(&alpha));
+        command.push("beta", base64::encode(&[2u8; 10]));
+        command.push("omega", base64::encode(&omega));
+        let packet = Packet::new(
+            Header::new(PacketType::Command),
+            packets::Data::Command(command),
+        );
+        send.unbounded_send((addr(), packet)).unwrap();
+
+        let (res, _) = core.run(sent.into_future())
+            .map_err(|_| "Channel closed")
+            .unwrap();
+        let (to, packet) = res.unwrap();
+        assert_eq!(to, addr());
+        let command = match packet.data {
+            packets::Data::Command(command) => command,
+            _ => panic!("Expected a command"),
+        };
+        let cmds = command.get_commands();
+        let cmd = &cmds[0];
+        assert_eq!(cmd.command, "clientinit");
+        assert_eq!(cmd.args["client_nickname"], "Bot");
+        assert_eq!(
+            cmd.args["client_server_password"],
+            "5en6G6MezRroT3XKqkdPOmY/BfQ="
+        );
+        assert_eq!(
+            cmd.args["client_key_offset"],
+            data.borrow().identity.key_offset().to_string()
+        );
+
+        let data = data.borrow();
+        let con = &data.connections[&addr()];
+        assert!(con.params.is_some());
+        match con.state.state {
+            ServerConnectionState::Connecting { clientinit_id: 1 } => {}
+            ref state => panic!("Unexpected state {:?}", state),
+        }
+    }
+
+    /// An `initivexpand2` answer with the license chain of a server without a
+    /// license, like it is sent by the server.
+    ///
+    /// The P-256 key of the server (`omega`) and the `proof` were created for
+    /// this test, the expected values were computed independently of this
+    /// crate.
+    mod initivexpand2 {
+        pub const ALPHA: [u8; 10] = [10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+        pub const BETA: &str = "AwoRGB8mLTQ7QklQV15lbHN6gYiPlp2kq7K5wMfO1dzj6v\
+            H4/wYNFBsiKTA3PkVMU1phaG92";
+        pub const L: &str = "AQA1hUFJiiSs0wFXkYuPUJVcDa6XCrZTcsvkB0Ffzz4CmwII\
+            TRXgCqeTYAcAAAAgQW5vbnltb3VzAAC4R+5mos+UQ/KCbkpQLMI5WRp4wkQu8e5PZY4\
+            zU+/FlyAJwaE8CcJJ/A==";
+        pub const OMEGA: &str = "ME0DAgcAAgEgAiEAsaNhz+BvVtHj8+rBYgWCmhU28T5L\
+            Xp7qgdkC4aBS2z4CIQDJ23DEWWCdhQGiqcisFk9T4YIcxd4iu9RY9q2RgvKQRg==";
+        pub const PROOF: &str = "MEUCIDRgA34ThKIvbHfc0GEeExEcWQKEVQMQAOJm3k5X\
+            xHzjAiEAvWxNUduxOp05gjuYvuJkvZlaYC+TrZ22KMv7JKjdELU=";
+
+        /// The temporary private key of the client.
+        pub const EK_PRIVATE: [u8; 32] = [
+            0x90, 0x5a, 0x59, 0xf9, 0x8b, 0x55, 0xa9, 0x60, 0x72, 0x74, 0x62,
+            0x48, 0x44, 0x58, 0xd0, 0x63, 0x6a, 0xae, 0x82, 0x80, 0xfb, 0xc6,
+            0xfe, 0x95, 0x58, 0x1e, 0xa3, 0x35, 0x05, 0x20, 0x7f, 0x46,
+        ];
+        /// The `ek` of the `clientek` answer.
+        pub const EK: &str = "iE3fpfsRUJSvI8DWcVDt1t5UwzWQK9aRyVNkDwB5LbI=";
+        pub const IV: &str = "d048d81f0d9b2d04f237f8d9c77e7c1830404fb29ebaa7ac\
+            939c51d9ce998849c679a0053dd49e44c0a015c7894696cab349a03bb2c51bb3696\
+            35e63c9f6399e";
+        pub const MAC: [u8; 8] =
+            [0x78, 0x0b, 0x5a, 0x44, 0xe7, 0x2a, 0x5f, 0x66];
+    }
+
+    fn initivexpand2_command(proof: &str) -> Command {
+        let mut command = Command::new("initivexpand2");
+        command.push("l", initivexpand2::L);
+        command.push("beta", initivexpand2::BETA);
+        command.push("omega", initivexpand2::OMEGA);
+        command.push("ot", "1");
+        command.push("proof", proof);
+        command
+    }
+
+    #[test]
+    fn handle_initivexpand2_vector() {
+        ::init().unwrap();
+        let mut identity = Identity::create().unwrap();
+        // The ephemeral license of the chain is valid for a short time
+        let now = Utc.ymd(2018, 3, 10).and_hms(12, 0, 0);
+        let command = initivexpand2_command(initivexpand2::PROOF);
+        let cmd = command.get_commands().remove(0);
+        let (mut params, clientek) = handle_initivexpand2_with(
+            &initivexpand2::ALPHA,
+            &cmd,
+            &mut identity,
+            &initivexpand2::EK_PRIVATE,
+            now,
+        ).unwrap();
+
+        let expected_iv = (0..64)
+            .map(|i| {
+                u8::from_str_radix(&initivexpand2::IV[(i * 2)..(i * 2 + 2)], 16)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(params.shared_iv, expected_iv);
+        assert_eq!(params.shared_mac, initivexpand2::MAC);
+        assert_eq!(
+            Provider.export_public_key(&mut params.public_key).unwrap(),
+            base64::decode(initivexpand2::OMEGA).unwrap()
+        );
+
+        // clientek contains our temporary key, signed with our identity
+        let command = match clientek.data {
+            packets::Data::Command(command) => command,
+            _ => panic!("Expected a command"),
+        };
+        let cmds = command.get_commands();
+        let cmd = &cmds[0];
+        assert_eq!(cmd.command, "clientek");
+        assert_eq!(cmd.args["ek"], initivexpand2::EK);
+        let mut sign_data = base64::decode(initivexpand2::EK).unwrap();
+        sign_data.extend_from_slice(
+            &base64::decode(initivexpand2::BETA).unwrap(),
+        );
+        let proof = base64::decode(cmd.args["proof"]).unwrap();
+        Provider
+            .verify(identity.key_mut(), &sign_data, &proof)
+            .unwrap();
+    }
+
+    #[test]
+    fn handle_initivexpand2_invalid() {
+        ::init().unwrap();
+        let mut identity = Identity::create().unwrap();
+        let now = Utc.ymd(2018, 3, 10).and_hms(12, 0, 0);
+        let handle = |command: Command,
+                      identity: &mut Identity,
+                      now: DateTime<Utc>| {
+            let cmd = command.get_commands().remove(0);
+            handle_initivexpand2_with(
+                &initivexpand2::ALPHA,
+                &cmd,
+                identity,
+                &initivexpand2::EK_PRIVATE,
+                now,
+            ).map(|_| ())
+        };
+
+        // The license chain is not signed by the server
+        let mut proof = base64::decode(initivexpand2::PROOF).unwrap();
+        proof[10] ^= 1;
+        let command = initivexpand2_command(&base64::encode(&proof));
+        assert!(handle(command, &mut identity, now).is_err());
+
+        // The ephemeral license expired
+        let command = initivexpand2_command(initivexpand2::PROOF);
+        let later = Utc.ymd(2019, 1, 1).and_hms(0, 0, 0);
+        assert!(handle(command, &mut identity, later).is_err());
+
+        // Missing arguments
+        let mut command = Command::new("initivexpand2");
+        command.push("beta", initivexpand2::BETA);
+        command.push("omega", initivexpand2::OMEGA);
+        assert!(handle(command, &mut identity, now).is_err());
+
+        let command = initivexpand2_command(initivexpand2::PROOF);
+        assert!(handle(command, &mut identity, now).is_ok());
+    }
+
+    #[test]
+    fn report_key_change() {
+        let mut core = Core::new().unwrap();
+        let (data, send, sent) = setup_with_sent(&core);
+        let alpha = [1; 10];
+        let known =
+            Rc::new(RefCell::new(KnownServers::new(KeyChangeAction::Warn)));
+        known.borrow_mut().insert(addr(), vec![1, 2, 3]);
+        let (change_send, change_recv) = mpsc::unbounded();
+        {
+            let mut data = data.borrow_mut();
+            let con = data.connections.get_mut(&addr()).unwrap();
+            con.state.state = ServerConnectionState::ClientInitIv { alpha };
+            con.state.options = ConnectOptions::new("Bot")
+                .known_servers(known.clone())
+                .key_change_listener(change_send);
+            con.params = None;
+        }
+        core.handle().spawn(
+            ClientData::get_packets(data.clone())
+                .for_each(|_| future::ok(()))
+                .map_err(|_| ()),
+        );
+
+        let mut server_key = Provider.generate_key().unwrap();
+        let omega = Provider.export_public_key(&mut server_key).unwrap();
+        let mut command = Command::new("initivexpand");
+        command.push("alpha", base64::encode(&alpha));
+        command.push("beta", base64::encode(&[2u8; 10]));
+        command.push("omega", base64::encode(&omega));
+        let packet = Packet::new(
+            Header::new(PacketType::Command),
+            packets::Data::Command(command),
+        );
+        send.unbounded_send((addr(), packet)).unwrap();
+
+        // The connection continues with the new key
+        let (res, _) = core.run(sent.into_future())
+            .map_err(|_| "Channel closed")
+            .unwrap();
+        assert!(res.is_some());
+        let (change, _) = core.run(change_recv.into_future())
+            .map_err(|_| "Channel closed")
+            .unwrap();
+        assert_eq!(
+            change,
+            Some(KeyChange {
+                addr: addr(),
+                old: KnownServer::new(vec![1, 2, 3]),
+                new: KnownServer::new(omega.clone()),
+            })
+        );
+        assert_eq!(known.borrow().get(&addr()).unwrap().public_key, omega);
+    }
+
+    #[test]
+    fn upgrade_identity_before_connecting() {
+        let mut core = Core::new().unwrap();
+        let (data, mut sent, _send, _res) =
+            start_connect(&core, TimeoutConfig::default());
+        assert!(
+            data.borrow_mut().identity.level().unwrap()
+                < DEFAULT_HASH_CASH_LEVEL
+        );
+
+        // Init0 is sent after the security level was increased
+        next_init(&mut core, &mut sent);
+        assert!(
+            data.borrow_mut().identity.level().unwrap()
+                >= DEFAULT_HASH_CASH_LEVEL
+        );
+    }
+
+    /// Receive a `notifyconnectioninforequest`, which is passed on to the
+    /// packet stream.
+    fn request_connection_info(
+        core: &mut Core,
+        data: &Rc<RefCell<ClientData>>,
+        send: &mpsc::UnboundedSender<(SocketAddr, Packet)>,
+    ) {
+        let packet = Packet::new(
+            Header::new(PacketType::Command),
+            packets::Data::Command(Command::new("notifyconnectioninforequest")),
+        );
+        send.unbounded_send((addr(), packet)).unwrap();
+        let (res, _) = core
+            .run(ClientData::get_packets(data.clone()).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert!(res.is_some());
+    }
+
+    #[test]
+    fn answer_connection_info() {
+        let mut core = Core::new().unwrap();
+        let (data, send, sent) = setup_with_sent(&core);
+        {
+            let mut data = data.borrow_mut();
+            let con = data.connections.get_mut(&addr()).unwrap();
+            con.ping_stats.ping = Duration::microseconds(25_500);
+            con.ping_stats.ping_deviation = Duration::microseconds(1_250);
+            con.stats.packet_sent(PacketType::Voice, 100);
+            con.stats.packet_sent(PacketType::VoiceWhisper, 50);
+            con.stats.packet_sent(PacketType::Ping, 10);
+            con.stats.packet_received(PacketType::Pong, 12);
+            con.stats.packet_received(PacketType::Command, 300);
+            con.stats.packet_received(PacketType::Ack, 20);
+        }
+        request_connection_info(&mut core, &data, &send);
+
+        let (res, _) = core.run(sent.into_future())
+            .map_err(|_| "Channel closed")
+            .unwrap();
+        let (to, packet) = res.unwrap();
+        assert_eq!(to, addr());
+        let command = match packet.data {
+            packets::Data::Command(command) => command,
+            _ => panic!("Expected a command"),
+        };
+        let cmds = command.get_commands();
+        let cmd = &cmds[0];
+        assert_eq!(cmd.command, "setconnectioninfo");
+        let expected = [
+            ("connection_ping", "25.5000"),
+            ("connection_ping_deviation", "1.2500"),
+            ("connection_packets_sent_speech", "2"),
+            ("connection_bytes_sent_speech", "150"),
+            ("connection_packets_received_speech", "0"),
+            ("connection_bytes_received_speech", "0"),
+            ("connection_packets_sent_keepalive", "1"),
+            ("connection_bytes_sent_keepalive", "10"),
+            ("connection_packets_received_keepalive", "1"),
+            ("connection_bytes_received_keepalive", "12"),
+            ("connection_packets_sent_control", "0"),
+            ("connection_bytes_sent_control", "0"),
+            ("connection_packets_received_control", "2"),
+            ("connection_bytes_received_control", "320"),
+            ("connection_server2client_packetloss_speech", "0.0000"),
+            ("connection_server2client_packetloss_keepalive", "0.0000"),
+            ("connection_server2client_packetloss_control", "0.0000"),
+            ("connection_server2client_packetloss_total", "0.0000"),
+        ];
+        for &(arg, value) in &expected {
+            assert_eq!((arg, cmd.args[arg]), (arg, value));
+        }
+        assert_eq!(cmd.args.len(), expected.len());
+    }
+
+    #[test]
+    fn no_connection_info_answer() {
+        let mut core = Core::new().unwrap();
+        let (data, send, sent) = setup_with_sent(&core);
+        {
+            let mut data = data.borrow_mut();
+            let con = data.connections.get_mut(&addr()).unwrap();
+            con.state.options =
+                ConnectOptions::new("Bot").answer_connection_info(false);
+        }
+        request_connection_info(&mut core, &data, &send);
+
+        // Give a spawned answer the chance to be sent
+        core.turn(Some(Duration::milliseconds(50).to_std().unwrap()));
+        let mut sent = sent;
+        let res = core.run(future::lazy(move || {
+            Ok::<_, ()>(sent.poll())
+        })).unwrap();
+        assert!(res.unwrap().is_not_ready());
     }
 }
@@ -0,0 +1,210 @@
+//! Exchangeable backends for the cryptographic primitives of the protocol.
+//!
+//! The protocol uses the `Provider`, which is the [`TomcryptProvider`] if the
+//! `tomcrypt` feature is enabled (the default) and the [`RustCryptoProvider`]
+//! otherwise.
+//!
+//! [`TomcryptProvider`]: struct.TomcryptProvider.html
+//! [`RustCryptoProvider`]: ../rust_crypto/struct.RustCryptoProvider.html
+#[cfg(feature = "tomcrypt")]
+use tomcrypt;
+
+use Result;
+#[cfg(feature = "tomcrypt")]
+use rust_crypto::RustCryptoProvider;
+
+/// The crypto backend, which is used by the protocol.
+#[cfg(feature = "tomcrypt")]
+pub use self::TomcryptProvider as Provider;
+/// The crypto backend, which is used by the protocol.
+#[cfg(not(feature = "tomcrypt"))]
+pub use rust_crypto::RustCryptoProvider as Provider;
+
+/// A key of the crypto backend, which is used by the protocol.
+pub type EccKey = <Provider as CryptoProvider>::EccKey;
+
+/// The cryptographic operations, which are needed by the protocol.
+///
+/// The protocol uses elliptic curve keys on the NIST P-256 curve, AES-128 in
+/// EAX mode with 8 byte tags and SHA hashes. Keys are imported and exported in
+/// the DER format of libtomcrypt, which is also used by the official client.
+pub trait CryptoProvider {
+    /// An elliptic curve key, which contains either only the public key or
+    /// also the private key.
+    type EccKey;
+
+    /// Generate a new random private key.
+    fn generate_key(&self) -> Result<Self::EccKey>;
+    /// Import a public or private key.
+    fn import_key(&self, data: &[u8]) -> Result<Self::EccKey>;
+    fn export_public_key(&self, key: &mut Self::EccKey) -> Result<Vec<u8>>;
+    /// Fails if the key contains only a public key.
+    fn export_private_key(&self, key: &mut Self::EccKey) -> Result<Vec<u8>>;
+    /// Compute the shared secret of the ECDH key exchange.
+    ///
+    /// The secret is the x coordinate of the shared point, padded with
+    /// leading zero bytes to 32 bytes like libtomcrypt does.
+    fn shared_secret(
+        &self,
+        private_key: &mut Self::EccKey,
+        public_key: &mut Self::EccKey,
+    ) -> Result<Vec<u8>>;
+    /// Sign the SHA-256 hash of `data` with ECDSA.
+    ///
+    /// The signature is returned in the DER format.
+    fn sign(&self, key: &mut Self::EccKey, data: &[u8]) -> Result<Vec<u8>>;
+    /// Check an ECDSA signature, which was created by [`sign`].
+    ///
+    /// Returns an error if the signature is wrong.
+    ///
+    /// [`sign`]: #tymethod.sign
+    fn verify(
+        &self,
+        key: &mut Self::EccKey,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<()>;
+
+    /// Encrypt `data` in place with AES-128 in EAX mode and return the mac,
+    /// which also authenticates the `header`.
+    fn eax_encrypt(
+        &self,
+        key: &[u8; 16],
+        nonce: &[u8; 16],
+        header: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; 8]>;
+    /// Decrypt `data` in place and check the `mac`.
+    ///
+    /// If the mac is wrong, an error is returned and the content of `data` is
+    /// undefined.
+    fn eax_decrypt(
+        &self,
+        key: &[u8; 16],
+        nonce: &[u8; 16],
+        header: &[u8],
+        data: &mut [u8],
+        mac: &[u8; 8],
+    ) -> Result<()>;
+
+    fn sha1(&self, data: &[u8]) -> [u8; 20];
+    fn sha256(&self, data: &[u8]) -> [u8; 32];
+    fn sha512(&self, data: &[u8]) -> [u8; 64];
+}
+
+/// The crypto backend using the libtomcrypt C library.
+///
+/// [`init`] has to be called before this backend is used.
+///
+/// [`init`]: ../fn.init.html
+#[cfg(feature = "tomcrypt")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TomcryptProvider;
+
+#[cfg(feature = "tomcrypt")]
+impl CryptoProvider for TomcryptProvider {
+    type EccKey = tomcrypt::EccKey;
+
+    fn generate_key(&self) -> Result<Self::EccKey> {
+        Ok(tomcrypt::EccKey::new(tomcrypt::sprng(), 32)?)
+    }
+
+    fn import_key(&self, data: &[u8]) -> Result<Self::EccKey> {
+        Ok(tomcrypt::EccKey::import(data)?)
+    }
+
+    fn export_public_key(&self, key: &mut Self::EccKey) -> Result<Vec<u8>> {
+        Ok(key.export_public()?)
+    }
+
+    fn export_private_key(&self, key: &mut Self::EccKey) -> Result<Vec<u8>> {
+        Ok(key.export_private()?)
+    }
+
+    fn shared_secret(
+        &self,
+        private_key: &mut Self::EccKey,
+        public_key: &mut Self::EccKey,
+    ) -> Result<Vec<u8>> {
+        Ok(tomcrypt::EccKey::create_shared_secret(
+            private_key,
+            public_key,
+            32,
+        )?)
+    }
+
+    fn sign(&self, key: &mut Self::EccKey, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = self.sha256(data);
+        Ok(key.sign_hash(&hash, tomcrypt::sprng())?)
+    }
+
+    fn verify(
+        &self,
+        key: &mut Self::EccKey,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let hash = self.sha256(data);
+        if key.verify_hash(&hash, signature)? {
+            Ok(())
+        } else {
+            Err("Wrong signature".into())
+        }
+    }
+
+    fn eax_encrypt(
+        &self,
+        key: &[u8; 16],
+        nonce: &[u8; 16],
+        header: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; 8]> {
+        let mut eax = tomcrypt::EaxState::new(
+            tomcrypt::rijndael(),
+            key,
+            nonce,
+            Some(header),
+        )?;
+        eax.encrypt_in_place(data)?;
+        let mut mac = [0; 8];
+        mac.copy_from_slice(&eax.finish(8)?);
+        Ok(mac)
+    }
+
+    fn eax_decrypt(
+        &self,
+        key: &[u8; 16],
+        nonce: &[u8; 16],
+        header: &[u8],
+        data: &mut [u8],
+        mac: &[u8; 8],
+    ) -> Result<()> {
+        let mut eax = tomcrypt::EaxState::new(
+            tomcrypt::rijndael(),
+            key,
+            nonce,
+            Some(header),
+        )?;
+        eax.decrypt_in_place(data)?;
+        if !mac.iter().eq(&eax.finish(8)?) {
+            Err("Packet has wrong mac".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // libtomcrypt is only used for the elliptic curves and AES, the hashes
+    // are the same for both backends.
+
+    fn sha1(&self, data: &[u8]) -> [u8; 20] {
+        RustCryptoProvider.sha1(data)
+    }
+
+    fn sha256(&self, data: &[u8]) -> [u8; 32] {
+        RustCryptoProvider.sha256(data)
+    }
+
+    fn sha512(&self, data: &[u8]) -> [u8; 64] {
+        RustCryptoProvider.sha512(data)
+    }
+}
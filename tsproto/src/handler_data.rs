@@ -1,21 +1,31 @@
 use std::cell::RefCell;
 use std::cmp::{Ord, Ordering};
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
 use std::u16;
 
-use {slog, slog_async, slog_term, tomcrypt};
+use {slog, slog_async, slog_term};
 use chrono::{DateTime, Duration, Utc};
 use futures::{self, Sink, Stream};
 use futures::task::Task;
+use futures::unsync::oneshot;
 use num::ToPrimitive;
 use slog::Drain;
 use tokio_core::net::UdpSocket;
 use tokio_core::reactor::Handle;
 
 use {Error, Map, Result, TsCodec};
+use algorithms::KeyNonceCache;
+use buffer_pool::BufferPool;
+use crypto::EccKey;
+use identity::Identity;
 use packets::*;
+use ping::PingStats;
+use resend::{ResendState, TimeoutConfig};
+use stats::{ConnectionStats, StatsSnapshot};
 
 /// A record of a packet that can be resent.
 pub struct SendRecord {
@@ -59,10 +69,14 @@ pub struct Data<ConnectionState> {
     pub is_client: bool,
     /// The address of the socket.
     pub local_addr: SocketAddr,
-    /// The private key of this instance.
-    pub private_key: tomcrypt::EccKey,
+    /// The identity of this instance, which contains our private key.
+    pub identity: Identity,
     pub handle: Handle,
     pub logger: slog::Logger,
+    /// Buffers which are reused for sent and received udp packets.
+    pub buffer_pool: BufferPool,
+    /// The timeouts which are used for connections of this instance.
+    pub timeout_config: TimeoutConfig,
 
     /// The raw udp stream.
     pub raw_stream:
@@ -91,12 +105,22 @@ pub struct Data<ConnectionState> {
 
     /// A list of all connected clients or servers
     pub connections: Map<SocketAddr, Connection<ConnectionState>>,
-
-    /// A queue of packets that where sent and when they were sent.
+    /// Gets called when a connection is removed because of the given reason.
     ///
-    /// Used to resend packets when they are not acknowledged in a certain time.
-    /// That is only done for `Command` and `CommandLow` packets.
-    pub send_queue: BinaryHeap<SendRecord>,
+    /// If it returns a new connection, the removed connection is replaced and
+    /// the `disconnect_listener`s are moved to the new connection. This is
+    /// used to reconnect.
+    pub disconnect_handler: Option<
+        Box<
+            FnMut(
+                SocketAddr,
+                &mut Connection<ConnectionState>,
+                &DisconnectReason,
+            ) -> Option<Connection<ConnectionState>>,
+        >,
+    >,
+
+    /// The task which resends packets.
     pub(crate) resend_task: Option<Task>,
 }
 
@@ -118,6 +142,118 @@ pub struct DataPackets<CS> {
     data: Weak<RefCell<Data<CS>>>,
 }
 
+/// The reason why a client was moved or removed from the server, as sent in
+/// the `reasonid` of `notifyclientleftview` and `notifyclientmoved`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, FromPrimitive, ToPrimitive)]
+pub enum MoveReason {
+    /// No reason data
+    None,
+    /// Has invoker
+    Moved,
+    /// No reason data
+    Subscription,
+    LostConnection,
+    /// Has invoker
+    KickChannel,
+    /// Has invoker
+    KickServer,
+    /// Has invoker, bantime
+    KickServerBan,
+    Serverstop,
+    Clientdisconnect,
+    /// No reason data
+    Channelupdate,
+    /// Has invoker
+    Channeledit,
+    ClientdisconnectServerShutdown,
+}
+
+/// Why a connection was closed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DisconnectReason {
+    /// We closed the connection.
+    Disconnected,
+    /// The handshake with the server failed or did not finish in time.
+    HandshakeFailed(String),
+    /// The other side did not answer anymore.
+    TimedOut,
+    /// The server removed the client, e.g. it was kicked or banned.
+    Kicked {
+        reason: MoveReason,
+        /// The message of the invoker.
+        message: Option<String>,
+        /// How long the client is banned, if it was banned.
+        ban_time: Option<Duration>,
+    },
+    /// The server was shut down.
+    ServerShutdown {
+        message: Option<String>,
+    },
+    /// The public key of the server differs from the key, which is known for
+    /// this address.
+    ///
+    /// See [`KnownServers`].
+    ///
+    /// [`KnownServers`]: ../known_servers/struct.KnownServers.html
+    ServerKeyChanged { known_uid: String, uid: String },
+}
+
+impl DisconnectReason {
+    /// If the client was banned from the server.
+    pub fn is_ban(&self) -> bool {
+        if let DisconnectReason::Kicked {
+            reason: MoveReason::KickServerBan,
+            ..
+        } = *self
+        {
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::Disconnected => write!(f, "Disconnected"),
+            DisconnectReason::HandshakeFailed(ref msg) => {
+                write!(f, "Handshake failed ({})", msg)
+            }
+            DisconnectReason::TimedOut => write!(f, "Connection timed out"),
+            DisconnectReason::Kicked {
+                reason,
+                ref message,
+                ref ban_time,
+            } => {
+                write!(f, "Removed from server ({:?})", reason)?;
+                if let Some(ref time) = *ban_time {
+                    write!(f, " for {} seconds", time.num_seconds())?;
+                }
+                if let Some(ref msg) = *message {
+                    write!(f, ": {}", msg)?;
+                }
+                Ok(())
+            }
+            DisconnectReason::ServerShutdown { ref message } => {
+                write!(f, "Server shutdown")?;
+                if let Some(ref msg) = *message {
+                    write!(f, ": {}", msg)?;
+                }
+                Ok(())
+            }
+            DisconnectReason::ServerKeyChanged {
+                ref known_uid,
+                ref uid,
+            } => write!(
+                f,
+                "The key of the server changed from {} to {}",
+                known_uid, uid
+            ),
+        }
+    }
+}
+
 /// Represents a currently alive connection.
 pub struct Connection<ConnectionState> {
     /// The custom state of the connection.
@@ -128,6 +264,31 @@ pub struct Connection<ConnectionState> {
     pub srtt: Duration,
     /// Deviation of the srtt.
     pub srtt_dev: Duration,
+    /// The state of the resend algorithm for this connection.
+    pub resend_state: ResendState,
+    /// When the current `resend_state` was entered.
+    pub resend_state_since: DateTime<Utc>,
+    /// When the last packet from the other side was received.
+    pub last_received: DateTime<Utc>,
+    /// Gets notified when the connection is closed.
+    pub disconnect_listener: Vec<oneshot::Sender<DisconnectReason>>,
+
+    /// A queue of packets that where sent and when they were sent.
+    ///
+    /// Used to resend packets when they are not acknowledged in a certain time.
+    /// That is only done for `Command` and `CommandLow` packets and for the
+    /// `Init` packets of clients.
+    pub send_queue: BinaryHeap<SendRecord>,
+    /// The task which waits for free space in the `send_queue`.
+    pub(crate) send_task: Option<Task>,
+
+    /// Statistics about the keepalive pings of this connection.
+    pub ping_stats: PingStats,
+    /// The ids of sent pings, which are not yet answered, and when they were
+    /// sent.
+    pub(crate) pending_pings: VecDeque<(u16, DateTime<Utc>)>,
+    /// Traffic statistics of this connection.
+    pub stats: ConnectionStats,
 }
 
 /// Data that has to be stored for a connection when it is connected.
@@ -158,22 +319,146 @@ pub struct ConnectedParams {
     pub c_id: u16,
     /// If voice packets should be encrypted
     pub voice_encryption: bool,
+    /// The id of the next voice packet that is sent, this is independent of
+    /// the packet id.
+    pub voice_id: u16,
 
-    pub public_key: tomcrypt::EccKey,
+    pub public_key: EccKey,
     /// The iv used to encrypt and decrypt packets.
-    pub shared_iv: [u8; 20],
+    ///
+    /// It has 20 bytes for the old handshake and 64 bytes for the new one.
+    pub shared_iv: Vec<u8>,
     /// The mac used for unencrypted packets.
     pub shared_mac: [u8; 8],
+    /// The keys and nonces of the current generations.
+    pub key_nonce_cache: KeyNonceCache,
 }
 
 impl<State> Connection<State> {
     /// Creates a new connection state.
     pub fn new(state: State) -> Self {
+        let now = Utc::now();
         Self {
             state,
             params: None,
             srtt: Duration::milliseconds(2500),
             srtt_dev: Duration::milliseconds(0),
+            resend_state: ResendState::Connecting,
+            resend_state_since: now,
+            last_received: now,
+            disconnect_listener: Vec::new(),
+            send_queue: Default::default(),
+            send_task: None,
+            ping_stats: Default::default(),
+            pending_pings: VecDeque::new(),
+            stats: Default::default(),
+        }
+    }
+
+    pub(crate) fn set_resend_state(&mut self, state: ResendState) {
+        self.resend_state = state;
+        self.resend_state_since = Utc::now();
+    }
+
+    /// Called for every packet which is received on this connection.
+    pub(crate) fn packet_received(&mut self) {
+        self.last_received = Utc::now();
+        if self.resend_state == ResendState::Connecting {
+            self.set_resend_state(ResendState::Normal);
+        }
+    }
+
+    /// Remove an acknowledged packet from the send queue.
+    ///
+    /// This also updates the smoothed round trip time and the resend state.
+    pub(crate) fn ack_packet(&mut self, p_type: PacketType, p_id: u16) {
+        self.remove_send_records(|r| r.p_type == p_type && r.p_id == p_id);
+    }
+
+    /// Remove the sent init packet from the send queue because the next init
+    /// step was received.
+    pub(crate) fn ack_init_packet(&mut self) {
+        self.remove_send_records(|r| r.p_type == PacketType::Init);
+    }
+
+    fn remove_send_records<F: Fn(&SendRecord) -> bool>(&mut self, f: F) {
+        let mut rec = None;
+        let mut items = self.send_queue
+            .drain()
+            .filter_map(|r| if f(&r) {
+                rec = Some(r);
+                None
+            } else {
+                Some(r)
+            })
+            .collect();
+        mem::swap(&mut items, &mut self.send_queue);
+
+        if let Some(rec) = rec {
+            self.ack_received();
+            // Update smoothed round trip time, only if it was not resent
+            if rec.tries == 1 {
+                let now = Utc::now();
+                let diff = now.naive_utc()
+                    .signed_duration_since(rec.sent.naive_utc());
+                self.update_srtt(diff);
+            }
+            // There is space in the send queue again
+            if let Some(task) = self.send_task.take() {
+                task.notify();
+            }
+        }
+    }
+
+    /// The current traffic statistics together with the ping and the
+    /// estimated packet loss.
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot::new(&self.stats, &self.ping_stats)
+    }
+
+    /// Remember a sent ping to measure the round trip time when the answer
+    /// arrives.
+    pub(crate) fn ping_sent(&mut self, p_id: u16) {
+        self.pending_pings.push_back((p_id, Utc::now()));
+        self.ping_stats.sent += 1;
+    }
+
+    /// Called when a pong is received, which answers one of our pings.
+    ///
+    /// This updates the ping statistics and the smoothed round trip time.
+    pub(crate) fn pong_received(&mut self, p_id: u16) {
+        let pos = self.pending_pings.iter().position(|&(id, _)| id == p_id);
+        if let Some(pos) = pos {
+            let (_, sent) = self.pending_pings.remove(pos).unwrap();
+            let rtt = Utc::now()
+                .naive_utc()
+                .signed_duration_since(sent.naive_utc());
+            self.ping_stats.add_rtt(rtt);
+            self.update_srtt(rtt);
+        }
+    }
+
+    /// Count pings, which were not answered for longer than `timeout`, as
+    /// lost.
+    pub(crate) fn remove_lost_pings(&mut self, timeout: Duration) {
+        let now = Utc::now();
+        while self.pending_pings
+            .front()
+            .map(|&(_, sent)| sent + timeout < now)
+            .unwrap_or(false)
+        {
+            self.pending_pings.pop_front();
+            self.ping_stats.lost += 1;
+        }
+    }
+
+    /// Called when one of our packets was acknowledged.
+    fn ack_received(&mut self) {
+        match self.resend_state {
+            ResendState::Connecting |
+            ResendState::Stalling |
+            ResendState::Dead => self.set_resend_state(ResendState::Normal),
+            ResendState::Normal | ResendState::Disconnecting => {}
         }
     }
 
@@ -191,7 +476,7 @@ impl<State> Connection<State> {
 
 impl ConnectedParams {
     /// Fills the parameters for a connection with their default state.
-    pub fn new(public_key: tomcrypt::EccKey, shared_iv: [u8; 20], shared_mac: [u8; 8]) -> Self {
+    pub fn new(public_key: EccKey, shared_iv: Vec<u8>, shared_mac: [u8; 8]) -> Self {
         Self {
             outgoing_p_ids: Default::default(),
             receive_queue: Default::default(),
@@ -199,12 +484,25 @@ impl ConnectedParams {
             incoming_p_ids: Default::default(),
             c_id: 0,
             voice_encryption: true,
+            voice_id: 0,
             public_key,
             shared_iv,
             shared_mac,
+            key_nonce_cache: Default::default(),
         }
     }
 
+    /// Get the key and nonce to encrypt or decrypt a packet with the shared
+    /// iv of this connection.
+    pub fn get_key_nonce(
+        &mut self,
+        header: &Header,
+        generation_id: u32,
+    ) -> ([u8; 16], [u8; 16]) {
+        self.key_nonce_cache
+            .get_key_nonce(header, generation_id, &self.shared_iv)
+    }
+
     /// Check if a given id is in the receive window.
     pub(crate) fn in_receive_window(
         &self,
@@ -225,15 +523,123 @@ impl ConnectedParams {
     }
 }
 
+impl<CS> Data<CS> {
+    /// Remove a connection together with its queued packets.
+    ///
+    /// The `reason` is reported to the `disconnect_listener`s of the
+    /// connection, unless the [`disconnect_handler`] replaces the connection.
+    ///
+    /// [`disconnect_handler`]: #structfield.disconnect_handler
+    pub fn remove_connection(
+        &mut self,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    ) -> Option<Connection<CS>> {
+        let mut con = self.connections.remove(&addr);
+        let mut new_con = None;
+        if let Some(ref mut con) = con {
+            new_con = self.disconnect_handler
+                .as_mut()
+                .and_then(|h| h(addr, con, &reason));
+            if let Some(ref mut new_con) = new_con {
+                new_con.disconnect_listener.append(&mut con.disconnect_listener);
+            }
+
+            for l in con.disconnect_listener.drain(..) {
+                // Ignore listeners which are not waiting anymore
+                let _ = l.send(reason.clone());
+            }
+        }
+        if let Some(new_con) = new_con {
+            self.connections.insert(addr, new_con);
+        }
+        con
+    }
+}
+
+impl<CS> Drop for Data<CS> {
+    fn drop(&mut self) {
+        // Let the resend future notice that the data is gone
+        if let Some(task) = self.resend_task.take() {
+            task.notify();
+        }
+    }
+}
+
 impl<CS: 'static> Data<CS> {
+    /// Bind an udp socket to `local_addr` and use it to send and receive
+    /// packets.
     pub fn new<L: Into<Option<slog::Logger>>>(
         local_addr: SocketAddr,
-        private_key: tomcrypt::EccKey,
+        identity: Identity,
         handle: Handle,
         is_client: bool,
         logger: L,
     ) -> Result<Rc<RefCell<Self>>> {
-        let logger = logger.into().unwrap_or_else(|| {
+        // Create the socket
+        let socket = UdpSocket::bind(&local_addr, &handle)?;
+        let local_addr = socket.local_addr().unwrap_or(local_addr);
+        let buffer_pool = BufferPool::new();
+        let codec = TsCodec {
+            pool: buffer_pool.clone(),
+        };
+        let (sink, stream) = socket.framed(codec).split();
+        Ok(Self::create(
+            local_addr,
+            identity,
+            handle,
+            is_client,
+            logger.into(),
+            buffer_pool,
+            Box::new(stream.map_err(|e| e.into())),
+            Box::new(sink.sink_map_err(|e| e.into())),
+        ))
+    }
+
+    /// Send and receive packets over an arbitrary transport instead of an udp
+    /// socket, e.g. an in-memory channel, a proxy or a tunnel.
+    ///
+    /// The `stream` and `sink` carry single udp packets, the packets are
+    /// encrypted and decrypted by the layers above.
+    ///
+    /// `local_addr` is only used as information, no socket is bound.
+    pub fn with_transport<
+        L: Into<Option<slog::Logger>>,
+        St: Stream<Item = (SocketAddr, UdpPacket), Error = Error> + 'static,
+        Si: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error> + 'static,
+    >(
+        local_addr: SocketAddr,
+        identity: Identity,
+        handle: Handle,
+        is_client: bool,
+        logger: L,
+        stream: St,
+        sink: Si,
+    ) -> Rc<RefCell<Self>> {
+        Self::create(
+            local_addr,
+            identity,
+            handle,
+            is_client,
+            logger.into(),
+            BufferPool::new(),
+            Box::new(stream),
+            Box::new(sink),
+        )
+    }
+
+    #[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+    fn create(
+        local_addr: SocketAddr,
+        identity: Identity,
+        handle: Handle,
+        is_client: bool,
+        logger: Option<slog::Logger>,
+        buffer_pool: BufferPool,
+        stream: Box<Stream<Item = (SocketAddr, UdpPacket), Error = Error>>,
+        sink: Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>,
+    ) -> Rc<RefCell<Self>> {
+        let logger = logger.unwrap_or_else(|| {
             let decorator = slog_term::TermDecorator::new().build();
             //let decorator = slog_term::PlainSyncDecorator::new(::std::io::stdout());
             let drain = slog_term::FullFormat::new(decorator).build().fuse();
@@ -242,51 +648,24 @@ impl<CS: 'static> Data<CS> {
             slog::Logger::root(drain, o!())
         });
 
-        // Create the socket
-        let socket = UdpSocket::bind(&local_addr, &handle)?;
-        let local_addr = socket.local_addr().unwrap_or(local_addr);
-        let (sink, stream) = socket.framed(TsCodec::default()).split();
-        let raw_sink = Box::new(sink.sink_map_err(|e| e.into()));
-        let raw_stream = Box::new(stream.map_err(|e| e.into()));
-
-        Ok(Rc::new(RefCell::new(Self {
+        Rc::new(RefCell::new(Self {
             is_client,
             local_addr,
-            private_key,
+            identity,
             handle,
             logger,
-            udp_packet_stream: Some(raw_stream),
-            udp_packet_sink: Some(raw_sink),
+            buffer_pool,
+            timeout_config: TimeoutConfig::default(),
+            udp_packet_stream: Some(stream),
+            udp_packet_sink: Some(sink),
             raw_stream: None,
             raw_sink: None,
             packet_stream: None,
             packet_sink: None,
             connections: Default::default(),
-            send_queue: Default::default(),
+            disconnect_handler: None,
             resend_task: None,
-        })))
-    }
-
-    pub fn add_outgoing_packet(
-        data: Rc<RefCell<Self>>,
-        p_type: PacketType,
-        p_id: u16,
-        packet: (SocketAddr, UdpPacket),
-    ) {
-        let now = Utc::now();
-        let rec = SendRecord {
-            sent: now,
-            next: now,
-            tries: 0,
-            p_type,
-            p_id,
-            packet,
-        };
-        let mut data = data.borrow_mut();
-        data.send_queue.push(rec);
-        if let Some(ref task) = data.resend_task {
-            task.notify();
-        }
+        }))
     }
 
     /// Gives a `Stream` and `Sink` of `UdpPacket`s, which always references the
@@ -459,3 +838,136 @@ impl<CS> Sink for DataPackets<CS> {
         res
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    use futures::{Sink, Stream};
+    use futures::unsync::mpsc;
+    use slog;
+    use tokio_core::net::UdpCodec;
+    use tokio_core::reactor::Core;
+
+    use {Error, Result, TsCodec};
+    use commands::Command;
+    use crypto::{CryptoProvider, Provider};
+    use handler_data::{ConnectedParams, Connection, Data};
+    use identity::Identity;
+    use packet_codec::{PacketCodecSink, PacketCodecStream};
+    use packets::{self, *};
+    use resend::ResendSink;
+
+    fn client_addr() -> SocketAddr {
+        "127.0.0.1:50000".parse().unwrap()
+    }
+
+    fn server_addr() -> SocketAddr {
+        "127.0.0.1:9987".parse().unwrap()
+    }
+
+    /// Create one side of a connection, which sends and receives the raw
+    /// datagrams through channels instead of an udp socket.
+    fn setup(
+        core: &Core,
+        is_client: bool,
+        remote: SocketAddr,
+        recv: mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>,
+        send: mpsc::UnboundedSender<(SocketAddr, Vec<u8>)>,
+    ) -> Rc<RefCell<Data<()>>> {
+        let local = if is_client { client_addr() } else { server_addr() };
+        let mut decoder = TsCodec::default();
+        let mut encoder = TsCodec::default();
+        // Convert the datagrams like the udp socket does
+        let stream = recv.map_err(|_| Error::from("Channel closed"))
+            .and_then(move |(addr, buf)| -> Result<_> {
+                Ok(decoder.decode(&addr, &buf)?)
+            });
+        let sink = send.sink_map_err(|_| Error::from("Channel closed"))
+            .with(move |packet: (SocketAddr, UdpPacket)| -> Result<_> {
+                let mut buf = Vec::new();
+                let to = encoder.encode(packet, &mut buf);
+                assert_eq!(to, remote);
+                // The receiver sees our address as source
+                Ok((local, buf))
+            });
+
+        ::init().unwrap();
+        let data = Data::with_transport(
+            local,
+            Identity::create().unwrap(),
+            core.handle(),
+            is_client,
+            slog::Logger::root(slog::Discard, o!()),
+            stream,
+            sink,
+        );
+        PacketCodecSink::apply(data.clone());
+        PacketCodecStream::apply(data.clone(), true);
+        ResendSink::apply(data.clone()).unwrap();
+        {
+            let mut data = data.borrow_mut();
+            let mut con = Connection::new(());
+            let key = Provider.generate_key().unwrap();
+            con.params = Some(ConnectedParams::new(key, vec![0; 20], [0; 8]));
+            data.connections.insert(remote, con);
+        }
+        data
+    }
+
+    #[test]
+    fn send_over_transport() {
+        let mut core = Core::new().unwrap();
+        let (c2s_send, c2s_recv) = mpsc::unbounded();
+        let (s2c_send, s2c_recv) = mpsc::unbounded();
+        let client = setup(&core, true, server_addr(), s2c_recv, c2s_send);
+        let server = setup(&core, false, client_addr(), c2s_recv, s2c_send);
+
+        let mut header = Header::default();
+        header.set_type(PacketType::Command);
+        let packet =
+            Packet::new(header, packets::Data::Command(Command::new("test")));
+        let sink = Data::get_packets(client.clone());
+        core.run(sink.send((server_addr(), packet))).unwrap();
+        // The command is stored until it is acknowledged
+        assert_eq!(
+            client.borrow().connections[&server_addr()].send_queue.len(),
+            1
+        );
+
+        // The server decrypts the command and sends an ack
+        let (res, _) = core.run(Data::get_packets(server.clone()).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        let (addr, packet) = res.unwrap();
+        assert_eq!(addr, client_addr());
+        match packet.data {
+            packets::Data::Command(cmd) => assert_eq!(cmd.command, "test"),
+            _ => panic!("Expected a command packet"),
+        }
+        // Acks are not resent
+        assert!(
+            server.borrow().connections[&client_addr()]
+                .send_queue
+                .is_empty()
+        );
+
+        // The client receives the ack and stops resending
+        let (res, _) = core.run(Data::get_packets(client.clone()).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        let (addr, packet) = res.unwrap();
+        assert_eq!(addr, server_addr());
+        match packet.data {
+            packets::Data::Ack(p_id) => assert_eq!(p_id, 0),
+            _ => panic!("Expected an ack packet"),
+        }
+        assert!(
+            client.borrow().connections[&server_addr()]
+                .send_queue
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use base64;
+
+use Result;
+use algorithms as algs;
+use crypto::{CryptoProvider, EccKey, Provider};
+
+/// The key, which is used to obfuscate identities in the format of the
+/// official client.
+const OBFUSCATION_KEY: &[u8] = b"b9dfaa7bee6ac57ac7b65f1094a1c155e747327bc2fe5d\
+    51c512023fe54a280201004e90ad1daaae1075d53b7d571c30e063b5a62a4a017bb394833aa0\
+    983e6e";
+
+/// The identity of a client, which consists of a private key and the offset,
+/// which increases the security level of this key.
+///
+/// The same identity should be used when reconnecting to a server, so the
+/// server recognizes the client.
+pub struct Identity {
+    key: EccKey,
+    key_offset: u64,
+}
+
+impl Identity {
+    /// Generate a new identity with a random key.
+    ///
+    /// The security level of the new identity is not increased, use
+    /// [`upgrade_level`] for this.
+    ///
+    /// [`upgrade_level`]: #method.upgrade_level
+    pub fn create() -> Result<Self> {
+        let key = Provider.generate_key()?;
+        Ok(Self::new(key, 0))
+    }
+
+    pub fn new(key: EccKey, key_offset: u64) -> Self {
+        Self { key, key_offset }
+    }
+
+    /// Import a key, which is encoded as base64 string.
+    pub fn new_from_str(key: &str) -> Result<Self> {
+        let key = Provider.import_key(&base64::decode(key)?)?;
+        Ok(Self::new(key, 0))
+    }
+
+    /// Import an identity from the obfuscated format of the official client.
+    ///
+    /// The format is `<key offset>V<obfuscated key>`, the quotes and the
+    /// `identity=` prefix of the settings file are optional.
+    pub fn new_from_ts_str(identity: &str) -> Result<Self> {
+        let mut identity = identity.trim();
+        if identity.starts_with("identity=") {
+            identity = &identity["identity=".len()..];
+        }
+        let identity = identity.trim_matches('"');
+
+        let pos = if let Some(pos) = identity.find('V') {
+            pos
+        } else {
+            bail!("Identity has no key offset");
+        };
+        let key_offset = identity[..pos].parse()?;
+        let mut data = base64::decode(&identity[pos + 1..])?;
+        if data.len() < 20 {
+            bail!("Identity is too short");
+        }
+
+        // Xor the first 20 bytes with the hash of the rest until the first 0
+        let hash = obfuscation_hash(&data);
+        for (d, h) in data.iter_mut().zip(hash.iter()) {
+            *d ^= *h;
+        }
+        for (d, k) in data.iter_mut().zip(OBFUSCATION_KEY.iter().take(100)) {
+            *d ^= *k;
+        }
+
+        let key = String::from_utf8(data)
+            .map_err(|_| "Identity contains an invalid key")?;
+        let mut identity = Self::new_from_str(&key)?;
+        identity.key_offset = key_offset;
+        Ok(identity)
+    }
+
+    /// Export this identity into the obfuscated format of the official client.
+    ///
+    /// The result can be imported with [`new_from_ts_str`] and by the official
+    /// client, when it is prefixed with `identity=` and quoted.
+    ///
+    /// [`new_from_ts_str`]: #method.new_from_ts_str
+    pub fn to_ts_str(&mut self) -> Result<String> {
+        let mut data = self.to_str()?.into_bytes();
+        for (d, k) in data.iter_mut().zip(OBFUSCATION_KEY.iter().take(100)) {
+            *d ^= *k;
+        }
+        let hash = obfuscation_hash(&data);
+        for (d, h) in data.iter_mut().zip(hash.iter()) {
+            *d ^= *h;
+        }
+        Ok(format!("{}V{}", self.key_offset, base64::encode(&data)))
+    }
+
+    /// Export the private key as base64 string.
+    pub fn to_str(&mut self) -> Result<String> {
+        Ok(base64::encode(&Provider.export_private_key(&mut self.key)?))
+    }
+
+    pub fn key(&self) -> &EccKey {
+        &self.key
+    }
+
+    pub fn key_mut(&mut self) -> &mut EccKey {
+        &mut self.key
+    }
+
+    pub fn key_offset(&self) -> u64 {
+        self.key_offset
+    }
+
+    pub fn set_key_offset(&mut self, key_offset: u64) {
+        self.key_offset = key_offset;
+    }
+
+    /// The public key of this identity as base64 string, also called omega.
+    pub fn get_omega(&mut self) -> Result<String> {
+        Ok(base64::encode(&Provider.export_public_key(&mut self.key)?))
+    }
+
+    /// The unique id of this identity, which is `base64(sha1(omega))`.
+    pub fn get_uid(&mut self) -> Result<String> {
+        let omega = self.get_omega()?;
+        Ok(base64::encode(&Provider.sha1(omega.as_bytes())))
+    }
+
+    /// The current security level of this identity.
+    pub fn level(&mut self) -> Result<u8> {
+        let omega = self.get_omega()?;
+        Ok(algs::get_hash_cash_level(&omega, self.key_offset))
+    }
+
+    /// Increase the security level of this identity to at least `level`.
+    ///
+    /// The search continues at the current key offset. Use
+    /// [`algorithms::hash_cash_search`] to search on multiple threads.
+    ///
+    /// [`algorithms::hash_cash_search`]: ../algorithms/fn.hash_cash_search.html
+    pub fn upgrade_level(&mut self, level: u8) -> Result<()> {
+        if self.level()? < level {
+            let omega = self.get_omega()?;
+            self.key_offset = algs::hash_cash_search(
+                &omega,
+                level,
+                self.key_offset,
+                1,
+                None,
+                |_| {},
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The hash, which is used to obfuscate the first 20 bytes of an identity.
+///
+/// It is computed over the data after the first 20 bytes until the first null
+/// byte.
+fn obfuscation_hash(data: &[u8]) -> [u8; 20] {
+    let rest = &data[20..];
+    let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+    Provider.sha1(&rest[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use identity::*;
+
+    /// The private key, which is used in the examples.
+    const KEY: &str = "MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
+        k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
+        DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI";
+    const UID: &str = "lks7QL5OVMKo4pZ79cEOI5r5oEA=";
+    /// `KEY` with the key offset 354 in the format of the official client.
+    const TS_IDENTITY: &str = "354VOfydZwzLwCCWm3smUImF+mI+6W1fV1lhW1UpASZ\
+        ABXEQe1h0XnoPAA8ZEABhJXoDSF4Id2FYdj82Un0GfFZ5X1Nce18DCUoFDCFJBlcSCgN\
+        6AmNCT0AGVlYBcUUBLGMBLGRFcC81MENJQThNNW5tREJubURNL2daLy80QUFBQUFBQUF\
+        BQUFBQUFBQUFBQUFaUnpPSQ==";
+
+    #[test]
+    fn import_ts_identity() {
+        ::init().unwrap();
+        let mut identity = Identity::new_from_ts_str(&format!(
+            "identity=\"{}\"",
+            TS_IDENTITY
+        )).unwrap();
+        assert_eq!(identity.to_str().unwrap(), KEY);
+        assert_eq!(identity.key_offset(), 354);
+        assert_eq!(identity.get_uid().unwrap(), UID);
+        assert_eq!(identity.level().unwrap(), 8);
+        assert_eq!(identity.to_ts_str().unwrap(), TS_IDENTITY);
+
+        assert!(Identity::new_from_ts_str("354").is_err());
+        assert!(Identity::new_from_ts_str("354VAAAA").is_err());
+    }
+
+    #[test]
+    fn upgrade_level() {
+        ::init().unwrap();
+        let mut identity = Identity::new_from_str(KEY).unwrap();
+        assert_eq!(identity.get_uid().unwrap(), UID);
+        assert_eq!(identity.level().unwrap(), 0);
+        identity.upgrade_level(8).unwrap();
+        // The smallest offset with this level
+        assert_eq!(identity.key_offset(), 354);
+    }
+
+    #[test]
+    fn ts_identity_round_trip() {
+        ::init().unwrap();
+        let mut identity = Identity::create().unwrap();
+        identity.set_key_offset(1_234_567);
+        let ts_identity = identity.to_ts_str().unwrap();
+        assert!(ts_identity.starts_with("1234567V"));
+
+        let mut imported = Identity::new_from_ts_str(&ts_identity).unwrap();
+        assert_eq!(imported.to_str().unwrap(), identity.to_str().unwrap());
+        assert_eq!(imported.key_offset(), 1_234_567);
+        assert_eq!(imported.get_uid().unwrap(), identity.get_uid().unwrap());
+    }
+}
@@ -0,0 +1,465 @@
+//! Reorder received voice packets and smooth the jitter of their arrival.
+//!
+//! Every talking client has its own buffer, which is ordered by the voice
+//! packet id. Frames are taken out of the buffer in a fixed interval, the
+//! length of a frame (usually 20 ms). The buffer waits until a number of frames
+//! is buffered before the playback of a client starts. This delay is
+//! increased when the buffer runs empty and decreased when the connection is
+//! stable.
+use std::collections::VecDeque;
+use std::cmp;
+
+use Map;
+use packets::*;
+
+/// The delay in frames when a client starts talking.
+const INITIAL_DELAY: usize = 3;
+/// The minimum delay in frames.
+const MIN_DELAY: usize = 1;
+/// The maximum delay in frames.
+const MAX_DELAY: usize = 15;
+/// The number of frames without an underrun, after which the delay is
+/// decreased by one frame (5 seconds for 20 ms frames).
+const STABLE_FRAMES: u32 = 250;
+/// If a packet is further ahead than this, the client probably started a new
+/// transmission and the buffer is reset.
+const MAX_BUFFERED: usize = 50;
+/// End the transmission of a client, which sent nothing and played nothing
+/// for this many frames (1 second for 20 ms frames), e.g. because the packet,
+/// which marks the end, was lost.
+const SILENCE_FRAMES: u32 = 50;
+
+/// A frame, which is returned by the [`JitterBuffer`].
+///
+/// [`JitterBuffer`]: struct.JitterBuffer.html
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Frame {
+    Voice {
+        id: u16,
+        codec_type: CodecType,
+        data: Vec<u8>,
+    },
+    /// The packet of this frame was lost, the decoder should conceal it.
+    Lost { id: u16 },
+    /// The client stopped talking.
+    End,
+}
+
+/// Statistics about the received voice packets.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JitterStats {
+    /// The number of packets, which were added to a buffer.
+    pub received: u64,
+    /// The number of packets, which were received twice.
+    pub duplicates: u64,
+    /// The number of packets, which arrived after their frame was played.
+    pub late: u64,
+    /// The number of frames, which were reported as [`Frame::Lost`].
+    ///
+    /// [`Frame::Lost`]: enum.Frame.html#variant.Lost
+    pub lost: u64,
+    /// How often the buffer of a client ran empty while it was talking.
+    pub underruns: u64,
+}
+
+/// The buffer of a single client.
+#[derive(Debug)]
+struct Talker {
+    /// The id of the first frame in `frames`.
+    next_id: u16,
+    /// The received frames, a `None` is a missing frame.
+    frames: VecDeque<Option<(CodecType, Vec<u8>)>>,
+    /// The current delay in frames.
+    target_delay: usize,
+    /// If the playback started or if we are still waiting for the
+    /// `target_delay`.
+    playing: bool,
+    /// If a frame of the current transmission was played, later packets
+    /// with a smaller id are too late.
+    started: bool,
+    /// The number of frames, which were played since the last underrun.
+    stable_frames: u32,
+    /// The number of frames since the last packet was received or played.
+    silent_frames: u32,
+}
+
+/// Buffers and reorders the voice packets of all talking clients.
+#[derive(Debug, Default)]
+pub struct JitterBuffer {
+    talkers: Map<ClientId, Talker>,
+    pub stats: JitterStats,
+}
+
+impl Talker {
+    fn new(next_id: u16) -> Self {
+        Self {
+            next_id,
+            frames: VecDeque::new(),
+            target_delay: INITIAL_DELAY,
+            playing: false,
+            started: false,
+            stable_frames: 0,
+            silent_frames: 0,
+        }
+    }
+
+    /// Clear the buffer, but keep the delay.
+    fn reset(&mut self, next_id: u16) {
+        self.next_id = next_id;
+        self.frames.clear();
+        self.playing = false;
+        self.started = false;
+    }
+
+    fn push(
+        &mut self,
+        stats: &mut JitterStats,
+        id: u16,
+        codec_type: CodecType,
+        data: Vec<u8>,
+    ) {
+        self.silent_frames = 0;
+        let offset = id.wrapping_sub(self.next_id);
+        let offset = if (offset as i16) < 0 {
+            if self.started {
+                stats.late += 1;
+                return;
+            }
+            let behind = self.next_id.wrapping_sub(id) as usize;
+            if behind > MAX_BUFFERED {
+                self.reset(id);
+            } else {
+                // Reordered before the playback started
+                for _ in 0..behind {
+                    self.frames.push_front(None);
+                }
+                self.next_id = id;
+            }
+            0
+        } else if offset as usize >= MAX_BUFFERED {
+            self.reset(id);
+            0
+        } else {
+            offset as usize
+        };
+
+        while self.frames.len() <= offset {
+            self.frames.push_back(None);
+        }
+        if self.frames[offset].is_some() {
+            stats.duplicates += 1;
+            return;
+        }
+        self.frames[offset] = Some((codec_type, data));
+        stats.received += 1;
+    }
+
+    fn pop(&mut self, stats: &mut JitterStats) -> Option<Frame> {
+        if !self.playing {
+            if self.frames.len() < self.target_delay
+                && !self.frames.iter().any(is_end)
+            {
+                return self.wait();
+            }
+            self.playing = true;
+        }
+
+        let id = self.next_id;
+        self.silent_frames = 0;
+        match self.frames.pop_front() {
+            Some(Some((codec_type, data))) => {
+                self.next_id = id.wrapping_add(1);
+                self.started = true;
+                self.stable_frames += 1;
+                if self.stable_frames >= STABLE_FRAMES {
+                    self.stable_frames = 0;
+                    self.target_delay =
+                        cmp::max(self.target_delay - 1, MIN_DELAY);
+                }
+                if data.is_empty() {
+                    Some(Frame::End)
+                } else {
+                    Some(Frame::Voice {
+                        id,
+                        codec_type,
+                        data,
+                    })
+                }
+            }
+            Some(None) => {
+                self.next_id = id.wrapping_add(1);
+                self.started = true;
+                stats.lost += 1;
+                Some(Frame::Lost { id })
+            }
+            None => {
+                // Underrun, wait until the buffer is filled again
+                stats.underruns += 1;
+                self.playing = false;
+                self.stable_frames = 0;
+                self.target_delay = cmp::min(self.target_delay + 1, MAX_DELAY);
+                self.wait()
+            }
+        }
+    }
+
+    /// Called when no frame is played.
+    ///
+    /// Returns [`Frame::End`] if the client was silent for too long.
+    ///
+    /// [`Frame::End`]: enum.Frame.html#variant.End
+    fn wait(&mut self) -> Option<Frame> {
+        self.silent_frames += 1;
+        if self.silent_frames >= SILENCE_FRAMES {
+            Some(Frame::End)
+        } else {
+            None
+        }
+    }
+}
+
+fn is_end(frame: &Option<(CodecType, Vec<u8>)>) -> bool {
+    frame.as_ref().map(|f| f.1.is_empty()).unwrap_or(false)
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a received frame of a client.
+    ///
+    /// An empty frame marks the end of a transmission. Duplicated frames and
+    /// frames, which arrive after their playback time, are dropped.
+    pub fn push(
+        &mut self,
+        from: ClientId,
+        id: u16,
+        codec_type: CodecType,
+        data: Vec<u8>,
+    ) {
+        let stats = &mut self.stats;
+        self.talkers
+            .entry(from)
+            .or_insert_with(|| Talker::new(id))
+            .push(stats, id, codec_type, data);
+    }
+
+    /// Add a voice or whisper packet, which was sent by the server.
+    ///
+    /// Returns `false` if the packet contains no voice.
+    pub fn push_packet(&mut self, packet: Packet) -> bool {
+        match packet.data {
+            Data::S2CVoice(S2CVoice::Voice {
+                id,
+                from_id,
+                codec_type,
+                voice_data,
+            })
+            | Data::S2CVoice(S2CVoice::VoiceWhisper {
+                id,
+                from_id,
+                codec_type,
+                voice_data,
+            }) => {
+                self.push(ClientId(from_id), id, codec_type, voice_data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Take the next frame of every client, which is playing.
+    ///
+    /// This should be called once per frame length. A client is removed after
+    /// its [`Frame::End`] was returned. This frame is also returned if a
+    /// client sent nothing for a while, so clients do not stay in the buffer
+    /// when the end of their transmission was lost.
+    ///
+    /// [`Frame::End`]: enum.Frame.html#variant.End
+    pub fn pop(&mut self) -> Vec<(ClientId, Frame)> {
+        let mut res = Vec::new();
+        for (from, talker) in &mut self.talkers {
+            if let Some(frame) = talker.pop(&mut self.stats) {
+                res.push((*from, frame));
+            }
+        }
+        for &(from, ref frame) in &res {
+            if *frame == Frame::End {
+                self.talkers.remove(&from);
+            }
+        }
+        res
+    }
+
+    /// The current delay of a client in frames.
+    pub fn target_delay(&self, from: ClientId) -> Option<usize> {
+        self.talkers.get(&from).map(|t| t.target_delay)
+    }
+
+    /// Forget the buffer of a client, e.g. when it left.
+    pub fn remove(&mut self, from: ClientId) {
+        self.talkers.remove(&from);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jitter::*;
+
+    const CODEC: CodecType = CodecType::OpusVoice;
+
+    fn voice(id: u16) -> Frame {
+        Frame::Voice {
+            id,
+            codec_type: CODEC,
+            data: vec![id as u8],
+        }
+    }
+
+    fn push(buf: &mut JitterBuffer, id: u16) {
+        buf.push(ClientId(1), id, CODEC, vec![id as u8]);
+    }
+
+    /// Pop frames until the buffer returns nothing.
+    fn pop_all(buf: &mut JitterBuffer) -> Vec<Frame> {
+        let mut res = Vec::new();
+        loop {
+            let frames = buf.pop();
+            if frames.is_empty() {
+                break;
+            }
+            for (from, f) in frames {
+                assert_eq!(from, ClientId(1));
+                res.push(f);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn reorder() {
+        let mut buf = JitterBuffer::new();
+        push(&mut buf, 11);
+        push(&mut buf, 10);
+        assert!(buf.pop().is_empty());
+        push(&mut buf, 12);
+        push(&mut buf, 11);
+        assert_eq!(buf.stats.duplicates, 1);
+        assert_eq!(pop_all(&mut buf), vec![voice(10), voice(11), voice(12)]);
+        // Too late
+        push(&mut buf, 11);
+        assert_eq!(buf.stats.late, 1);
+        assert_eq!(buf.stats.received, 3);
+    }
+
+    #[test]
+    fn gaps() {
+        let mut buf = JitterBuffer::new();
+        for &id in &[0xfffe, 0, 1, 3] {
+            push(&mut buf, id);
+        }
+        buf.push(ClientId(1), 4, CODEC, Vec::new());
+        assert_eq!(
+            pop_all(&mut buf),
+            vec![
+                voice(0xfffe),
+                Frame::Lost { id: 0xffff },
+                voice(0),
+                voice(1),
+                Frame::Lost { id: 2 },
+                voice(3),
+                Frame::End,
+            ]
+        );
+        assert_eq!(buf.stats.lost, 2);
+        assert_eq!(buf.target_delay(ClientId(1)), None);
+    }
+
+    #[test]
+    fn short_transmission() {
+        // The end is played even if the delay is not reached
+        let mut buf = JitterBuffer::new();
+        push(&mut buf, 0);
+        buf.push(ClientId(1), 1, CODEC, Vec::new());
+        assert_eq!(pop_all(&mut buf), vec![voice(0), Frame::End]);
+    }
+
+    #[test]
+    fn adapt_delay() {
+        let mut buf = JitterBuffer::new();
+        for id in 0..3 {
+            push(&mut buf, id);
+        }
+        assert_eq!(pop_all(&mut buf).len(), 3);
+        assert_eq!(buf.stats.underruns, 1);
+        assert_eq!(buf.target_delay(ClientId(1)), Some(INITIAL_DELAY + 1));
+
+        // Decrease the delay if the connection is stable, the first 3 frames
+        // fill the buffer again
+        let mut id = 3;
+        for _ in 0..(STABLE_FRAMES + 3) {
+            push(&mut buf, id);
+            id += 1;
+            buf.pop();
+        }
+        assert_eq!(buf.target_delay(ClientId(1)), Some(INITIAL_DELAY));
+    }
+
+    #[test]
+    fn multiple_talkers() {
+        let mut buf = JitterBuffer::new();
+        for id in 0..3 {
+            buf.push(ClientId(1), id, CODEC, vec![1]);
+            buf.push(ClientId(2), id + 100, CODEC, vec![2]);
+        }
+        let mut frames = buf.pop();
+        frames.sort_by_key(|&(from, _)| from.0);
+        assert_eq!(
+            frames,
+            vec![
+                (
+                    ClientId(1),
+                    Frame::Voice {
+                        id: 0,
+                        codec_type: CODEC,
+                        data: vec![1],
+                    },
+                ),
+                (
+                    ClientId(2),
+                    Frame::Voice {
+                        id: 100,
+                        codec_type: CODEC,
+                        data: vec![2],
+                    },
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn lost_end() {
+        let mut buf = JitterBuffer::new();
+        for id in 0..3 {
+            push(&mut buf, id);
+        }
+        // The packet, which marks the end, is lost, so the buffer runs empty
+        assert_eq!(pop_all(&mut buf), vec![voice(0), voice(1), voice(2)]);
+        assert_eq!(buf.stats.underruns, 1);
+
+        // A received packet restarts the timeout
+        for _ in 1..(SILENCE_FRAMES - 1) {
+            assert!(buf.pop().is_empty());
+        }
+        push(&mut buf, 3);
+        for _ in 0..(SILENCE_FRAMES - 1) {
+            assert!(buf.pop().is_empty());
+        }
+        assert!(buf.target_delay(ClientId(1)).is_some());
+
+        // The client is removed after it was silent for too long
+        assert_eq!(buf.pop(), vec![(ClientId(1), Frame::End)]);
+        assert_eq!(buf.target_delay(ClientId(1)), None);
+    }
+}
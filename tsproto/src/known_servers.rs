@@ -0,0 +1,329 @@
+//! Remember the public keys of servers to notice when the key of a server
+//! changes (trust on first use).
+//!
+//! A changed key means that either the server was replaced or that someone
+//! intercepts the connection.
+use std::fmt;
+use std::collections::hash_map;
+use std::net::SocketAddr;
+
+use base64;
+
+use {ErrorKind, Map, Result};
+use crypto::{CryptoProvider, Provider};
+
+/// What happens, if a known server sends a different public key.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum KeyChangeAction {
+    /// Abort the connection with an [`ErrorKind::ServerKeyChanged`] error.
+    ///
+    /// [`ErrorKind::ServerKeyChanged`]: ../errors/enum.ErrorKind.html
+    Reject,
+    /// Remember the new key and continue connecting.
+    ///
+    /// The client logs a warning and reports the change as [`KeyChange`].
+    ///
+    /// [`KeyChange`]: struct.KeyChange.html
+    Warn,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KnownServer {
+    /// The unique id of the server, which is computed like the uid of a
+    /// client.
+    pub uid: String,
+    /// The public key (omega) in the DER format of libtomcrypt.
+    pub public_key: Vec<u8>,
+}
+
+/// The result of [`KnownServers::check`].
+///
+/// [`KnownServers::check`]: struct.KnownServers.html#method.check
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum KeyCheck {
+    /// The server was not known before, its key was added.
+    New,
+    /// The key is the same as the stored key.
+    Known,
+    /// The key changed and was replaced, this is only returned for
+    /// [`KeyChangeAction::Warn`].
+    ///
+    /// [`KeyChangeAction::Warn`]: enum.KeyChangeAction.html#variant.Warn
+    Changed { old: KnownServer },
+    /// The key is known for a different address, the server moved from
+    /// `old_addr` to the new address.
+    Moved { old_addr: SocketAddr },
+}
+
+/// The key of a known server changed and the new key was accepted because of
+/// [`KeyChangeAction::Warn`].
+///
+/// [`KeyChangeAction::Warn`]: enum.KeyChangeAction.html#variant.Warn
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KeyChange {
+    pub addr: SocketAddr,
+    pub old: KnownServer,
+    pub new: KnownServer,
+}
+
+/// The public keys of servers, stored by their address and uid.
+///
+/// The store can be saved and loaded as text with one server per line, see
+/// [`parse`].
+///
+/// [`parse`]: #method.parse
+#[derive(Debug, Clone)]
+pub struct KnownServers {
+    servers: Map<SocketAddr, KnownServer>,
+    /// The address of every server by its uid.
+    uids: Map<String, SocketAddr>,
+    on_change: KeyChangeAction,
+}
+
+/// Compute the uid for a public key, which is `base64(sha1(base64(key)))`.
+pub fn get_uid(public_key: &[u8]) -> String {
+    let omega = base64::encode(public_key);
+    base64::encode(&Provider.sha1(omega.as_bytes()))
+}
+
+impl KnownServer {
+    pub fn new(public_key: Vec<u8>) -> Self {
+        Self {
+            uid: get_uid(&public_key),
+            public_key,
+        }
+    }
+}
+
+impl KnownServers {
+    pub fn new(on_change: KeyChangeAction) -> Self {
+        Self {
+            servers: Map::new(),
+            uids: Map::new(),
+            on_change,
+        }
+    }
+
+    /// Load servers, which were saved with the `Display` implementation.
+    ///
+    /// Every line contains the address, the uid and the base64 encoded public
+    /// key of a server, separated by spaces.
+    pub fn parse(s: &str, on_change: KeyChangeAction) -> Result<Self> {
+        let mut res = Self::new(on_change);
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let parts = line.split(' ').collect::<Vec<_>>();
+            if parts.len() != 3 {
+                bail!("Invalid known server entry: {}", line);
+            }
+            let addr = parts[0]
+                .parse()
+                .map_err(|_| format!("Invalid server address: {}", parts[0]))?;
+            let server = KnownServer::new(base64::decode(parts[2])?);
+            if server.uid != parts[1] {
+                bail!("The uid of {} does not match its key", addr);
+            }
+            res.insert(addr, server.public_key);
+        }
+        Ok(res)
+    }
+
+    pub fn on_change(&self) -> KeyChangeAction {
+        self.on_change
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&KnownServer> {
+        self.servers.get(addr)
+    }
+
+    /// Find the address of a server by its uid.
+    pub fn find_uid(&self, uid: &str) -> Option<SocketAddr> {
+        self.uids.get(uid).cloned()
+    }
+
+    /// Store the key of a server and return the previous entry for this
+    /// address.
+    ///
+    /// If the key is known for another address, that entry is removed.
+    pub fn insert(
+        &mut self,
+        addr: SocketAddr,
+        public_key: Vec<u8>,
+    ) -> Option<KnownServer> {
+        let server = KnownServer::new(public_key);
+        // A server is stored only for its last address
+        if let Some(old_addr) = self.uids.insert(server.uid.clone(), addr) {
+            if old_addr != addr {
+                self.servers.remove(&old_addr);
+            }
+        }
+        let old = self.servers.insert(addr, server);
+        if let Some(ref old) = old {
+            if old.uid != self.servers[&addr].uid {
+                self.uids.remove(&old.uid);
+            }
+        }
+        old
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<KnownServer> {
+        let old = self.servers.remove(addr);
+        if let Some(ref old) = old {
+            self.uids.remove(&old.uid);
+        }
+        old
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<SocketAddr, KnownServer> {
+        self.servers.iter()
+    }
+
+    /// Check the public key of a server and remember it, if the server is not
+    /// known yet.
+    ///
+    /// A server, which uses a known key on a new address, is moved to the new
+    /// address. If the key changed, an [`ErrorKind::ServerKeyChanged`] error is
+    /// returned or the key is replaced, depending on the [`KeyChangeAction`].
+    ///
+    /// [`ErrorKind::ServerKeyChanged`]: ../errors/enum.ErrorKind.html
+    /// [`KeyChangeAction`]: enum.KeyChangeAction.html
+    pub fn check(
+        &mut self,
+        addr: SocketAddr,
+        public_key: &[u8],
+    ) -> Result<KeyCheck> {
+        let known_uid = match self.servers.get(&addr) {
+            Some(server) if server.public_key == public_key => {
+                return Ok(KeyCheck::Known);
+            }
+            Some(server) => Some(server.uid.clone()),
+            None => None,
+        };
+        let known_uid = if let Some(uid) = known_uid {
+            uid
+        } else if let Some(old_addr) = self.find_uid(&get_uid(public_key)) {
+            self.insert(addr, public_key.to_vec());
+            return Ok(KeyCheck::Moved { old_addr });
+        } else {
+            self.insert(addr, public_key.to_vec());
+            return Ok(KeyCheck::New);
+        };
+        match self.on_change {
+            KeyChangeAction::Reject => Err(ErrorKind::ServerKeyChanged(
+                addr,
+                known_uid,
+                get_uid(public_key),
+            ).into()),
+            KeyChangeAction::Warn => {
+                let old = self.insert(addr, public_key.to_vec()).unwrap();
+                Ok(KeyCheck::Changed { old })
+            }
+        }
+    }
+}
+
+impl fmt::Display for KnownServers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Sort by address, so the output does not change between calls
+        let mut servers = self.servers.iter().collect::<Vec<_>>();
+        servers.sort_by_key(|&(addr, _)| (addr.ip(), addr.port()));
+        for (addr, server) in servers {
+            writeln!(
+                f,
+                "{} {} {}",
+                addr,
+                server.uid,
+                base64::encode(&server.public_key)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use known_servers::*;
+    use ErrorKind;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:9987".parse().unwrap()
+    }
+
+    #[test]
+    fn check_keys() {
+        let mut known = KnownServers::new(KeyChangeAction::Reject);
+        assert_eq!(known.check(addr(), &[1, 2, 3]).unwrap(), KeyCheck::New);
+        assert_eq!(known.check(addr(), &[1, 2, 3]).unwrap(), KeyCheck::Known);
+        let uid = known.get(&addr()).unwrap().uid.clone();
+        assert_eq!(known.find_uid(&uid), Some(addr()));
+
+        match *known.check(addr(), &[4, 5, 6]).unwrap_err().kind() {
+            ErrorKind::ServerKeyChanged(a, ref known_uid, _) => {
+                assert_eq!(a, addr());
+                assert_eq!(*known_uid, uid);
+            }
+            ref e => panic!("Unexpected error {:?}", e),
+        }
+        // The old key is kept
+        assert_eq!(known.get(&addr()).unwrap().public_key, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn warn_on_change() {
+        let mut known = KnownServers::new(KeyChangeAction::Warn);
+        known.insert(addr(), vec![1, 2, 3]);
+        assert_eq!(
+            known.check(addr(), &[4, 5, 6]).unwrap(),
+            KeyCheck::Changed {
+                old: KnownServer::new(vec![1, 2, 3]),
+            }
+        );
+        assert_eq!(known.check(addr(), &[4, 5, 6]).unwrap(), KeyCheck::Known);
+        // Only the new key can be found
+        assert_eq!(
+            known.find_uid(&KnownServer::new(vec![1, 2, 3]).uid),
+            None
+        );
+        assert_eq!(
+            known.find_uid(&KnownServer::new(vec![4, 5, 6]).uid),
+            Some(addr())
+        );
+    }
+
+    #[test]
+    fn move_server() {
+        let new_addr = "127.0.0.1:9988".parse().unwrap();
+        let mut known = KnownServers::new(KeyChangeAction::Reject);
+        known.insert(addr(), vec![1, 2, 3]);
+        let uid = known.get(&addr()).unwrap().uid.clone();
+        assert_eq!(
+            known.check(new_addr, &[1, 2, 3]).unwrap(),
+            KeyCheck::Moved { old_addr: addr() }
+        );
+        assert_eq!(known.find_uid(&uid), Some(new_addr));
+        assert_eq!(known.get(&addr()), None);
+        assert_eq!(known.check(new_addr, &[1, 2, 3]).unwrap(), KeyCheck::Known);
+        assert_eq!(known.iter().count(), 1);
+
+        // A different server at the new address
+        assert_eq!(known.check(addr(), &[4, 5, 6]).unwrap(), KeyCheck::New);
+        assert_eq!(known.remove(&new_addr).unwrap().uid, uid);
+        assert_eq!(known.find_uid(&uid), None);
+        assert_eq!(known.check(addr(), &[4, 5, 6]).unwrap(), KeyCheck::Known);
+    }
+
+    #[test]
+    fn save_load() {
+        let mut known = KnownServers::new(KeyChangeAction::Reject);
+        known.insert(addr(), vec![1, 2, 3]);
+        known.insert("[::1]:9987".parse().unwrap(), vec![4, 5, 6]);
+        let s = known.to_string();
+        let loaded = KnownServers::parse(&s, KeyChangeAction::Reject).unwrap();
+        assert_eq!(loaded.to_string(), s);
+        assert_eq!(loaded.get(&addr()), known.get(&addr()));
+
+        assert!(KnownServers::parse("127.0.0.1:9987 abc AQID",
+            KeyChangeAction::Reject).is_err());
+    }
+}
@@ -3,9 +3,11 @@
 #![cfg_attr(feature = "cargo-clippy",
            allow(redundant_closure_call, clone_on_ref_ptr))]
 
+extern crate aes;
 extern crate base64;
 extern crate byteorder;
 extern crate chrono;
+extern crate curve25519_dalek;
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
@@ -14,15 +16,19 @@ extern crate nom;
 extern crate num;
 #[macro_use]
 extern crate num_derive;
+#[cfg(feature = "audio")]
+extern crate opus;
 extern crate quicklz;
 extern crate rand;
-extern crate ring;
+extern crate sha1;
+extern crate sha2;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_perf;
 extern crate slog_term;
 extern crate tokio_core;
+#[cfg(feature = "tomcrypt")]
 extern crate tomcrypt;
 
 use std::{fmt, io, str};
@@ -39,37 +45,57 @@ pub mod errors {
     error_chain! {
         foreign_links {
             Io(::std::io::Error);
-            Ring(::ring::error::Unspecified);
             Base64(::base64::DecodeError);
             Utf8(::std::str::Utf8Error);
             ParseInt(::std::num::ParseIntError);
             FutureCanceled(::futures::Canceled);
+            Opus(::opus::Error) #[cfg(feature = "audio")];
         }
         links {
-            Tomcrypt(::tomcrypt::errors::Error, ::tomcrypt::errors::ErrorKind);
+            Tomcrypt(::tomcrypt::errors::Error, ::tomcrypt::errors::ErrorKind)
+                #[cfg(feature = "tomcrypt")];
             Quicklz(::quicklz::errors::Error, ::quicklz::errors::ErrorKind);
         }
-    }
-}
-use errors::*;
 
-macro_rules! tryf {
-    ($e:expr) => {
-        match $e {
-            Ok(e) => e,
-            Err(error) => return Box::new(future::err(error.into())),
+        errors {
+            /// The connection was closed.
+            Disconnected(reason: ::handler_data::DisconnectReason) {
+                description("Connection closed")
+                display("Connection closed: {}", reason)
+            }
+            /// The public key of a server differs from the key, which was
+            /// stored in the known servers.
+            ServerKeyChanged(addr: ::std::net::SocketAddr, known_uid: String,
+                uid: String) {
+                description("Server key changed")
+                display("The key of the server {} changed from {} to {}",
+                    addr, known_uid, uid)
+            }
         }
-    };
+    }
 }
+use errors::*;
 
 pub mod algorithms;
+#[cfg(feature = "audio")]
+pub mod audio;
+pub mod buffer_pool;
 pub mod client;
 pub mod commands;
+pub mod crypto;
 pub mod handler_data;
+pub mod identity;
+pub mod jitter;
+pub mod known_servers;
+pub mod license;
 pub mod log;
 pub mod packets;
 pub mod packet_codec;
+pub mod ping;
 pub mod resend;
+pub mod rust_crypto;
+pub mod stats;
+pub mod voice;
 
 type BoxFuture<T, E> = Box<Future<Item = T, Error = E>>;
 type Map<K, V> = std::collections::HashMap<K, V>;
@@ -79,21 +105,29 @@ type Map<K, V> = std::collections::HashMap<K, V>;
 const MAX_FRAGMENTS_LENGTH: usize = 40960;
 /// The maximum number of packets that are stored, if they receive out-of-order.
 const MAX_QUEUE_LEN: usize = 50;
+/// The maximum number of sent command packets, which are not yet acknowledged,
+/// per connection.
+const MAX_SEND_QUEUE_LEN: usize = 50;
 /// The maximum decompressed size of a packet.
 #[cfg_attr(feature = "cargo-clippy", allow(unreadable_literal))]
 const MAX_DECOMPRESSED_SIZE: u32 = 40960;
 const FAKE_KEY: &str = "c:\\windows\\syste";
 const FAKE_NONCE: &str = "m\\firewall32.cpl";
-/// How long packets are resent until the connection is closed.
-const TIMEOUT_SECONDS: i64 = 30;
 
+/// The address of a client connection.
+///
+/// The id of a client on a server is [`packets::ClientId`].
+///
+/// [`packets::ClientId`]: packets/struct.ClientId.html
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
-pub struct ClientId(pub SocketAddr);
+pub struct ClientAddr(pub SocketAddr);
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub struct ServerId(pub SocketAddr);
 
 #[derive(Default)]
-struct TsCodec;
+struct TsCodec {
+    pool: buffer_pool::BufferPool,
+}
 
 struct HexSlice<'a, T: fmt::LowerHex + 'a>(&'a [T]);
 
@@ -118,7 +152,7 @@ impl<'a> fmt::Debug for HexSlice<'a, u8> {
     }
 }
 
-impl Into<SocketAddr> for ClientId {
+impl Into<SocketAddr> for ClientAddr {
     fn into(self) -> SocketAddr {
         self.0
     }
@@ -134,7 +168,7 @@ impl UdpCodec for TsCodec {
     type Out = (SocketAddr, UdpPacket);
 
     fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        let mut res = Vec::with_capacity(buf.len());
+        let mut res = self.pool.get();
         res.extend_from_slice(buf);
         Ok((*src, UdpPacket(res)))
     }
@@ -146,13 +180,28 @@ impl UdpCodec for TsCodec {
         buf: &mut Vec<u8>,
     ) -> SocketAddr {
         buf.append(&mut packet);
+        self.pool.put(packet);
         addr
     }
 }
 
+/// Initialize the crypto backend.
+///
+/// This has to be called once before the [`TomcryptProvider`] is used.
+///
+/// [`TomcryptProvider`]: crypto/struct.TomcryptProvider.html
+#[cfg(feature = "tomcrypt")]
 pub fn init() -> Result<()> {
     tomcrypt::init();
     tomcrypt::register_sprng()?;
     tomcrypt::register_rijndael_cipher()?;
     Ok(())
 }
+
+/// Initialize the crypto backend.
+///
+/// Without the `tomcrypt` feature, nothing has to be initialized.
+#[cfg(not(feature = "tomcrypt"))]
+pub fn init() -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,285 @@
+//! The license chain, which is sent by the server in the `initivexpand2`
+//! command.
+//!
+//! Every license contains an Ed25519 public key. Starting at the
+//! [`LICENSE_ROOT_KEY`], the key of each license in the chain is used to
+//! derive a new public key. The key, which is derived from the whole chain, is
+//! the public part of the ephemeral key exchange with the server.
+//!
+//! [`LICENSE_ROOT_KEY`]: constant.LICENSE_ROOT_KEY.html
+use std::str;
+
+use chrono::{DateTime, TimeZone, Utc};
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+
+use Result;
+use crypto::{CryptoProvider, Provider};
+
+/// The public key at the root of all license chains.
+pub const LICENSE_ROOT_KEY: [u8; 32] = [
+    0xcd, 0x0d, 0xe2, 0xae, 0xd4, 0x63, 0x45, 0x50, 0x9a, 0x7e, 0x3c, 0xfd,
+    0x8f, 0x68, 0xb3, 0xdc, 0x75, 0x55, 0xb2, 0x9d, 0xcc, 0xec, 0x73, 0xcd,
+    0x18, 0x75, 0x0f, 0x99, 0x38, 0x12, 0x40, 0x8a,
+];
+/// Timestamps in licenses are stored as seconds since 2013-01-01.
+const TIMESTAMP_OFFSET: i64 = 0x50e2_2700;
+/// The length of the fields, which every license contains.
+const MIN_LICENSE_LENGTH: usize = 42;
+
+/// The content of a license, which depends on the type of the license.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LicenseData {
+    Intermediate { issuer: String },
+    Website { issuer: String },
+    Server {
+        license_type: u8,
+        max_clients: u32,
+        issuer: String,
+    },
+    Code { issuer: String },
+    /// A license, which is created by the server for a single connection.
+    Ephemeral,
+}
+
+#[derive(Debug, Clone)]
+pub struct License {
+    pub public_key: [u8; 32],
+    pub not_valid_before: DateTime<Utc>,
+    pub not_valid_after: DateTime<Utc>,
+    pub data: LicenseData,
+    /// The bytes of this license without the key type, they are hashed to
+    /// derive the public key.
+    hash_data: Vec<u8>,
+}
+
+/// A chain of licenses, the first license is signed by the root key and every
+/// following license by the license before.
+#[derive(Debug, Clone)]
+pub struct Licenses {
+    pub licenses: Vec<License>,
+}
+
+impl Licenses {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.first() != Some(&1) {
+            return Err("Unsupported license version".into());
+        }
+        let mut data = &data[1..];
+        let mut licenses = Vec::new();
+        while !data.is_empty() {
+            let (license, rest) = License::parse(data)?;
+            licenses.push(license);
+            data = rest;
+        }
+        Ok(Self { licenses })
+    }
+
+    /// Check that the chain ends with an ephemeral license and that no license
+    /// is expired.
+    ///
+    /// The start of the validity is not checked because the clocks of client
+    /// and server can differ and the ephemeral license of the server starts at
+    /// the time it was created.
+    pub fn verify(&self, now: DateTime<Utc>) -> Result<()> {
+        match self.licenses.split_last() {
+            Some((last, rest)) => {
+                if last.data != LicenseData::Ephemeral {
+                    return Err(
+                        "The license chain does not end with an ephemeral \
+                         license"
+                            .into(),
+                    );
+                }
+                if rest.iter().any(|l| l.data == LicenseData::Ephemeral) {
+                    return Err("Ephemeral license inside the license chain"
+                        .into());
+                }
+            }
+            None => return Err("The license chain is empty".into()),
+        }
+        for l in &self.licenses {
+            if l.not_valid_before > l.not_valid_after {
+                return Err("License has an invalid time span".into());
+            }
+            if l.not_valid_after < now {
+                return Err("License is expired".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Derive the public key of the last license, starting at the
+    /// [`LICENSE_ROOT_KEY`].
+    ///
+    /// [`LICENSE_ROOT_KEY`]: constant.LICENSE_ROOT_KEY.html
+    pub fn derive_public_key(&self) -> Result<EdwardsPoint> {
+        let mut key = decompress(&LICENSE_ROOT_KEY)?;
+        for l in &self.licenses {
+            key = l.derive_public_key(&key)?;
+        }
+        Ok(key)
+    }
+}
+
+impl License {
+    /// Parse a single license and return the remaining data.
+    fn parse(data: &[u8]) -> Result<(Self, &[u8])> {
+        if data.len() < MIN_LICENSE_LENGTH {
+            return Err("License is too short".into());
+        }
+        if data[0] != 0 {
+            return Err("Unsupported license key type".into());
+        }
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&data[1..33]);
+        let not_valid_before = read_timestamp(&data[34..38]);
+        let not_valid_after = read_timestamp(&data[38..42]);
+
+        let content = &data[MIN_LICENSE_LENGTH..];
+        let (license_data, len) = match data[33] {
+            0 => {
+                if content.len() < 4 {
+                    return Err("License is too short".into());
+                }
+                // 4 unknown bytes
+                let (issuer, len) = read_string(&content[4..])?;
+                (LicenseData::Intermediate { issuer }, 4 + len)
+            }
+            1 => {
+                let (issuer, len) = read_string(content)?;
+                (LicenseData::Website { issuer }, len)
+            }
+            2 => {
+                if content.len() < 5 {
+                    return Err("License is too short".into());
+                }
+                let max_clients = (u32::from(content[1]) << 24)
+                    | (u32::from(content[2]) << 16)
+                    | (u32::from(content[3]) << 8)
+                    | u32::from(content[4]);
+                let (issuer, len) = read_string(&content[5..])?;
+                let license_data = LicenseData::Server {
+                    license_type: content[0],
+                    max_clients,
+                    issuer,
+                };
+                (license_data, 5 + len)
+            }
+            3 => {
+                let (issuer, len) = read_string(content)?;
+                (LicenseData::Code { issuer }, len)
+            }
+            32 => (LicenseData::Ephemeral, 0),
+            t => return Err(format!("Unknown license type {}", t).into()),
+        };
+
+        let len = MIN_LICENSE_LENGTH + len;
+        let license = Self {
+            public_key,
+            not_valid_before,
+            not_valid_after,
+            data: license_data,
+            hash_data: data[1..len].to_vec(),
+        };
+        Ok((license, &data[len..]))
+    }
+
+    /// Compute `public_key * hash + parent_key`, where the hash is the
+    /// clamped first half of the SHA-512 hash of the license.
+    fn derive_public_key(&self, parent_key: &EdwardsPoint) -> Result<EdwardsPoint> {
+        let hash = Provider.sha512(&self.hash_data);
+        let mut scalar = [0; 32];
+        scalar.copy_from_slice(&hash[..32]);
+        scalar[0] &= 248;
+        scalar[31] &= 63;
+        scalar[31] |= 64;
+        let key = decompress(&self.public_key)?;
+        Ok(key * Scalar::from_bits(scalar) + parent_key)
+    }
+}
+
+fn decompress(key: &[u8; 32]) -> Result<EdwardsPoint> {
+    CompressedEdwardsY(*key)
+        .decompress()
+        .ok_or_else(|| "Invalid license public key".into())
+}
+
+fn read_timestamp(data: &[u8]) -> DateTime<Utc> {
+    let secs = (u32::from(data[0]) << 24) | (u32::from(data[1]) << 16)
+        | (u32::from(data[2]) << 8) | u32::from(data[3]);
+    Utc.timestamp(i64::from(secs) + TIMESTAMP_OFFSET, 0)
+}
+
+/// Read a null-terminated string and return it together with the number of
+/// read bytes, including the terminating null.
+fn read_string(data: &[u8]) -> Result<(String, usize)> {
+    let len = match data.iter().position(|b| *b == 0) {
+        Some(len) => len,
+        None => return Err("Unterminated string in license".into()),
+    };
+    Ok((str::from_utf8(&data[..len])?.to_string(), len + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use chrono::{TimeZone, Utc};
+
+    use license::*;
+
+    /// A license chain, which was sent by a server without a license.
+    const LICENSE: &str = "AQA1hUFJiiSs0wFXkYuPUJVcDa6XCrZTcsvkB0Ffzz4CmwIITRX\
+        gCqeTYAcAAAAgQW5vbnltb3VzAAC4R+5mos+UQ/KCbkpQLMI5WRp4wkQu8e5PZY4zU+/\
+        FlyAJwaE8CcJJ/A==";
+
+    #[test]
+    fn parse_license() {
+        let licenses = Licenses::parse(&base64::decode(LICENSE).unwrap())
+            .unwrap();
+        assert_eq!(licenses.licenses.len(), 2);
+        assert_eq!(
+            licenses.licenses[0].data,
+            LicenseData::Server {
+                license_type: 7,
+                max_clients: 32,
+                issuer: "Anonymous".into(),
+            }
+        );
+        assert_eq!(licenses.licenses[1].data, LicenseData::Ephemeral);
+        assert_eq!(
+            licenses.licenses[1].not_valid_before,
+            Utc.ymd(2018, 3, 10).and_hms(11, 57, 48)
+        );
+
+        licenses
+            .verify(Utc.ymd(2018, 3, 10).and_hms(12, 0, 0))
+            .unwrap();
+        assert!(licenses.verify(Utc.ymd(2019, 1, 1).and_hms(0, 0, 0)).is_err());
+    }
+
+    #[test]
+    fn parse_invalid_license() {
+        let data = base64::decode(LICENSE).unwrap();
+        assert!(Licenses::parse(&data[..50]).is_err());
+        assert!(Licenses::parse(&data[1..]).is_err());
+        // Only the server license
+        let licenses = Licenses::parse(&data[..58]).unwrap();
+        assert!(licenses
+            .verify(Utc.ymd(2018, 3, 10).and_hms(12, 0, 0))
+            .is_err());
+    }
+
+    #[test]
+    fn derive_public_key() {
+        let licenses = Licenses::parse(&base64::decode(LICENSE).unwrap())
+            .unwrap();
+        let key = licenses.derive_public_key().unwrap();
+        let expected = [
+            0x40, 0xe9, 0x50, 0xc4, 0x61, 0xba, 0x18, 0x3a, 0x1e, 0xb7, 0xcb,
+            0xb1, 0x9a, 0xc3, 0xd8, 0xd9, 0xc4, 0xd5, 0x24, 0xdb, 0x38, 0xf7,
+            0x2d, 0x3d, 0x66, 0x75, 0x77, 0x2a, 0xc5, 0x9c, 0xc5, 0xc6,
+        ];
+        assert_eq!(key.compress().to_bytes(), expected);
+    }
+}
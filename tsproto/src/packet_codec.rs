@@ -1,19 +1,17 @@
 use std::cell::RefCell;
 use std::io::Cursor;
-use std::mem;
 use std::net::{self, SocketAddr};
 use std::rc::{Rc, Weak};
 use std::u16;
 
-use chrono::Utc;
 use futures::{self, future, Future, Sink, Stream};
 use futures::task;
 use num::ToPrimitive;
 use slog;
 
 use {
-    packets, BoxFuture, Error, Result, ResultExt, MAX_FRAGMENTS_LENGTH,
-    MAX_QUEUE_LEN,
+    packets, stats, BoxFuture, Error, Result, ResultExt,
+    MAX_FRAGMENTS_LENGTH, MAX_QUEUE_LEN,
 };
 use algorithms as algs;
 use handler_data::{ConnectedParams, Data};
@@ -28,6 +26,8 @@ pub struct PacketCodecStream<
     receive_buffer: Vec<Packet>,
     receive_addr: SocketAddr,
     ack_packet: Option<(SocketAddr, Packet)>,
+    /// A copy of a packet, which is restored if fake decryption fails.
+    decrypt_backup: Vec<u8>,
 }
 
 impl<CS, Inner: Stream<Item = (SocketAddr, UdpPacket), Error = Error>>
@@ -42,18 +42,21 @@ impl<CS, Inner: Stream<Item = (SocketAddr, UdpPacket), Error = Error>>
                 0,
             ),
             ack_packet: None,
+            decrypt_backup: Vec::new(),
         }
     }
 
     /// Handle `Command` and `CommandLow` packets.
     ///
-    /// They have to be handled in the right order.
+    /// They have to be handled in the right order. The finished packets are
+    /// added to `packets` in reverse order, so they can be popped.
     fn handle_command_packet(
         logger: slog::Logger,
         params: &mut ConnectedParams,
         mut header: Header,
         mut packet: UdpPacket,
-    ) -> Result<Vec<Packet>> {
+        packets: &mut Vec<Packet>,
+    ) -> Result<()> {
         let mut id = header.p_id;
         let type_i = header.get_type().to_usize().unwrap();
         let cmd_i = if header.get_type() == PacketType::Command {
@@ -67,7 +70,7 @@ impl<CS, Inner: Stream<Item = (SocketAddr, UdpPacket), Error = Error>>
         let cur_next = in_ids.1;
         if cur_next == id {
             // In order
-            let mut packets = Vec::new();
+            let start = packets.len();
             loop {
                 // Compute packet generation
                 if id < in_ids.1 {
@@ -149,8 +152,8 @@ impl<CS, Inner: Stream<Item = (SocketAddr, UdpPacket), Error = Error>>
                 }
             }
             // The first packets should be returned first
-            packets.reverse();
-            Ok(packets)
+            packets[start..].reverse();
+            Ok(())
         } else {
             // Out of order
             warn!(logger, "Out of order command packet"; "got" => id, "expected" => cur_next);
@@ -160,7 +163,7 @@ impl<CS, Inner: Stream<Item = (SocketAddr, UdpPacket), Error = Error>>
                 || (cur_next > limit && (id >= cur_next || id < limit))
             {
                 r_queue.push((header, packet.0));
-                Ok(vec![])
+                Ok(())
             } else {
                 Err("Max queue length for commands exceeded".into())
             }
@@ -228,12 +231,25 @@ impl<
                             r.position() as usize,
                         )
                     };
-                    let mut udp_packet = udp_packet.split_off(pos);
+                    let packet_len = udp_packet.len();
+                    // Remove the header without reallocating
+                    udp_packet.drain(..pos);
+                    let pool = data.buffer_pool.clone();
+                    if let Some(con) = data.connections.get_mut(&addr) {
+                        con.packet_received();
+                        con.stats
+                            .packet_received(header.get_type(), packet_len);
+                    }
 
-                    let packets: Vec<Packet> = {
+                    {
                         let logger = data.logger.clone();
                         let data = &mut *data;
-                        let mut update_rtt = None;
+                        // An acknowledged packet
+                        let mut acked = None;
+                        // The packet id of a received pong
+                        let mut pong = None;
+                        // The number of packets of this type, which were lost
+                        let mut lost = 0;
                         let res = if let Some(params) = data.connections
                             .get_mut(&addr)
                             .and_then(|con| con.params.as_mut())
@@ -268,14 +284,16 @@ impl<
                                         && header.p_id == 0
                                         && !is_client
                                     {
-                                        let udp_packet_bak = udp_packet.clone();
+                                        let backup = &mut self.decrypt_backup;
+                                        backup.clear();
+                                        backup.extend_from_slice(&udp_packet);
                                         if algs::decrypt_fake(
                                             &header,
                                             &mut udp_packet,
                                         ).is_ok() {
                                             true
                                         } else {
-                                            udp_packet.copy_from_slice(&udp_packet_bak);
+                                            udp_packet.copy_from_slice(backup);
                                             false
                                         }
                                     } else {
@@ -283,11 +301,13 @@ impl<
                                     };
                                     if !decrypted {
                                         // Decrypt the packet
-                                        algs::decrypt(
+                                        let (key, nonce) =
+                                            params.get_key_nonce(&header, gen_id);
+                                        algs::decrypt_key_nonce(
                                             &header,
                                             &mut udp_packet,
-                                            gen_id,
-                                            &params.shared_iv,
+                                            &key,
+                                            &nonce,
                                         ).chain_err(
                                             || "Packet decryption failed",
                                         )?
@@ -323,6 +343,7 @@ impl<
                                             params,
                                             header,
                                             UdpPacket(udp_packet),
+                                            &mut self.receive_buffer,
                                         );
                                         // Don't send an ack if an error is
                                         // returned
@@ -332,6 +353,13 @@ impl<
                                         res
                                     }
                                     _ => {
+                                        // Skipped packet ids are lost
+                                        if in_recv_win {
+                                            lost = stats::skipped_packets(
+                                                cur_next,
+                                                id,
+                                            );
+                                        }
                                         if header.get_type() == PacketType::Ping
                                         {
                                             ack = Some((
@@ -345,10 +373,24 @@ impl<
                                         params.incoming_p_ids[type_i] =
                                             (gen_id, id.wrapping_add(1));
 
-                                        match packets::Data::read(
-                                            &header,
-                                            &mut Cursor::new(udp_packet),
-                                        ) {
+                                        let p_data = if p_type.is_voice() {
+                                            // Keep the buffer for the voice
+                                            // data
+                                            packets::Data::read_buf(
+                                                &header,
+                                                udp_packet,
+                                            )
+                                        } else {
+                                            let mut r = Cursor::new(udp_packet);
+                                            let p_data = packets::Data::read(
+                                                &header,
+                                                &mut r,
+                                            );
+                                            // Reuse the buffer
+                                            pool.put(r.into_inner());
+                                            p_data
+                                        };
+                                        match p_data {
                                             Ok(p_data) => {
                                                 // Remove command packet from send queue if the fitting ack is received.
                                                 match p_data {
@@ -367,33 +409,17 @@ impl<
                                                         } else {
                                                             PacketType::CommandLow
                                                         };
-                                                        let mut rec = None;
-                                                        let mut items = data.send_queue.drain()
-                                                            .filter_map(|r| if r.p_type == p_type && r.p_id == p_id {
-                                                                rec = Some(r);
-                                                                None
-                                                            } else {
-                                                                Some(r)
-                                                            })
-                                                            .collect();
-                                                        mem::swap(&mut items, &mut data.send_queue);
-                                                        // Update smoothed round trip time
-                                                        if let Some(rec) = rec {
-                                                            // Only if it was not resent
-                                                            if rec.tries == 1 {
-                                                                let now =
-                                                                    Utc::now();
-                                                                let diff = now.naive_utc().signed_duration_since(rec.sent.naive_utc());
-                                                                update_rtt =
-                                                                    Some(diff);
-                                                            }
-                                                        }
+                                                        acked = Some((p_type, p_id));
                                                     }
+                                                    packets::Data::Pong(
+                                                        p_id,
+                                                    ) => pong = Some(p_id),
                                                     _ => {}
                                                 }
-                                                Ok(vec![
+                                                self.receive_buffer.push(
                                                     Packet::new(header, p_data),
-                                                ])
+                                                );
+                                                Ok(())
                                             }
                                             Err(error) => Err(error.into()),
                                         }
@@ -414,6 +440,9 @@ impl<
                                         packets::Data::AckLow(header.p_id),
                                     ));
                                 }
+                                // The other side resent a packet, so our
+                                // ack or their packet was lost
+                                lost = 1;
                                 Err(
                                     format!(
                                         "Packet {} not in receive window \
@@ -438,7 +467,8 @@ impl<
                             if header.get_type() == PacketType::Command
                                 && is_client
                             {
-                                let udp_packet_bak = udp_packet.clone();
+                                self.decrypt_backup.clear();
+                                self.decrypt_backup.extend_from_slice(&udp_packet);
                                 if algs::decrypt_fake(&header, &mut udp_packet)
                                     .is_ok()
                                 {
@@ -453,25 +483,35 @@ impl<
                                         ),
                                     ));
                                 } else {
-                                    udp_packet.copy_from_slice(&udp_packet_bak);
+                                    udp_packet.copy_from_slice(&self.decrypt_backup);
                                 }
                             }
-                            let p_data = packets::Data::read(
-                                &header,
-                                &mut Cursor::new(udp_packet),
-                            )?;
-                            Ok(vec![Packet::new(header, p_data)])
+                            let mut r = Cursor::new(udp_packet);
+                            let p_data = packets::Data::read(&header, &mut r);
+                            pool.put(r.into_inner());
+                            let p_data = p_data?;
+                            self.receive_buffer.push(Packet::new(header, p_data));
+                            Ok(())
                         };
-                        if let Some(rtt) = update_rtt {
+                        if let Some((p_type, p_id)) = acked {
+                            if let Some(con) = data.connections.get_mut(&addr) {
+                                con.ack_packet(p_type, p_id);
+                            }
+                        }
+                        if let Some(p_id) = pong {
                             if let Some(con) = data.connections.get_mut(&addr) {
-                                con.update_srtt(rtt);
+                                con.pong_received(p_id);
+                            }
+                        }
+                        if lost > 0 {
+                            if let Some(con) = data.connections.get_mut(&addr) {
+                                con.stats.packets_lost(header.get_type(), lost);
                             }
                         }
                         res
                     }?;
 
                     self.receive_addr = addr;
-                    self.receive_buffer = packets;
                     if !self.receive_buffer.is_empty()
                         || self.ack_packet.is_some()
                     {
@@ -540,6 +580,135 @@ impl<
             ),
         }
     }
+
+    /// Write a packet into udp packets and add them to the send buffer.
+    ///
+    /// Returns `false` if the packet should not be sent.
+    fn encode(&mut self, addr: SocketAddr, packet: Packet) -> Result<bool> {
+        let data = match self.data.upgrade() {
+            Some(data) => data,
+            None => return Err("Connection is gone".into()),
+        };
+        let mut data = data.borrow_mut();
+        let is_client = data.is_client;
+        let pool = data.buffer_pool.clone();
+        let p_type = packet.header.get_type();
+        let use_newprotocol = p_type.is_command() && is_client;
+        let send_buffer = &mut self.send_buffer;
+
+        if let Some(con) = data.connections.get_mut(&addr) {
+            if p_type.is_voice() && con.resend_state.is_stalled() {
+                // Drop voice packets while the connection is unstable
+                return Ok(false);
+            }
+            // The id of a sent ping
+            let mut ping_id = None;
+            if let Some(params) = con.params.as_mut() {
+                let type_i = p_type.to_usize().unwrap();
+                // Add the header and encrypt the packet data
+                let mut encode =
+                    |mut header: Header, mut p_data: Vec<u8>| -> Result<_> {
+                    // Get packet id
+                    let (mut gen, mut p_id) = params.outgoing_p_ids[type_i];
+                    header.p_id = p_id;
+                    if header.get_type() == PacketType::Ping {
+                        ping_id = Some(p_id);
+                    }
+
+                    // Client id for clients
+                    if is_client {
+                        header.c_id = Some(params.c_id);
+                    } else {
+                        header.c_id = None;
+                    }
+
+                    // Set newprotocol flag if needed
+                    if use_newprotocol {
+                        header.set_newprotocol(true);
+                    }
+
+                    // Encrypt if necessary, fake encrypt the initivexpand packet
+                    if algs::should_encrypt(
+                        header.get_type(),
+                        params.voice_encryption,
+                    ) {
+                        header.set_unencrypted(false);
+                        if header.get_type() == PacketType::Command
+                            && !is_client && header.p_id == 0
+                        {
+                            algs::encrypt_fake(&mut header, &mut p_data)?;
+                        } else {
+                            let (key, nonce) =
+                                params.get_key_nonce(&header, gen);
+                            algs::encrypt_key_nonce(
+                                &mut header,
+                                &mut p_data,
+                                &key,
+                                &nonce,
+                            )?;
+                        }
+                    } else {
+                        header.set_unencrypted(true);
+                        header.mac.copy_from_slice(&params.shared_mac);
+                    };
+
+                    // Increment outgoing_p_ids
+                    p_id = p_id.wrapping_add(1);
+                    if p_id == 0 {
+                        gen = gen.wrapping_add(1);
+                    }
+                    params.outgoing_p_ids[type_i] = (gen, p_id);
+                    let mut buf = pool.get();
+                    header.write(&mut buf)?;
+                    buf.append(&mut p_data);
+                    pool.put(p_data);
+                    Ok(UdpPacket(buf))
+                };
+
+                // Compress and split packet
+                if p_type.is_command() {
+                    for (header, p_data) in algs::compress_and_split(&packet) {
+                        send_buffer.push(encode(header, p_data)?);
+                    }
+                } else {
+                    let mut p_data = pool.get();
+                    packet.data.write(&mut p_data).unwrap();
+                    send_buffer.push(encode(packet.header, p_data)?);
+                }
+            } else {
+                let mut p_data = pool.get();
+                packet.data.write(&mut p_data).unwrap();
+                let mut header = packet.header;
+                // Client id for clients
+                if is_client {
+                    header.c_id = Some(0);
+                } else {
+                    header.c_id = None;
+                }
+                // Fake encrypt if needed
+                if algs::should_encrypt(header.get_type(), false) {
+                    header.set_unencrypted(false);
+                    algs::encrypt_fake(&mut header, &mut p_data)?;
+                }
+
+                let mut buf = pool.get();
+                header.write(&mut buf)?;
+                buf.append(&mut p_data);
+                pool.put(p_data);
+                send_buffer.push(UdpPacket(buf));
+            }
+            // Measure the round trip time when the pong is received
+            if let Some(p_id) = ping_id {
+                con.ping_sent(p_id);
+            }
+        } else {
+            // We are not yet connected, so do nothing
+            let mut buf = pool.get();
+            packet.write(&mut buf)?;
+            send_buffer.push(UdpPacket(buf));
+        }
+        Ok(true)
+    }
 }
 
 impl<CS: 'static> PacketCodecSink<CS, ::handler_data::DataUdpPackets<CS>> {
@@ -567,131 +736,23 @@ impl<
         }
 
         let p_type = packet.header.get_type();
-        let mut start_p_id = 0;
-        let mut packets = {
-            let data = self.data.upgrade().unwrap();
-            let mut data = data.borrow_mut();
-            let is_client = data.is_client;
-            let use_newprotocol = (packet.header.get_type()
-                == PacketType::Command
-                || packet.header.get_type() == PacketType::CommandLow)
-                && data.is_client;
-
-            if let Some(con) = data.connections.get_mut(&addr) {
-                if let Some(params) = con.params.as_mut() {
-                    let p_type = packet.header.get_type();
-                    let type_i = p_type.to_usize().unwrap();
-                    start_p_id = params.outgoing_p_ids[type_i].1;
-                    // Compress and split packet
-                    let mut packets = if p_type == PacketType::Command
-                        || p_type == PacketType::CommandLow
-                    {
-                        algs::compress_and_split(&packet)
-                    } else {
-                        let mut data = Vec::new();
-                        packet.data.write(&mut data).unwrap();
-                        vec![(packet.header, data)]
-                    };
-                    let packets = packets
-                        .drain(..)
-                        .map(|(mut header, mut p_data)| -> Result<_> {
-                            // Packet data (without header)
-                            // Get packet id
-                            let (mut gen, mut p_id) = params.outgoing_p_ids[type_i];
-                            header.p_id = p_id;
-
-                            // Client id for clients
-                            if is_client {
-                                header.c_id = Some(params.c_id);
-                            } else {
-                                header.c_id = None;
-                            }
-
-                            // Set newprotocol flag if needed
-                            if use_newprotocol {
-                                header.set_newprotocol(true);
-                            }
-
-                            // Encrypt if necessary, fake encrypt the initivexpand packet
-                            if algs::should_encrypt(
-                                header.get_type(),
-                                params.voice_encryption,
-                            ) {
-                                header.set_unencrypted(false);
-                                if header.get_type() == PacketType::Command
-                                    && !is_client && header.p_id == 0
-                                {
-                                    algs::encrypt_fake(&mut header, &mut p_data)?;
-                                } else {
-                                    algs::encrypt(
-                                        &mut header,
-                                        &mut p_data,
-                                        gen,
-                                        &params.shared_iv,
-                                    )?;
-                                }
-                            } else {
-                                header.set_unencrypted(true);
-                                header.mac.copy_from_slice(&params.shared_mac);
-                            };
-
-                            // Increment outgoing_p_ids
-                            p_id = p_id.wrapping_add(1);
-                            if p_id == 0 {
-                                gen = gen.wrapping_add(1);
-                            }
-                            params.outgoing_p_ids[type_i] = (gen, p_id);
-                            let mut buf = Vec::new();
-                            header.write(&mut buf)?;
-                            buf.append(&mut p_data);
-                            Ok(UdpPacket(buf))
-                        })
-                        .collect::<Result<Vec<_>>>()?;
-                    packets
-                } else {
-                    let mut p_data = Vec::new();
-                    packet.data.write(&mut p_data).unwrap();
-                    let mut header = packet.header;
-                    // Client id for clients
-                    if is_client {
-                        header.c_id = Some(0);
-                    } else {
-                        header.c_id = None;
-                    }
-                    // Fake encrypt if needed
-                    if algs::should_encrypt(header.get_type(), false) {
-                        header.set_unencrypted(false);
-                        algs::encrypt_fake(&mut header, &mut p_data)?;
-                    }
-
-                    let mut buf = Vec::new();
-                    header.write(&mut buf)?;
-                    buf.append(&mut p_data);
-                    vec![UdpPacket(buf)]
+        // The send buffer is empty, so it can take the new udp packets
+        let res = self.encode(addr, packet);
+        if res.is_err() {
+            self.send_buffer.clear();
+        }
+        if !res? {
+            return Ok(futures::AsyncSink::Ready);
+        }
+        if let Some(data) = self.data.upgrade() {
+            if let Some(con) = data.borrow_mut().connections.get_mut(&addr) {
+                for p in &self.send_buffer {
+                    con.stats.packet_sent(p_type, p.0.len());
                 }
-            } else {
-                // We are not yet connected, so do nothing
-                let mut buf = Vec::new();
-                packet.write(&mut buf)?;
-                vec![UdpPacket(buf)]
             }
-        };
-        // Add command packets to send queue instead of sending them
-        if p_type.is_command() {
-            for p in packets {
-                Data::add_outgoing_packet(
-                    self.data.upgrade().unwrap(),
-                    p_type,
-                    start_p_id,
-                    (addr, p),
-                );
-                start_p_id = start_p_id.wrapping_add(1);
-            }
-        } else {
-            packets.reverse();
-            self.send_buffer = packets;
-            self.send_addr = addr;
         }
+        self.send_buffer.reverse();
+        self.send_addr = addr;
         Ok(futures::AsyncSink::Ready)
     }
 
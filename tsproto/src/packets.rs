@@ -50,6 +50,236 @@ pub enum CodecType {
     OpusMusic,
 }
 
+/// The kind of clients, which receive a group whisper packet.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, FromPrimitive, ToPrimitive)]
+pub enum GroupWhisperType {
+    /// The clients of a server group, the target id is the server group id.
+    ServerGroup,
+    /// The clients of a channel group, the target id is the channel group id.
+    ChannelGroup,
+    /// All channel commanders.
+    ChannelCommander,
+    /// All clients.
+    AllClients,
+}
+
+/// The channels of the clients, which receive a group whisper packet. The
+/// channels are relative to the current channel of the sender.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, FromPrimitive, ToPrimitive)]
+pub enum GroupWhisperTarget {
+    AllChannels,
+    CurrentChannel,
+    ParentChannel,
+    AllParentChannels,
+    /// The current channel and its subchannels.
+    ChannelFamily,
+    /// The parent channels, the current channel and its subchannels.
+    CompleteChannelFamily,
+    Subchannels,
+}
+
+/// Read and write enums, which are sent as a single byte.
+macro_rules! u8_enum_impls {
+    ($($name:ident),*) => {
+        $(
+        impl $name {
+            pub fn read<T>(_: T, r: &mut Read) -> Result<$name> {
+                let val = r.read_u8()?;
+                $name::from_u8(val).ok_or_else(|| {
+                    format!("Invalid {} {}", stringify!($name), val).into()
+                })
+            }
+
+            pub fn write(&self, w: &mut Write) -> Result<()> {
+                w.write_u8(self.to_u8().unwrap())?;
+                Ok(())
+            }
+        }
+        )*
+    };
+}
+
+u8_enum_impls!(CodecType, GroupWhisperType, GroupWhisperTarget);
+
+/// A `ChannelId` identifies a channel.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct ChannelId(pub u64);
+
+/// A `ClientId` identifies a client which is connected to a server.
+///
+/// Every client that we see on a server has a `ClientId`, even our own
+/// connection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct ClientId(pub u16);
+
+/// The channels and clients, which receive a whisper packet.
+///
+/// Sent as the count of channels and clients, followed by the channel ids and
+/// the client ids.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct WhisperTargets {
+    pub channels: Vec<ChannelId>,
+    pub clients: Vec<ClientId>,
+}
+
+impl WhisperTargets {
+    pub fn read<T>(_: T, r: &mut Read) -> Result<WhisperTargets> {
+        let channel_count = r.read_u8()?;
+        let client_count = r.read_u8()?;
+        let mut res = WhisperTargets::default();
+        for _ in 0..channel_count {
+            res.channels
+                .push(ChannelId(r.read_u64::<NetworkEndian>()?));
+        }
+        for _ in 0..client_count {
+            res.clients.push(ClientId(r.read_u16::<NetworkEndian>()?));
+        }
+        Ok(res)
+    }
+
+    pub fn write(&self, w: &mut Write) -> Result<()> {
+        if self.channels.len() > 255 || self.clients.len() > 255 {
+            bail!("Cannot whisper to more than 255 channels or clients");
+        }
+        w.write_u8(self.channels.len() as u8)?;
+        w.write_u8(self.clients.len() as u8)?;
+        for c in &self.channels {
+            w.write_u64::<NetworkEndian>(c.0)?;
+        }
+        for c in &self.clients {
+            w.write_u16::<NetworkEndian>(c.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Create a `VoiceWhisper` packet.
+///
+/// A whisper is sent either to a list of channels and clients or to a group
+/// of clients, both cannot be combined.
+///
+/// # Example
+///
+/// ```
+/// # use tsproto::packets::*;
+/// let packet = WhisperBuilder::new()
+///     .channel(ChannelId(1))
+///     .client(ClientId(5))
+///     .build(0, CodecType::OpusVoice, vec![1, 2, 3])
+///     .unwrap();
+/// assert_eq!(packet.header.get_type(), PacketType::VoiceWhisper);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WhisperBuilder {
+    targets: WhisperTargets,
+    group: Option<(GroupWhisperType, GroupWhisperTarget, u64)>,
+}
+
+impl WhisperBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn channel(mut self, channel: ChannelId) -> Self {
+        self.targets.channels.push(channel);
+        self
+    }
+
+    pub fn channels<I: IntoIterator<Item = ChannelId>>(
+        mut self,
+        channels: I,
+    ) -> Self {
+        self.targets.channels.extend(channels);
+        self
+    }
+
+    pub fn client(mut self, client: ClientId) -> Self {
+        self.targets.clients.push(client);
+        self
+    }
+
+    pub fn clients<I: IntoIterator<Item = ClientId>>(
+        mut self,
+        clients: I,
+    ) -> Self {
+        self.targets.clients.extend(clients);
+        self
+    }
+
+    /// Whisper to the members of a server group in the given channels.
+    pub fn server_group(
+        mut self,
+        group: u64,
+        target: GroupWhisperTarget,
+    ) -> Self {
+        self.group = Some((GroupWhisperType::ServerGroup, target, group));
+        self
+    }
+
+    /// Whisper to the members of a channel group in the given channels.
+    pub fn channel_group(
+        mut self,
+        group: u64,
+        target: GroupWhisperTarget,
+    ) -> Self {
+        self.group = Some((GroupWhisperType::ChannelGroup, target, group));
+        self
+    }
+
+    /// Whisper to all clients in the current channel and its subchannels.
+    ///
+    /// If `complete` is `true`, the parent channels are included.
+    pub fn channel_family(mut self, complete: bool) -> Self {
+        let target = if complete {
+            GroupWhisperTarget::CompleteChannelFamily
+        } else {
+            GroupWhisperTarget::ChannelFamily
+        };
+        self.group = Some((GroupWhisperType::AllClients, target, 0));
+        self
+    }
+
+    /// Create the packet, the `id` is the voice packet id.
+    ///
+    /// Fails if no targets or both targets and a group were set.
+    pub fn build(
+        self,
+        id: u16,
+        codec_type: CodecType,
+        voice_data: Vec<u8>,
+    ) -> Result<Packet> {
+        let has_targets = !self.targets.channels.is_empty()
+            || !self.targets.clients.is_empty();
+        let mut header = Header::new(PacketType::VoiceWhisper);
+        let data = match self.group {
+            Some(_) if has_targets => {
+                bail!("A whisper cannot have a group and other targets")
+            }
+            Some((group_whisper_type, group_whisper_target, target_id)) => {
+                header.set_newprotocol(true);
+                C2SVoice::VoiceWhisperNew {
+                    id,
+                    codec_type,
+                    group_whisper_type,
+                    group_whisper_target,
+                    target_id,
+                    voice_data,
+                }
+            }
+            None if !has_targets => bail!("A whisper needs a target"),
+            None => C2SVoice::VoiceWhisper {
+                id,
+                codec_type,
+                targets: self.targets,
+                voice_data,
+            },
+        };
+        Ok(Packet::new(header, Data::C2SVoice(data)))
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct UdpPacket(pub Vec<u8>);
 
@@ -151,3 +381,222 @@ impl Header {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use packets::*;
+
+    /// Write a packet, which was sent by a client, and read it again.
+    fn round_trip(mut packet: Packet) -> Packet {
+        packet.header.c_id = Some(0);
+        let mut buf = Vec::new();
+        packet.write(&mut buf).unwrap();
+        Packet::read(&true, &mut Cursor::new(&buf)).unwrap()
+    }
+
+    #[test]
+    fn whisper_targets() {
+        let packet = WhisperBuilder::new()
+            .channels(vec![ChannelId(1), ChannelId(0x1234_5678_9abc)])
+            .client(ClientId(7))
+            .build(5, CodecType::OpusVoice, vec![1, 2, 3])
+            .unwrap();
+        assert!(!packet.header.get_newprotocol());
+        match round_trip(packet).data {
+            Data::C2SVoice(C2SVoice::VoiceWhisper {
+                id,
+                codec_type,
+                targets,
+                voice_data,
+            }) => {
+                assert_eq!(id, 5);
+                assert_eq!(codec_type, CodecType::OpusVoice);
+                assert_eq!(
+                    targets.channels,
+                    vec![ChannelId(1), ChannelId(0x1234_5678_9abc)]
+                );
+                assert_eq!(targets.clients, vec![ClientId(7)]);
+                assert_eq!(voice_data, vec![1, 2, 3]);
+            }
+            data => panic!("Unexpected packet {:?}", data),
+        }
+    }
+
+    #[test]
+    fn whisper_group() {
+        let packet = WhisperBuilder::new()
+            .server_group(9, GroupWhisperTarget::AllChannels)
+            .build(1, CodecType::OpusMusic, vec![4])
+            .unwrap();
+        assert!(packet.header.get_newprotocol());
+        match round_trip(packet).data {
+            Data::C2SVoice(C2SVoice::VoiceWhisperNew {
+                group_whisper_type,
+                group_whisper_target,
+                target_id,
+                voice_data,
+                ..
+            }) => {
+                assert_eq!(group_whisper_type, GroupWhisperType::ServerGroup);
+                assert_eq!(
+                    group_whisper_target,
+                    GroupWhisperTarget::AllChannels
+                );
+                assert_eq!(target_id, 9);
+                assert_eq!(voice_data, vec![4]);
+            }
+            data => panic!("Unexpected packet {:?}", data),
+        }
+
+        let packet = WhisperBuilder::new()
+            .channel_family(true)
+            .build(1, CodecType::OpusVoice, Vec::new())
+            .unwrap();
+        match round_trip(packet).data {
+            Data::C2SVoice(C2SVoice::VoiceWhisperNew {
+                group_whisper_type,
+                group_whisper_target,
+                ..
+            }) => {
+                assert_eq!(group_whisper_type, GroupWhisperType::AllClients);
+                assert_eq!(
+                    group_whisper_target,
+                    GroupWhisperTarget::CompleteChannelFamily
+                );
+            }
+            data => panic!("Unexpected packet {:?}", data),
+        }
+    }
+
+    #[test]
+    fn whisper_invalid() {
+        assert!(
+            WhisperBuilder::new()
+                .build(0, CodecType::OpusVoice, Vec::new())
+                .is_err()
+        );
+        assert!(
+            WhisperBuilder::new()
+                .client(ClientId(1))
+                .channel_group(2, GroupWhisperTarget::CurrentChannel)
+                .build(0, CodecType::OpusVoice, Vec::new())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn server_voice() {
+        let mut header = Header::new(PacketType::Voice);
+        let data = Data::S2CVoice(S2CVoice::Voice {
+            id: 0x102,
+            from_id: 3,
+            codec_type: CodecType::OpusVoice,
+            voice_data: vec![7, 8],
+        });
+        let mut buf = Vec::new();
+        Packet::new(header.clone(), data).write(&mut buf).unwrap();
+        assert_eq!(&buf[11..], &[1, 2, 0, 3, 4, 7, 8]);
+
+        let packet = Packet::read(&false, &mut Cursor::new(&buf)).unwrap();
+        match packet.data {
+            Data::S2CVoice(S2CVoice::Voice { from_id, codec_type, .. }) => {
+                assert_eq!(from_id, 3);
+                assert_eq!(codec_type, CodecType::OpusVoice);
+            }
+            data => panic!("Unexpected packet {:?}", data),
+        }
+
+        // Whisper packets from the server look the same
+        header.set_type(PacketType::VoiceWhisper);
+        buf[10] = header.p_type;
+        let packet = Packet::read(&false, &mut Cursor::new(&buf)).unwrap();
+        match packet.data {
+            Data::S2CVoice(S2CVoice::VoiceWhisper { id, voice_data, .. }) => {
+                assert_eq!(id, 0x102);
+                assert_eq!(voice_data, vec![7, 8]);
+            }
+            data => panic!("Unexpected packet {:?}", data),
+        }
+    }
+
+    /// Check that `read_buf` gives the same result as `read`.
+    fn check_read_buf(packet: Packet, from_client: bool) {
+        let mut buf = Vec::new();
+        packet.write(&mut buf).unwrap();
+        let mut r = Cursor::new(&buf);
+        let header = Header::read(&from_client, &mut r).unwrap();
+        let pos = r.position() as usize;
+        let data = Data::read_buf(&header, buf[pos..].to_vec()).unwrap();
+        assert_eq!(format!("{:?}", data), format!("{:?}", packet.data));
+    }
+
+    #[test]
+    fn read_buf() {
+        let mut header = Header::new(PacketType::Voice);
+        check_read_buf(
+            Packet::new(
+                header.clone(),
+                Data::S2CVoice(S2CVoice::Voice {
+                    id: 0x102,
+                    from_id: 3,
+                    codec_type: CodecType::OpusVoice,
+                    voice_data: vec![7, 8],
+                }),
+            ),
+            false,
+        );
+        header.set_type(PacketType::VoiceWhisper);
+        check_read_buf(
+            Packet::new(
+                header,
+                Data::S2CVoice(S2CVoice::VoiceWhisper {
+                    id: 1,
+                    from_id: 2,
+                    codec_type: CodecType::OpusMusic,
+                    voice_data: Vec::new(),
+                }),
+            ),
+            false,
+        );
+
+        let mut header = Header::new(PacketType::Voice);
+        header.c_id = Some(0);
+        check_read_buf(
+            Packet::new(
+                header,
+                Data::C2SVoice(C2SVoice::Voice {
+                    id: 4,
+                    codec_type: CodecType::OpusVoice,
+                    voice_data: vec![1, 2, 3],
+                }),
+            ),
+            true,
+        );
+
+        let mut packet = WhisperBuilder::new()
+            .channels(vec![ChannelId(1), ChannelId(2)])
+            .client(ClientId(7))
+            .build(5, CodecType::OpusVoice, vec![1, 2, 3])
+            .unwrap();
+        packet.header.c_id = Some(0);
+        check_read_buf(packet, true);
+
+        let mut packet = WhisperBuilder::new()
+            .server_group(9, GroupWhisperTarget::AllChannels)
+            .build(1, CodecType::OpusMusic, vec![4])
+            .unwrap();
+        packet.header.c_id = Some(0);
+        check_read_buf(packet, true);
+    }
+
+    #[test]
+    fn read_buf_invalid() {
+        let header = Header::new(PacketType::Command);
+        assert!(Data::read_buf(&header, vec![0; 10]).is_err());
+        // Too short
+        let header = Header::new(PacketType::Voice);
+        assert!(Data::read_buf(&header, vec![0, 1, 0]).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use chrono::Duration;
+use futures::{self, Future, Sink, Stream};
+use tokio_core::reactor::Interval;
+
+use {packets, Error, Result};
+use handler_data::Data;
+use packets::*;
+use resend::ResendState;
+
+/// Statistics about the keepalive pings of a connection.
+#[derive(Debug, Clone)]
+pub struct PingStats {
+    /// The smoothed round trip time of the pings.
+    pub ping: Duration,
+    /// The deviation of the round trip time (jitter).
+    pub ping_deviation: Duration,
+    /// The number of sent pings.
+    pub sent: u64,
+    /// The number of pings, which were answered with a pong.
+    pub received: u64,
+    /// The number of pings, which were not answered in the `ping_timeout`.
+    pub lost: u64,
+}
+
+impl Default for PingStats {
+    fn default() -> Self {
+        Self {
+            ping: Duration::zero(),
+            ping_deviation: Duration::zero(),
+            sent: 0,
+            received: 0,
+            lost: 0,
+        }
+    }
+}
+
+impl PingStats {
+    /// The fraction of lost pings, between `0` and `1`.
+    pub fn loss(&self) -> f64 {
+        let finished = self.received + self.lost;
+        if finished == 0 {
+            0.0
+        } else {
+            self.lost as f64 / finished as f64
+        }
+    }
+
+    /// Add the round trip time of a ping to the statistics.
+    pub(crate) fn add_rtt(&mut self, rtt: Duration) {
+        if self.received == 0 {
+            self.ping = rtt;
+        } else {
+            let diff = if rtt > self.ping {
+                rtt - self.ping
+            } else {
+                self.ping - rtt
+            };
+            self.ping_deviation = self.ping_deviation * 3 / 4 + diff / 4;
+            self.ping = self.ping * 7 / 8 + rtt / 8;
+        }
+        self.received += 1;
+    }
+}
+
+/// A future which sends a `Ping` packet to all established connections in the
+/// `ping_interval` of the [`TimeoutConfig`].
+///
+/// The answering `Pong` packets update the round trip time of the connection
+/// and the [`PingStats`].
+///
+/// [`TimeoutConfig`]: ../resend/struct.TimeoutConfig.html
+/// [`PingStats`]: struct.PingStats.html
+pub struct PingFuture<CS> {
+    data: Weak<RefCell<Data<CS>>>,
+    interval: Interval,
+}
+
+impl<CS: 'static> PingFuture<CS> {
+    pub fn new(data: Rc<RefCell<Data<CS>>>) -> Result<Self> {
+        let interval = {
+            let data = data.borrow();
+            Interval::new(
+                data.timeout_config.ping_interval.to_std().unwrap(),
+                &data.handle,
+            )?
+        };
+        Ok(Self {
+            data: Rc::downgrade(&data),
+            interval,
+        })
+    }
+
+    /// Spawn a ping future for the data.
+    pub fn apply(data: Rc<RefCell<Data<CS>>>) -> Result<()> {
+        let future = Self::new(data.clone())?;
+        let data = data.borrow();
+        let logger = data.logger.clone();
+        data.handle.spawn(future.map_err(move |e| {
+            error!(logger, "Ping"; "error" => ?e);
+        }));
+        Ok(())
+    }
+
+    /// Send a ping to all established connections.
+    fn send_pings(data: Rc<RefCell<Data<CS>>>) {
+        let mut pings = Vec::new();
+        {
+            let mut data = data.borrow_mut();
+            let data = &mut *data;
+            let timeout = data.timeout_config.ping_timeout;
+            for (addr, con) in &mut data.connections {
+                con.remove_lost_pings(timeout);
+                if con.resend_state == ResendState::Disconnecting
+                    || con.params.is_none()
+                {
+                    continue;
+                }
+                // The ping is recorded by the packet codec, which assigns the
+                // packet id.
+                pings.push(*addr);
+            }
+        }
+
+        let (handle, logger) = {
+            let data = data.borrow();
+            (data.handle.clone(), data.logger.clone())
+        };
+        for addr in pings {
+            let packet =
+                Packet::new(Header::new(PacketType::Ping), packets::Data::Ping);
+            let logger = logger.clone();
+            handle.spawn(
+                Data::get_packets(data.clone())
+                    .send((addr, packet))
+                    .map(|_| ())
+                    .map_err(move |e| {
+                        warn!(logger, "Failed to send ping"; "addr" => %addr,
+                            "error" => ?e);
+                    }),
+            );
+        }
+    }
+}
+
+impl<CS: 'static> Future for PingFuture<CS> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        while let futures::Async::Ready(tick) = self.interval.poll()? {
+            if tick.is_none() {
+                return Ok(futures::Async::Ready(()));
+            }
+            let data = if let Some(data) = self.data.upgrade() {
+                data
+            } else {
+                // The data is gone, so stop pinging
+                return Ok(futures::Async::Ready(()));
+            };
+            Self::send_pings(data);
+        }
+        Ok(futures::Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use chrono::{Duration, Utc};
+    use futures::{self, Future, Sink};
+    use futures::unsync::mpsc;
+    use num::ToPrimitive;
+    use slog;
+    use tokio_core::reactor::Core;
+
+    use Error;
+    use crypto::{CryptoProvider, Provider};
+    use handler_data::{ConnectedParams, Connection, Data};
+    use identity::Identity;
+    use packet_codec::PacketCodecSink;
+    use packets::{self, *};
+    use ping::*;
+
+    #[test]
+    fn rtt_and_deviation() {
+        let mut stats = PingStats::default();
+        stats.add_rtt(Duration::milliseconds(80));
+        assert_eq!(stats.ping, Duration::milliseconds(80));
+        assert_eq!(stats.ping_deviation, Duration::zero());
+
+        stats.add_rtt(Duration::milliseconds(160));
+        assert_eq!(stats.ping, Duration::milliseconds(90));
+        assert_eq!(stats.ping_deviation, Duration::milliseconds(20));
+
+        // A faster answer lowers the ping, the deviation uses the difference
+        stats.add_rtt(Duration::milliseconds(10));
+        assert_eq!(stats.ping, Duration::milliseconds(80));
+        assert_eq!(stats.ping_deviation, Duration::milliseconds(35));
+        assert_eq!(stats.received, 3);
+    }
+
+    #[test]
+    fn ping_loss() {
+        let mut stats = PingStats::default();
+        assert_eq!(stats.loss(), 0.0);
+        stats.sent = 5;
+        stats.received = 3;
+        stats.lost = 1;
+        assert_eq!(stats.loss(), 0.25);
+        stats.received = 0;
+        assert_eq!(stats.loss(), 1.0);
+    }
+
+    #[test]
+    fn match_pongs() {
+        let mut con = Connection::new(());
+        con.ping_sent(1);
+        con.ping_sent(2);
+        assert_eq!(con.ping_stats.sent, 2);
+
+        // Unknown ids are ignored
+        con.pong_received(3);
+        assert_eq!(con.ping_stats.received, 0);
+        assert_eq!(con.pending_pings.len(), 2);
+
+        // Pongs can arrive out of order
+        con.pong_received(2);
+        assert_eq!(con.ping_stats.received, 1);
+        assert_eq!(
+            con.pending_pings.iter().map(|&(id, _)| id).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        // A duplicated pong counts only once
+        con.pong_received(2);
+        assert_eq!(con.ping_stats.received, 1);
+        con.pong_received(1);
+        assert_eq!(con.ping_stats.received, 2);
+        assert!(con.pending_pings.is_empty());
+    }
+
+    #[test]
+    fn lost_pings() {
+        let mut con = Connection::new(());
+        let now = Utc::now();
+        con.pending_pings.push_back((1, now - Duration::seconds(10)));
+        con.pending_pings.push_back((2, now - Duration::seconds(6)));
+        con.pending_pings.push_back((3, now));
+
+        con.remove_lost_pings(Duration::seconds(5));
+        assert_eq!(con.ping_stats.lost, 2);
+        assert_eq!(
+            con.pending_pings.iter().map(|&(id, _)| id).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        // A pong after the timeout does not count as received
+        con.pong_received(1);
+        assert_eq!(con.ping_stats.received, 0);
+        assert_eq!(con.ping_stats.loss(), 1.0);
+    }
+
+    #[test]
+    fn record_sent_pings() {
+        ::init().unwrap();
+        let core = Core::new().unwrap();
+        let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+        let (send, _recv) = mpsc::unbounded();
+        let data = Data::with_transport(
+            "127.0.0.1:0".parse().unwrap(),
+            Identity::create().unwrap(),
+            core.handle(),
+            true,
+            slog::Logger::root(slog::Discard, o!()),
+            futures::stream::empty(),
+            send.clone().sink_map_err(|_| "Channel closed".into()),
+        );
+        let mut con = Connection::new(());
+        let key = Provider.generate_key().unwrap();
+        let mut params = ConnectedParams::new(key, vec![0; 20], [0; 8]);
+        // Some pings were already sent
+        params.outgoing_p_ids[PacketType::Ping.to_usize().unwrap()].1 = 5;
+        con.params = Some(params);
+        data.borrow_mut().connections.insert(addr, con);
+
+        let mut sink = PacketCodecSink::new(
+            data.clone(),
+            send.sink_map_err(|_| Error::from("Channel closed")),
+        );
+        for _ in 0..2 {
+            let packet =
+                Packet::new(Header::new(PacketType::Ping), packets::Data::Ping);
+            sink = sink.send((addr, packet)).wait().unwrap();
+        }
+        // Other packets are not recorded
+        let packet =
+            Packet::new(Header::new(PacketType::Pong), packets::Data::Pong(1));
+        sink.send((addr, packet)).wait().unwrap();
+
+        let data = data.borrow();
+        let con = &data.connections[&addr];
+        assert_eq!(con.ping_stats.sent, 2);
+        assert_eq!(
+            con.pending_pings.iter().map(|&(id, _)| id).collect::<Vec<_>>(),
+            vec![5, 6]
+        );
+    }
+}
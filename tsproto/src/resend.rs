@@ -1,18 +1,25 @@
 use std::cell::RefCell;
+use std::cmp;
+use std::io::Cursor;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
 use std::time::Instant;
 
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use futures::{self, Future, Sink};
 use futures::task;
 use tokio_core::reactor::Timeout;
 
-use Error;
-use handler_data::{Data, SendRecord};
+use {Error, Result, MAX_SEND_QUEUE_LEN};
+use handler_data::{Connection, Data, DisconnectReason, SendRecord};
 use packets::*;
 
-enum ResendState {
+/// Check the state of all connections at least in this interval.
+const STATE_CHECK_INTERVAL_MS: i64 = 1000;
+
+/// The state of the resend algorithm for a connection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ResendState {
     /// Important for clients: The first packet is sent, but we got no response
     /// yet, so we don't know if the server exists.
     Connecting,
@@ -32,7 +39,15 @@ enum ResendState {
     Disconnecting,
 }
 
+impl ResendState {
+    /// If the connection is too unstable to send voice packets.
+    pub fn is_stalled(&self) -> bool {
+        *self == ResendState::Stalling || *self == ResendState::Dead
+    }
+}
+
 /// Configure the length of timeouts.
+#[derive(Debug, Clone)]
 pub struct TimeoutConfig {
     /// Interval to resend the first packet.
     pub connecting_interval: Duration,
@@ -49,9 +64,16 @@ pub struct TimeoutConfig {
     /// When in [`Dead`] state, close the connection after no packet is received
     /// for this duration.
     pub dead_timeout: Duration,
-    /// When in [`Disconnecting`] state, close the connection after no packet is
-    /// received for this duration.
+    /// When in [`Disconnecting`] state, close the connection after this
+    /// duration, even if the other side did not acknowledge the disconnect.
     pub disconnect_timeout: Duration,
+    /// Give up connecting if the RSA puzzle from the `Init3` packet cannot be
+    /// solved in this time.
+    pub rsa_puzzle_timeout: Duration,
+    /// Send a keepalive `Ping` to established connections in this interval.
+    pub ping_interval: Duration,
+    /// Count a `Ping` as lost if no `Pong` was received after this duration.
+    pub ping_timeout: Duration,
 }
 
 impl Default for TimeoutConfig {
@@ -64,143 +86,891 @@ impl Default for TimeoutConfig {
             stalling_timeout: Duration::seconds(30),
             dead_timeout: Duration::seconds(0),
             disconnect_timeout: Duration::seconds(5),
+            rsa_puzzle_timeout: Duration::seconds(5),
+            ping_interval: Duration::seconds(1),
+            ping_timeout: Duration::seconds(5),
         }
     }
 }
 
-// TODO Implement resending as sink layer.
-pub struct ResendFuture<CS> {
+/// A `Sink` layer, which stores sent `Command` and `CommandLow` packets and
+/// resends them until they are acknowledged.
+///
+/// `Init` packets of clients are resent in the `connecting_interval` until the
+/// next step of the handshake is received.
+///
+/// The packets are stored in the send queue of their [`Connection`]. If this
+/// queue is full, the sink is not ready until an acknowledgement is received.
+///
+/// The resending itself is done by the [`ResendFuture`], which has to be
+/// spawned on the reactor.
+///
+/// [`Connection`]: ../handler_data/struct.Connection.html
+/// [`ResendFuture`]: struct.ResendFuture.html
+pub struct ResendSink<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> {
+    core: Rc<RefCell<ResendCore<CS, Inner>>>,
+}
+
+/// Drives the resending of packets for a [`ResendSink`].
+///
+/// This future finishes when the sink or the [`Data`] is dropped.
+///
+/// [`ResendSink`]: struct.ResendSink.html
+/// [`Data`]: ../handler_data/struct.Data.html
+pub struct ResendFuture<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> {
+    core: Weak<RefCell<ResendCore<CS, Inner>>>,
+}
+
+/// The data which is shared between a [`ResendSink`] and its [`ResendFuture`].
+struct ResendCore<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> {
     data: Weak<RefCell<Data<CS>>>,
-    sink: Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>,
-    timeout_config: TimeoutConfig,
+    inner: Inner,
     /// The future to wake us up after a certain time.
     timeout: Timeout,
     /// If we are sending and should poll the sink.
     is_sending: bool,
-    state: ResendState,
+    /// The task of the [`ResendFuture`], it is woken up when the sink is
+    /// dropped.
+    ///
+    /// [`ResendFuture`]: struct.ResendFuture.html
+    task: Option<task::Task>,
 }
 
-impl<CS> ResendFuture<CS> {
+impl<
+    CS: 'static,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> ResendSink<CS, Inner> {
+    /// Create a new sink and the future which resends its packets.
     pub fn new(
         data: Rc<RefCell<Data<CS>>>,
-        sink: Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>,
-    ) -> Self {
-        Self::with_timeout_config(data, sink, TimeoutConfig::default())
-    }
-
-    pub fn with_timeout_config(
-        data: Rc<RefCell<Data<CS>>>,
-        sink: Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>,
-        timeout_config: TimeoutConfig,
-    ) -> Self {
+        inner: Inner,
+    ) -> Result<(Self, ResendFuture<CS, Inner>)> {
         let handle = data.borrow().handle.clone();
-        Self {
+        let core = Rc::new(RefCell::new(ResendCore {
             data: Rc::downgrade(&data),
-            sink,
-            timeout_config,
+            inner,
             timeout: Timeout::new(
                 Duration::seconds(1).to_std().unwrap(),
                 &handle,
-            ).unwrap(),
+            )?,
             is_sending: false,
-            state: ResendState::Normal,
+            task: None,
+        }));
+        let future = ResendFuture {
+            core: Rc::downgrade(&core),
+        };
+        Ok((Self { core }, future))
+    }
+}
+
+impl<CS: 'static>
+    ResendSink<
+        CS,
+        Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>,
+    > {
+    /// Add a resend sink to the data and spawn the future, which resends the
+    /// packets.
+    pub fn apply(data: Rc<RefCell<Data<CS>>>) -> Result<()> {
+        let inner = data.borrow_mut().udp_packet_sink.take().unwrap();
+        let (sink, future) = Self::new(data.clone(), inner)?;
+        let mut data = data.borrow_mut();
+        data.udp_packet_sink = Some(Box::new(sink));
+        let logger = data.logger.clone();
+        data.handle.spawn(future.map_err(move |e| {
+            error!(logger, "Resend"; "error" => ?e);
+        }));
+        Ok(())
+    }
+}
+
+/// The time until a packet should be resent.
+///
+/// If the packet was already sent before (`tries` is not zero), the smoothed
+/// round trip time is doubled.
+fn retransmission_timeout<CS>(
+    config: &TimeoutConfig,
+    con: &mut Connection<CS>,
+    p_type: PacketType,
+    tries: usize,
+) -> Duration {
+    if p_type == PacketType::Init {
+        return config.connecting_interval;
+    }
+    match con.resend_state {
+        ResendState::Connecting => config.connecting_interval,
+        ResendState::Stalling | ResendState::Dead => config.stalling_interval,
+        ResendState::Normal | ResendState::Disconnecting => {
+            // Double srtt on packet loss
+            if tries != 0 {
+                con.srtt = cmp::min(con.srtt * 2, config.normal_timeout);
+            }
+            cmp::min(con.srtt + con.srtt_dev * 4, config.stalling_interval)
         }
     }
 }
 
-impl<CS> Future for ResendFuture<CS> {
-    type Item = ();
-    type Error = Error;
+/// Advance the resend state of all connections and remove connections, which
+/// timed out.
+fn update_states<CS>(data: &mut Data<CS>, now: DateTime<Utc>) {
+    let config = data.timeout_config.clone();
+    let logger = data.logger.clone();
+    let mut closed = Vec::new();
+    for (addr, con) in &mut data.connections {
+        let in_state = now.naive_utc()
+            .signed_duration_since(con.resend_state_since.naive_utc());
+        // The time since the last sign of life
+        let idle = now.naive_utc().signed_duration_since(
+            cmp::max(con.resend_state_since, con.last_received).naive_utc(),
+        );
+        // Check if the handshake does not progress
+        if con.send_queue.iter().any(|r| {
+            r.p_type == PacketType::Init
+                && now.naive_utc().signed_duration_since(r.sent.naive_utc())
+                    > config.connecting_timeout
+        }) {
+            closed.push((
+                *addr,
+                DisconnectReason::HandshakeFailed(
+                    "Connecting timed out".into(),
+                ),
+            ));
+            continue;
+        }
+        match con.resend_state {
+            ResendState::Connecting => {
+                if in_state > config.connecting_timeout {
+                    closed.push((
+                        *addr,
+                        DisconnectReason::HandshakeFailed(
+                            "Connecting timed out".into(),
+                        ),
+                    ));
+                }
+            }
+            ResendState::Normal => {
+                // When the oldest packet, that is not acknowledged yet, was
+                // sent.
+                let oldest = con.send_queue.iter().map(|r| r.sent).min();
+                if let Some(sent) = oldest {
+                    let waiting = now.naive_utc()
+                        .signed_duration_since(sent.naive_utc());
+                    if waiting > con.srtt + config.normal_timeout {
+                        warn!(logger, "Connection is stalling";
+                            "addr" => %addr);
+                        con.set_resend_state(ResendState::Stalling);
+                    }
+                }
+            }
+            ResendState::Stalling => {
+                if in_state > config.stalling_timeout {
+                    warn!(logger, "Connection is dead"; "addr" => %addr);
+                    con.set_resend_state(ResendState::Dead);
+                }
+            }
+            ResendState::Dead => {
+                if idle >= config.dead_timeout {
+                    closed.push((*addr, DisconnectReason::TimedOut));
+                }
+            }
+            ResendState::Disconnecting => {
+                if in_state >= config.disconnect_timeout {
+                    // Give up waiting for the acknowledgement
+                    closed.push((*addr, DisconnectReason::Disconnected));
+                }
+            }
+        }
+    }
 
-    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
-        // Set task
+    for (addr, reason) in closed {
+        if reason != DisconnectReason::Disconnected {
+            warn!(logger, "Closing connection"; "addr" => %addr,
+                "reason" => %reason);
+        }
+        data.remove_connection(addr, reason);
+    }
+}
+
+impl<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> ResendCore<CS, Inner> {
+    /// Wake up the task at the given time.
+    fn schedule(
+        &mut self,
+        next: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let dur = next.naive_utc().signed_duration_since(now.naive_utc());
+        self.timeout
+            .reset(Instant::now() + dur.to_std().unwrap_or_default());
+        if let futures::Async::Ready(()) = self.timeout.poll()? {
+            task::current().notify();
+        }
+        Ok(())
+    }
+
+    fn poll_sending(&mut self) -> futures::Poll<(), Error> {
+        if self.is_sending {
+            if let futures::Async::Ready(()) = self.inner.poll_complete()? {
+                self.is_sending = false;
+            }
+        }
+        if self.is_sending {
+            Ok(futures::Async::NotReady)
+        } else {
+            Ok(futures::Async::Ready(()))
+        }
+    }
+
+    fn start_send(
+        &mut self,
+        (addr, packet): (SocketAddr, UdpPacket),
+    ) -> futures::StartSend<(SocketAddr, UdpPacket), Error> {
+        let data = match self.data.upgrade() {
+            Some(data) => data,
+            None => return Err("Connection is gone".into()),
+        };
+        let p_type = {
+            let mut data = data.borrow_mut();
+            let is_client = data.is_client;
+            let header =
+                Header::read(&is_client, &mut Cursor::new(&packet.0))?;
+            let p_type = header.get_type();
+            if let Some(con) = data.connections.get_mut(&addr) {
+                if p_type.is_command()
+                    || (p_type == PacketType::Init && is_client)
+                {
+                    if con.send_queue.len() >= MAX_SEND_QUEUE_LEN {
+                        // Wait until a packet is acknowledged
+                        con.send_task = Some(task::current());
+                        return Ok(futures::AsyncSink::NotReady(
+                            (addr, packet),
+                        ));
+                    }
+                    Some((p_type, header.p_id))
+                } else {
+                    None
+                }
+            } else {
+                // Not connected
+                None
+            }
+        };
+
+        // Only packets, which are stored for resending, need a copy
+        let record =
+            p_type.map(|(p_type, p_id)| (p_type, p_id, packet.clone()));
+        if let futures::AsyncSink::NotReady(p) =
+            self.inner.start_send((addr, packet))?
+        {
+            return Ok(futures::AsyncSink::NotReady(p));
+        }
+        self.is_sending = true;
+
+        // Store the packet so it can be resent
+        if let Some((p_type, p_id, packet)) = record {
+            let mut data = data.borrow_mut();
+            let data = &mut *data;
+            if let Some(con) = data.connections.get_mut(&addr) {
+                let now = Utc::now();
+                let rto = retransmission_timeout(
+                    &data.timeout_config,
+                    con,
+                    p_type,
+                    0,
+                );
+                con.send_queue.push(SendRecord {
+                    sent: now,
+                    next: now + rto,
+                    tries: 1,
+                    p_type,
+                    p_id,
+                    packet: (addr, packet),
+                });
+            }
+            // Reschedule resending
+            if let Some(ref task) = data.resend_task {
+                task.notify();
+            }
+        }
+        Ok(futures::AsyncSink::Ready)
+    }
+
+    /// Resend packets, which were not acknowledged in time.
+    fn poll_resend(&mut self) -> futures::Poll<(), Error> {
+        self.task = Some(task::current());
         let data = if let Some(data) = self.data.upgrade() {
             data
         } else {
-            return Ok(futures::Async::NotReady);
+            // The data is gone, so stop resending
+            return Ok(futures::Async::Ready(()));
         };
         data.borrow_mut().resend_task = Some(task::current());
-        if self.is_sending {
-            if let futures::Async::Ready(()) = self.sink.poll_complete()? {
-                self.is_sending = false;
-            }
+        if let futures::Async::NotReady = self.poll_sending()? {
+            return Ok(futures::Async::NotReady);
         }
 
         let logger = data.borrow().logger.clone();
         let now = Utc::now();
-        while let Some(rec) = {
+        // Check the connection states regularly
+        let mut next_wakeup =
+            now + Duration::milliseconds(STATE_CHECK_INTERVAL_MS);
+
+        // Collect all packets which should be resent now
+        let mut due = Vec::new();
+        {
             let mut data = data.borrow_mut();
-            // Print packet for debugging
-            /*let mut q = ::std::collections::BinaryHeap::new();
-            mem::swap(&mut q, &mut data.send_queue);
-            let v = q.into_sorted_vec();
-            info!(data.logger, "Send queue: {:?}", v.iter().map(|rec| {
-                (rec.p_id, rec.next)
-            }).collect::<Vec<_>>());
-            data.send_queue.extend(v.into_iter());*/
-            if let Some(rec) = data.send_queue.peek() {
-                // Check if we should resend this packet
-                if rec.next > now {
-                    // Schedule next send
-                    let dur = rec.next
-                        .naive_utc()
-                        .signed_duration_since(now.naive_utc());
-                    let next = Instant::now() + dur.to_std().unwrap();
-                    self.timeout.reset(next);
-                    if let futures::Async::Ready(()) = self.timeout.poll()? {
-                        task::current().notify();
+            update_states(&mut *data, now);
+            let data = &mut *data;
+            for con in data.connections.values_mut() {
+                // When the oldest packet of this connection was sent
+                let oldest = con.send_queue.iter().map(|r| r.sent).min();
+                while let Some(rec) = {
+                    let mut next = None;
+                    if let Some(rec) = con.send_queue.peek() {
+                        // Check if we should resend this packet
+                        if rec.next > now {
+                            next = Some(rec.next);
+                        }
+                    }
+                    if let Some(next) = next {
+                        next_wakeup = cmp::min(next_wakeup, next);
+                        None
+                    } else {
+                        con.send_queue.pop()
                     }
-                    return Ok(futures::Async::NotReady);
+                } {
+                    let should_send = match con.resend_state {
+                        // Don't even try anymore
+                        ResendState::Dead => false,
+                        // Only resend the oldest packet of this connection
+                        ResendState::Stalling => Some(rec.sent) == oldest,
+                        _ => true,
+                    };
+                    let rto = retransmission_timeout(
+                        &data.timeout_config,
+                        con,
+                        rec.p_type,
+                        rec.tries,
+                    );
+                    due.push((rec, rto, should_send));
                 }
             }
-            data.send_queue.pop()
-        } {
-            // Try to resend this packet
-            if let futures::AsyncSink::NotReady(_) =
-                self.sink.start_send(rec.packet.clone())?
-            {
-                data.borrow_mut().send_queue.push(rec);
-                break;
-            } else {
-                if let futures::Async::Ready(()) = self.sink.poll_complete()? {
-                    self.is_sending = false;
-                } else {
-                    self.is_sending = true;
+        }
+
+        // Send the packets without borrowing the data
+        let mut due = due.into_iter();
+        let mut not_sent = Vec::new();
+        while let Some((rec, rto, should_send)) = due.next() {
+            let mut tries = rec.tries;
+            if should_send {
+                // Try to resend this packet
+                if let futures::AsyncSink::NotReady(_) =
+                    self.inner.start_send(rec.packet.clone())?
+                {
+                    // Try again later
+                    not_sent.push(rec);
+                    not_sent.extend(due.map(|(rec, _, _)| rec));
+                    break;
                 }
-                // Retransmission timeout
-                let rto = {
-                    // Get connection
+                self.is_sending = true;
+                tries += 1;
+            }
+
+            let rec = SendRecord {
+                next: now + rto,
+                tries,
+                ..rec
+            };
+            if should_send {
+                if rec.tries > 1 {
                     let mut data = data.borrow_mut();
                     if let Some(con) = data.connections.get_mut(&rec.packet.0) {
-                        // Double srtt on packet loss
-                        if rec.tries != 0 {
-                            con.srtt = con.srtt * 2;
-                        }
-                        con.srtt + con.srtt_dev * 4
-                    } else {
-                        // The connection is gone
-                        continue;
+                        let len = (rec.packet.1).0.len();
+                        con.stats.packet_resent(rec.p_type, len);
                     }
-                };
-                let next = now + rto;
-                let dur =
-                    next.naive_utc().signed_duration_since(now.naive_utc());
-                if dur > Duration::seconds(::TIMEOUT_SECONDS) {
-                    warn!(logger, "Max resend timeout exceeded"; "p_id" => rec.p_id);
-                    continue;
                 }
-                let rec = SendRecord {
-                    next,
-                    tries: rec.tries + 1,
-                    ..rec
-                };
-                if rec.tries != 1 {
-                    let to_s = if data.borrow().is_client { "S" } else { "C" };
-                    warn!(logger, "Resend"; "p_id" => rec.p_id, "tries" => rec.tries, "next" => %rec.next, "to" => to_s);
+                let to_s = if data.borrow().is_client { "S" } else { "C" };
+                warn!(logger, "Resend"; "p_id" => rec.p_id, "tries" => rec.tries, "next" => %rec.next, "to" => to_s);
+            }
+            next_wakeup = cmp::min(next_wakeup, rec.next);
+            not_sent.push(rec);
+        }
+
+        // Put the packets back into the queues
+        {
+            let mut data = data.borrow_mut();
+            for rec in not_sent {
+                // Drop them if the connection is gone
+                if let Some(con) = data.connections.get_mut(&rec.packet.0) {
+                    con.send_queue.push(rec);
                 }
-                data.borrow_mut().send_queue.push(rec);
             }
         }
+
+        self.poll_sending()?;
+        self.schedule(next_wakeup, now)?;
         Ok(futures::Async::NotReady)
     }
 }
+
+impl<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> Drop for ResendCore<CS, Inner> {
+    fn drop(&mut self) {
+        // Let the resend future finish
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> Sink for ResendSink<CS, Inner> {
+    type SinkItem = (SocketAddr, UdpPacket);
+    type SinkError = Error;
+
+    fn start_send(
+        &mut self,
+        item: Self::SinkItem,
+    ) -> futures::StartSend<Self::SinkItem, Self::SinkError> {
+        self.core.borrow_mut().start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
+        let mut core = self.core.borrow_mut();
+        let res = core.inner.poll_complete()?;
+        if let futures::Async::Ready(()) = res {
+            core.is_sending = false;
+        }
+        Ok(res)
+    }
+
+    fn close(&mut self) -> futures::Poll<(), Self::SinkError> {
+        self.core.borrow_mut().inner.close()
+    }
+}
+
+impl<
+    CS,
+    Inner: Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>,
+> Future for ResendFuture<CS, Inner> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        if let Some(core) = self.core.upgrade() {
+            let mut core = core.borrow_mut();
+            core.poll_resend()
+        } else {
+            // The sink is gone
+            Ok(futures::Async::Ready(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use futures::{self, Future, Sink, Stream};
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::unsync::{mpsc, oneshot};
+    use slog;
+    use tokio_core::reactor::Core;
+
+    use {Error, MAX_SEND_QUEUE_LEN};
+    use handler_data::{Connection, Data, DisconnectReason, SendRecord};
+    use identity::Identity;
+    use packet_codec::PacketCodecSink;
+    use packets::{self, *};
+    use resend::*;
+
+    type UdpSink =
+        Box<Sink<SinkItem = (SocketAddr, UdpPacket), SinkError = Error>>;
+
+    /// Counts how often a task was woken up.
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Counter {
+        fn get(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Notify for Counter {
+        fn notify(&self, _: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:9987".parse().unwrap()
+    }
+
+    /// Create a client with one connection and a resend sink, which sends the
+    /// udp packets into the returned channel.
+    fn setup(
+        core: &Core,
+    ) -> (
+        Rc<RefCell<Data<()>>>,
+        ResendSink<(), UdpSink>,
+        ResendFuture<(), UdpSink>,
+        mpsc::UnboundedReceiver<(SocketAddr, UdpPacket)>,
+    ) {
+        ::init().unwrap();
+        let (send, recv) = mpsc::unbounded();
+        let data = Data::with_transport(
+            "127.0.0.1:0".parse().unwrap(),
+            Identity::create().unwrap(),
+            core.handle(),
+            true,
+            slog::Logger::root(slog::Discard, o!()),
+            futures::stream::empty(),
+            send.clone().sink_map_err(|_| "Channel closed".into()),
+        );
+        data.borrow_mut()
+            .connections
+            .insert(addr(), Connection::new(()));
+        let inner: UdpSink =
+            Box::new(send.sink_map_err(|_| Error::from("Channel closed")));
+        let (sink, future) = ResendSink::new(data.clone(), inner).unwrap();
+        (data, sink, future, recv)
+    }
+
+    /// A command packet with the given id, as it is sent by a client.
+    fn command(p_id: u16) -> (SocketAddr, UdpPacket) {
+        let mut header = Header::new(PacketType::Command);
+        header.p_id = p_id;
+        header.c_id = Some(0);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        buf.extend_from_slice(b"test");
+        (addr(), UdpPacket(buf))
+    }
+
+    #[test]
+    fn stop_when_data_dropped() {
+        let core = Core::new().unwrap();
+        let (data, _sink, future, _recv) = setup(&core);
+        let counter = Arc::new(Counter::default());
+        let notify = NotifyHandle::from(counter.clone());
+        let mut future = executor::spawn(future);
+        assert_eq!(
+            future.poll_future_notify(&notify, 0).unwrap(),
+            futures::Async::NotReady
+        );
+
+        let notified = counter.get();
+        drop(data);
+        assert!(counter.get() > notified);
+        assert_eq!(
+            future.poll_future_notify(&notify, 0).unwrap(),
+            futures::Async::Ready(())
+        );
+    }
+
+    #[test]
+    fn stop_when_sink_dropped() {
+        let core = Core::new().unwrap();
+        let (_data, sink, future, _recv) = setup(&core);
+        let counter = Arc::new(Counter::default());
+        let notify = NotifyHandle::from(counter.clone());
+        let mut future = executor::spawn(future);
+        assert_eq!(
+            future.poll_future_notify(&notify, 0).unwrap(),
+            futures::Async::NotReady
+        );
+
+        let notified = counter.get();
+        drop(sink);
+        assert!(counter.get() > notified);
+        assert_eq!(
+            future.poll_future_notify(&notify, 0).unwrap(),
+            futures::Async::Ready(())
+        );
+    }
+
+    #[test]
+    fn send_queue_limit() {
+        let core = Core::new().unwrap();
+        let (data, sink, _future, recv) = setup(&core);
+        let counter = Arc::new(Counter::default());
+        let notify = NotifyHandle::from(counter.clone());
+        let mut sink = executor::spawn(sink);
+        for i in 0..MAX_SEND_QUEUE_LEN {
+            assert!(
+                sink.start_send_notify(command(i as u16), &notify, 0)
+                    .unwrap()
+                    .is_ready()
+            );
+        }
+        assert_eq!(
+            data.borrow().connections[&addr()].send_queue.len(),
+            MAX_SEND_QUEUE_LEN
+        );
+
+        // Wait until a packet is acknowledged
+        let next = MAX_SEND_QUEUE_LEN as u16;
+        assert!(
+            sink.start_send_notify(command(next), &notify, 0)
+                .unwrap()
+                .is_not_ready()
+        );
+        let notified = counter.get();
+        data.borrow_mut()
+            .connections
+            .get_mut(&addr())
+            .unwrap()
+            .ack_packet(PacketType::Command, 0);
+        assert!(counter.get() > notified);
+        assert!(
+            sink.start_send_notify(command(next), &notify, 0)
+                .unwrap()
+                .is_ready()
+        );
+        assert_eq!(
+            data.borrow().connections[&addr()].send_queue.len(),
+            MAX_SEND_QUEUE_LEN
+        );
+
+        // All accepted packets were sent
+        let sent = recv.take(MAX_SEND_QUEUE_LEN as u64 + 1)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(sent.len(), MAX_SEND_QUEUE_LEN + 1);
+    }
+
+    /// Let `update_states` run at the given time and return the new state of
+    /// the connection or `None` if it was removed.
+    fn state_at(
+        data: &Rc<RefCell<Data<()>>>,
+        now: DateTime<Utc>,
+    ) -> Option<ResendState> {
+        let mut data = data.borrow_mut();
+        update_states(&mut *data, now);
+        data.connections.get(&addr()).map(|con| con.resend_state)
+    }
+
+    /// Enter the state at a fixed time.
+    fn set_state(
+        data: &Rc<RefCell<Data<()>>>,
+        state: ResendState,
+        since: DateTime<Utc>,
+    ) {
+        let mut data = data.borrow_mut();
+        let con = data.connections.get_mut(&addr()).unwrap();
+        con.resend_state = state;
+        con.resend_state_since = since;
+    }
+
+    /// Register a listener, which gets the reason when the connection is
+    /// removed.
+    fn disconnect_listener(
+        data: &Rc<RefCell<Data<()>>>,
+    ) -> oneshot::Receiver<DisconnectReason> {
+        let (send, recv) = oneshot::channel();
+        data.borrow_mut()
+            .connections
+            .get_mut(&addr())
+            .unwrap()
+            .disconnect_listener
+            .push(send);
+        recv
+    }
+
+    #[test]
+    fn state_machine() {
+        let core = Core::new().unwrap();
+        let (data, _sink, _future, _recv) = setup(&core);
+        let config = TimeoutConfig {
+            dead_timeout: Duration::seconds(10),
+            ..TimeoutConfig::default()
+        };
+        data.borrow_mut().timeout_config = config.clone();
+        let ms = Duration::milliseconds(1);
+        let start = Utc.ymd(2018, 1, 1).and_hms(0, 0, 0);
+        let srtt = {
+            let mut data = data.borrow_mut();
+            let con = data.connections.get_mut(&addr()).unwrap();
+            con.last_received = start;
+            // A command, which is not acknowledged
+            con.send_queue.push(SendRecord {
+                sent: start,
+                next: start,
+                tries: 1,
+                p_type: PacketType::Command,
+                p_id: 0,
+                packet: command(0),
+            });
+            con.srtt
+        };
+
+        // Normal -> Stalling
+        set_state(&data, ResendState::Normal, start);
+        let stalling = start + srtt + config.normal_timeout;
+        assert_eq!(state_at(&data, stalling), Some(ResendState::Normal));
+        assert_eq!(
+            state_at(&data, stalling + ms),
+            Some(ResendState::Stalling)
+        );
+        assert!(data.borrow().connections[&addr()].resend_state.is_stalled());
+
+        // Stalling -> Dead
+        let stalling = stalling + ms;
+        set_state(&data, ResendState::Stalling, stalling);
+        let dead = stalling + config.stalling_timeout;
+        assert_eq!(state_at(&data, dead), Some(ResendState::Stalling));
+        assert_eq!(state_at(&data, dead + ms), Some(ResendState::Dead));
+        assert!(data.borrow().connections[&addr()].resend_state.is_stalled());
+
+        // Dead -> removed
+        let dead = dead + ms;
+        set_state(&data, ResendState::Dead, dead);
+        let listener = disconnect_listener(&data);
+        let closed = dead + config.dead_timeout;
+        assert_eq!(state_at(&data, closed - ms), Some(ResendState::Dead));
+        assert_eq!(state_at(&data, closed), None);
+        assert_eq!(listener.wait().unwrap(), DisconnectReason::TimedOut);
+    }
+
+    #[test]
+    fn connecting_timeout() {
+        let core = Core::new().unwrap();
+        let (data, _sink, _future, _recv) = setup(&core);
+        let config = data.borrow().timeout_config.clone();
+        let start = Utc.ymd(2018, 1, 1).and_hms(0, 0, 0);
+        set_state(&data, ResendState::Connecting, start);
+        let listener = disconnect_listener(&data);
+
+        let timeout = start + config.connecting_timeout;
+        assert_eq!(state_at(&data, timeout), Some(ResendState::Connecting));
+        assert_eq!(state_at(&data, timeout + Duration::milliseconds(1)), None);
+        match listener.wait().unwrap() {
+            DisconnectReason::HandshakeFailed(_) => {}
+            r => panic!("Unexpected disconnect reason {:?}", r),
+        }
+    }
+
+    #[test]
+    fn disconnect_timeout() {
+        let core = Core::new().unwrap();
+        let (data, _sink, _future, _recv) = setup(&core);
+        let config = data.borrow().timeout_config.clone();
+        let start = Utc.ymd(2018, 1, 1).and_hms(0, 0, 0);
+        set_state(&data, ResendState::Disconnecting, start);
+        let listener = disconnect_listener(&data);
+
+        let timeout = start + config.disconnect_timeout;
+        assert_eq!(
+            state_at(&data, timeout - Duration::milliseconds(1)),
+            Some(ResendState::Disconnecting)
+        );
+        assert_eq!(state_at(&data, timeout), None);
+        assert_eq!(listener.wait().unwrap(), DisconnectReason::Disconnected);
+    }
+
+    #[test]
+    fn retransmission_timeouts() {
+        let config = TimeoutConfig::default();
+        let mut con = Connection::new(());
+        let ms = Duration::milliseconds;
+        assert_eq!(
+            retransmission_timeout(&config, &mut con, PacketType::Init, 2),
+            config.connecting_interval
+        );
+        assert_eq!(
+            retransmission_timeout(&config, &mut con, PacketType::Command, 0),
+            config.connecting_interval
+        );
+
+        con.resend_state = ResendState::Normal;
+        con.srtt = ms(100);
+        con.srtt_dev = ms(10);
+        assert_eq!(
+            retransmission_timeout(&config, &mut con, PacketType::Command, 0),
+            ms(140)
+        );
+        // A lost packet doubles the round trip time
+        assert_eq!(
+            retransmission_timeout(&config, &mut con, PacketType::Command, 1),
+            ms(240)
+        );
+        assert_eq!(con.srtt, ms(200));
+        con.srtt = Duration::seconds(4);
+        assert_eq!(
+            retransmission_timeout(&config, &mut con, PacketType::Command, 1),
+            config.stalling_interval
+        );
+        assert_eq!(con.srtt, config.normal_timeout);
+
+        con.resend_state = ResendState::Stalling;
+        assert_eq!(
+            retransmission_timeout(&config, &mut con, PacketType::Command, 0),
+            config.stalling_interval
+        );
+    }
+
+    #[test]
+    fn drop_voice_when_stalled() {
+        let core = Core::new().unwrap();
+        let (data, _sink, _future, _recv) = setup(&core);
+        let (send, recv) = mpsc::unbounded();
+        let mut sink = PacketCodecSink::new(
+            data.clone(),
+            send.sink_map_err(|_| Error::from("Channel closed")),
+        );
+        let voice = || {
+            Packet::new(
+                Header::new(PacketType::Voice),
+                packets::Data::C2SVoice(C2SVoice::Voice {
+                    id: 0,
+                    codec_type: CodecType::OpusVoice,
+                    voice_data: vec![1, 2, 3],
+                }),
+            )
+        };
+        let now = Utc::now();
+
+        set_state(&data, ResendState::Normal, now);
+        sink = sink.send((addr(), voice())).wait().unwrap();
+        for &state in &[ResendState::Stalling, ResendState::Dead] {
+            set_state(&data, state, now);
+            sink = sink.send((addr(), voice())).wait().unwrap();
+        }
+        // Other packets are still sent
+        let ping =
+            Packet::new(Header::new(PacketType::Ping), packets::Data::Ping);
+        sink.send((addr(), ping)).wait().unwrap();
+
+        let sent = recv.take(2)
+            .map(|(_, packet)| {
+                Header::read(&true, &mut ::std::io::Cursor::new(&packet.0))
+                    .unwrap()
+                    .get_type()
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(sent, vec![PacketType::Voice, PacketType::Ping]);
+    }
+}
@@ -0,0 +1,902 @@
+//! A crypto backend, which is written in Rust only.
+//!
+//! The elliptic curve operations are not constant time.
+use std::fmt;
+
+use aes::Aes128;
+use aes::block_cipher_trait::BlockCipher;
+use aes::block_cipher_trait::generic_array::GenericArray;
+use num::{BigUint, One, Zero};
+use rand::{OsRng, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+use Result;
+use crypto::CryptoProvider;
+
+/// The crypto backend, which needs no C library.
+///
+/// It produces the same results as the [`TomcryptProvider`] and needs no
+/// initialization.
+///
+/// [`TomcryptProvider`]: ../crypto/struct.TomcryptProvider.html
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RustCryptoProvider;
+
+/// A key on the NIST P-256 curve.
+#[derive(Clone)]
+pub struct RustEccKey {
+    /// The affine coordinates of the public key.
+    public: (BigUint, BigUint),
+    private: Option<BigUint>,
+}
+
+impl RustEccKey {
+    pub fn has_private_key(&self) -> bool {
+        self.private.is_some()
+    }
+}
+
+impl fmt::Debug for RustEccKey {
+    /// The private key is not printed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RustEccKey")
+            .field("public", &self.public)
+            .field("has_private_key", &self.has_private_key())
+            .finish()
+    }
+}
+
+impl CryptoProvider for RustCryptoProvider {
+    type EccKey = RustEccKey;
+
+    fn generate_key(&self) -> Result<Self::EccKey> {
+        let curve = Curve::p256();
+        let mut rng = OsRng::new()?;
+        let mut buf = [0; 32];
+        loop {
+            rng.fill_bytes(&mut buf);
+            let private = BigUint::from_bytes_be(&buf);
+            if !private.is_zero() && private < curve.n {
+                return curve.create_key(private);
+            }
+        }
+    }
+
+    fn import_key(&self, data: &[u8]) -> Result<Self::EccKey> {
+        let curve = Curve::p256();
+        let mut r = DerReader::new(data).read_sequence()?;
+        let flags = r.read_bit_string()?;
+        let is_private = flags.first().map(|f| f & 0x80 != 0).unwrap_or(false);
+        if r.read_integer()? != BigUint::from(32u32) {
+            return Err("Unsupported key size".into());
+        }
+        let x = r.read_integer()?;
+        let y = r.read_integer()?;
+        let key = if is_private {
+            let private = r.read_integer()?;
+            if private.is_zero() || private >= curve.n {
+                return Err("Invalid private key".into());
+            }
+            RustEccKey {
+                public: (x, y),
+                private: Some(private),
+            }
+        } else {
+            RustEccKey {
+                public: (x, y),
+                private: None,
+            }
+        };
+        if !curve.is_on_curve(&key.public) {
+            return Err("Public key is not on the curve".into());
+        }
+        Ok(key)
+    }
+
+    fn export_public_key(&self, key: &mut Self::EccKey) -> Result<Vec<u8>> {
+        Ok(export_key(key, false))
+    }
+
+    fn export_private_key(&self, key: &mut Self::EccKey) -> Result<Vec<u8>> {
+        if key.private.is_none() {
+            return Err("The key contains no private key".into());
+        }
+        Ok(export_key(key, true))
+    }
+
+    fn shared_secret(
+        &self,
+        private_key: &mut Self::EccKey,
+        public_key: &mut Self::EccKey,
+    ) -> Result<Vec<u8>> {
+        let curve = Curve::p256();
+        let private = if let Some(ref private) = private_key.private {
+            private
+        } else {
+            return Err("The key contains no private key".into());
+        };
+        let point = curve.to_jacobian(&public_key.public);
+        match curve.to_affine(&curve.mul(&point, private)) {
+            Some((x, _)) => {
+                // Pad to the size of the field like libtomcrypt
+                let x = x.to_bytes_be();
+                let mut res = vec![0; 32 - x.len()];
+                res.extend_from_slice(&x);
+                Ok(res)
+            }
+            None => Err("Shared point is at infinity".into()),
+        }
+    }
+
+    fn sign(&self, key: &mut Self::EccKey, data: &[u8]) -> Result<Vec<u8>> {
+        let curve = Curve::p256();
+        let private = if let Some(ref private) = key.private {
+            private
+        } else {
+            return Err("The key contains no private key".into());
+        };
+        let (r, s) = curve.sign(private, &self.sha256(data))?;
+        let mut content = Vec::new();
+        write_der_integer(&mut content, &r);
+        write_der_integer(&mut content, &s);
+        let mut res = Vec::new();
+        write_der(&mut res, 0x30, &content);
+        Ok(res)
+    }
+
+    fn verify(
+        &self,
+        key: &mut Self::EccKey,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let curve = Curve::p256();
+        let mut r = DerReader::new(signature).read_sequence()?;
+        let sig_r = r.read_integer()?;
+        let sig_s = r.read_integer()?;
+        if curve.verify(&key.public, &self.sha256(data), &sig_r, &sig_s) {
+            Ok(())
+        } else {
+            Err("Wrong signature".into())
+        }
+    }
+
+    fn eax_encrypt(
+        &self,
+        key: &[u8; 16],
+        nonce: &[u8; 16],
+        header: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; 8]> {
+        let cipher = Aes128::new(GenericArray::from_slice(key));
+        let n = omac(&cipher, 0, nonce);
+        let h = omac(&cipher, 1, header);
+        ctr(&cipher, &n, data);
+        let c = omac(&cipher, 2, data);
+        Ok(eax_mac(&n, &h, &c))
+    }
+
+    fn eax_decrypt(
+        &self,
+        key: &[u8; 16],
+        nonce: &[u8; 16],
+        header: &[u8],
+        data: &mut [u8],
+        mac: &[u8; 8],
+    ) -> Result<()> {
+        let cipher = Aes128::new(GenericArray::from_slice(key));
+        let n = omac(&cipher, 0, nonce);
+        let h = omac(&cipher, 1, header);
+        let c = omac(&cipher, 2, data);
+        // Compare in constant time
+        let diff = eax_mac(&n, &h, &c)
+            .iter()
+            .zip(mac.iter())
+            .fold(0, |d, (a, b)| d | (a ^ b));
+        if diff != 0 {
+            return Err("Packet has wrong mac".into());
+        }
+        ctr(&cipher, &n, data);
+        Ok(())
+    }
+
+    fn sha1(&self, data: &[u8]) -> [u8; 20] {
+        let mut res = [0; 20];
+        res.copy_from_slice(&Sha1::digest(data));
+        res
+    }
+
+    fn sha256(&self, data: &[u8]) -> [u8; 32] {
+        let mut res = [0; 32];
+        res.copy_from_slice(&Sha256::digest(data));
+        res
+    }
+
+    fn sha512(&self, data: &[u8]) -> [u8; 64] {
+        let mut res = [0; 64];
+        res.copy_from_slice(&Sha512::digest(data));
+        res
+    }
+}
+
+/// The mac of EAX, which is truncated to 8 bytes.
+fn eax_mac(n: &[u8; 16], h: &[u8; 16], c: &[u8; 16]) -> [u8; 8] {
+    let mut mac = [0; 8];
+    for i in 0..8 {
+        mac[i] = n[i] ^ h[i] ^ c[i];
+    }
+    mac
+}
+
+fn encrypt_block(cipher: &Aes128, block: &mut [u8; 16]) {
+    let mut b = GenericArray::clone_from_slice(block);
+    cipher.encrypt_block(&mut b);
+    block.copy_from_slice(&b);
+}
+
+/// Multiply by `x` in GF(2^128), used to compute the CMAC subkeys.
+fn double(block: &[u8; 16]) -> [u8; 16] {
+    let mut res = [0; 16];
+    for i in 0..16 {
+        res[i] = block[i] << 1;
+        if i < 15 {
+            res[i] |= block[i + 1] >> 7;
+        }
+    }
+    if block[0] & 0x80 != 0 {
+        res[15] ^= 0x87;
+    }
+    res
+}
+
+/// The OMAC (CMAC) of `data`, prefixed with a block containing `tweak`, as
+/// it is used in EAX.
+fn omac(cipher: &Aes128, tweak: u8, data: &[u8]) -> [u8; 16] {
+    let mut l = [0; 16];
+    encrypt_block(cipher, &mut l);
+    let k1 = double(&l);
+    let k2 = double(&k1);
+
+    let mut state = [0; 16];
+    state[15] = tweak;
+    if data.is_empty() {
+        // The tweak is the last and complete block
+        for (s, k) in state.iter_mut().zip(k1.iter()) {
+            *s ^= *k;
+        }
+        encrypt_block(cipher, &mut state);
+        return state;
+    }
+    encrypt_block(cipher, &mut state);
+
+    let last_start = (data.len() - 1) / 16 * 16;
+    for block in data[..last_start].chunks(16) {
+        for (s, b) in state.iter_mut().zip(block) {
+            *s ^= *b;
+        }
+        encrypt_block(cipher, &mut state);
+    }
+    let last = &data[last_start..];
+    for (s, b) in state.iter_mut().zip(last) {
+        *s ^= *b;
+    }
+    if last.len() == 16 {
+        for (s, k) in state.iter_mut().zip(k1.iter()) {
+            *s ^= *k;
+        }
+    } else {
+        // Pad the incomplete block
+        state[last.len()] ^= 0x80;
+        for (s, k) in state.iter_mut().zip(k2.iter()) {
+            *s ^= *k;
+        }
+    }
+    encrypt_block(cipher, &mut state);
+    state
+}
+
+/// Encrypt or decrypt `data` in counter mode, the counter is a 128 bit big
+/// endian number.
+fn ctr(cipher: &Aes128, nonce: &[u8; 16], data: &mut [u8]) {
+    let mut counter = *nonce;
+    for chunk in data.chunks_mut(16) {
+        let mut key_stream = counter;
+        encrypt_block(cipher, &mut key_stream);
+        for (d, k) in chunk.iter_mut().zip(key_stream.iter()) {
+            *d ^= *k;
+        }
+        // Increment the counter
+        for c in counter.iter_mut().rev() {
+            *c = c.wrapping_add(1);
+            if *c != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Export a key in the DER format of libtomcrypt.
+fn export_key(key: &RustEccKey, private: bool) -> Vec<u8> {
+    let mut content = Vec::new();
+    // The flags contain one bit, which is set for private keys
+    write_der(
+        &mut content,
+        0x03,
+        &[7, if private { 0x80 } else { 0 }],
+    );
+    write_der_integer(&mut content, &BigUint::from(32u32));
+    write_der_integer(&mut content, &key.public.0);
+    write_der_integer(&mut content, &key.public.1);
+    if private {
+        write_der_integer(&mut content, key.private.as_ref().unwrap());
+    }
+    let mut res = Vec::new();
+    write_der(&mut res, 0x30, &content);
+    res
+}
+
+fn write_der(w: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    w.push(tag);
+    let len = content.len();
+    if len < 0x80 {
+        w.push(len as u8);
+    } else if len <= 0xff {
+        w.push(0x81);
+        w.push(len as u8);
+    } else {
+        w.push(0x82);
+        w.push((len >> 8) as u8);
+        w.push(len as u8);
+    }
+    w.extend_from_slice(content);
+}
+
+fn write_der_integer(w: &mut Vec<u8>, i: &BigUint) {
+    let mut bytes = i.to_bytes_be();
+    if bytes[0] & 0x80 != 0 {
+        // Integers are signed, so keep it positive
+        bytes.insert(0, 0);
+    }
+    write_der(w, 0x02, &bytes);
+}
+
+/// Reads the parts of a DER encoded key.
+struct DerReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Read a value with the given tag.
+    fn read(&mut self, tag: u8) -> Result<&'a [u8]> {
+        if self.data.len() < 2 || self.data[0] != tag {
+            return Err("Invalid key".into());
+        }
+        let (len, start) = match self.data[1] {
+            l if l < 0x80 => (l as usize, 2),
+            0x81 if self.data.len() >= 3 => (self.data[2] as usize, 3),
+            0x82 if self.data.len() >= 4 => {
+                (((self.data[2] as usize) << 8) | self.data[3] as usize, 4)
+            }
+            _ => return Err("Invalid key".into()),
+        };
+        if self.data.len() < start + len {
+            return Err("Key is too short".into());
+        }
+        let res = &self.data[start..start + len];
+        self.data = &self.data[start + len..];
+        Ok(res)
+    }
+
+    fn read_sequence(&mut self) -> Result<DerReader<'a>> {
+        Ok(DerReader::new(self.read(0x30)?))
+    }
+
+    /// Returns the bytes of the bit string without the number of unused bits.
+    fn read_bit_string(&mut self) -> Result<&'a [u8]> {
+        let data = self.read(0x03)?;
+        if data.is_empty() {
+            return Err("Invalid key".into());
+        }
+        Ok(&data[1..])
+    }
+
+    fn read_integer(&mut self) -> Result<BigUint> {
+        let data = self.read(0x02)?;
+        if data.first().map(|b| b & 0x80 != 0).unwrap_or(true) {
+            return Err("Invalid integer in key".into());
+        }
+        Ok(BigUint::from_bytes_be(data))
+    }
+}
+
+/// The inverse of `a` modulo the prime `m`, computed as `a^(m - 2)`.
+fn inverse(a: &BigUint, m: &BigUint) -> BigUint {
+    let exp = m - BigUint::from(2u32);
+    let mut res = BigUint::one();
+    for byte in exp.to_bytes_be() {
+        for i in (0..8).rev() {
+            res = (&res * &res) % m;
+            if byte & (1 << i) != 0 {
+                res = (&res * a) % m;
+            }
+        }
+    }
+    res
+}
+
+/// A point in jacobian coordinates, `(x, y, z)` stands for the affine point
+/// `(x / z², y / z³)`. `z = 0` is the point at infinity.
+type Point = (BigUint, BigUint, BigUint);
+
+/// The parameters of the NIST P-256 curve `y² = x³ - 3x + b`.
+struct Curve {
+    p: BigUint,
+    b: BigUint,
+    /// The order of the base point.
+    n: BigUint,
+    g: (BigUint, BigUint),
+}
+
+impl Curve {
+    fn p256() -> Self {
+        let parse = |s: &str| BigUint::parse_bytes(s.as_bytes(), 16).unwrap();
+        Self {
+            p: parse(
+                "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff",
+            ),
+            b: parse(
+                "5ac635d8aa3a93e7b3ebbd55769886bc651d06b0cc53b0f63bce3c3e27d2604b",
+            ),
+            n: parse(
+                "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551",
+            ),
+            g: (
+                parse(
+                    "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+                ),
+                parse(
+                    "4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5",
+                ),
+            ),
+        }
+    }
+
+    /// Create a key and compute the public key for a private key.
+    fn create_key(&self, private: BigUint) -> Result<RustEccKey> {
+        let g = self.to_jacobian(&self.g);
+        let public = match self.to_affine(&self.mul(&g, &private)) {
+            Some(p) => p,
+            None => return Err("Invalid private key".into()),
+        };
+        Ok(RustEccKey {
+            public,
+            private: Some(private),
+        })
+    }
+
+    fn is_on_curve(&self, &(ref x, ref y): &(BigUint, BigUint)) -> bool {
+        if *x >= self.p || *y >= self.p {
+            return false;
+        }
+        let x3 = self.mul_mod(&self.mul_mod(x, x), x);
+        let three_x = self.mul_mod(x, &BigUint::from(3u32));
+        let right = self.add_mod(&self.sub_mod(&x3, &three_x), &self.b);
+        self.mul_mod(y, y) == right
+    }
+
+    fn add_mod(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + b) % &self.p
+    }
+
+    fn sub_mod(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b) % &self.p
+    }
+
+    fn mul_mod(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a * b) % &self.p
+    }
+
+    /// The inverse of `a` modulo `p`.
+    fn inv_mod(&self, a: &BigUint) -> BigUint {
+        inverse(a, &self.p)
+    }
+
+    /// Create an ECDSA signature `(r, s)` for a hash.
+    fn sign(&self, private: &BigUint, hash: &[u8]) -> Result<(BigUint, BigUint)> {
+        let z = BigUint::from_bytes_be(hash) % &self.n;
+        let g = self.to_jacobian(&self.g);
+        let mut rng = OsRng::new()?;
+        let mut buf = [0; 32];
+        loop {
+            rng.fill_bytes(&mut buf);
+            let k = BigUint::from_bytes_be(&buf);
+            if k.is_zero() || k >= self.n {
+                continue;
+            }
+            let r = match self.to_affine(&self.mul(&g, &k)) {
+                Some((x, _)) => x % &self.n,
+                None => continue,
+            };
+            if r.is_zero() {
+                continue;
+            }
+            let s = (inverse(&k, &self.n) * ((&z + &r * private) % &self.n))
+                % &self.n;
+            if !s.is_zero() {
+                return Ok((r, s));
+            }
+        }
+    }
+
+    /// Check an ECDSA signature `(r, s)` of a hash.
+    fn verify(
+        &self,
+        public: &(BigUint, BigUint),
+        hash: &[u8],
+        r: &BigUint,
+        s: &BigUint,
+    ) -> bool {
+        if r.is_zero() || s.is_zero() || *r >= self.n || *s >= self.n {
+            return false;
+        }
+        let z = BigUint::from_bytes_be(hash) % &self.n;
+        let w = inverse(s, &self.n);
+        let u1 = (&z * &w) % &self.n;
+        let u2 = (r * &w) % &self.n;
+        let point = self.add(
+            &self.mul(&self.to_jacobian(&self.g), &u1),
+            &self.mul(&self.to_jacobian(public), &u2),
+        );
+        match self.to_affine(&point) {
+            Some((x, _)) => x % &self.n == *r,
+            None => false,
+        }
+    }
+
+    fn to_jacobian(&self, &(ref x, ref y): &(BigUint, BigUint)) -> Point {
+        (x.clone(), y.clone(), BigUint::one())
+    }
+
+    /// Returns `None` for the point at infinity.
+    fn to_affine(&self, &(ref x, ref y, ref z): &Point) -> Option<(BigUint, BigUint)> {
+        if z.is_zero() {
+            return None;
+        }
+        let z_inv = self.inv_mod(z);
+        let z_inv2 = self.mul_mod(&z_inv, &z_inv);
+        let z_inv3 = self.mul_mod(&z_inv2, &z_inv);
+        Some((self.mul_mod(x, &z_inv2), self.mul_mod(y, &z_inv3)))
+    }
+
+    fn double(&self, &(ref x, ref y, ref z): &Point) -> Point {
+        if z.is_zero() || y.is_zero() {
+            return (BigUint::one(), BigUint::one(), BigUint::zero());
+        }
+        let delta = self.mul_mod(z, z);
+        let gamma = self.mul_mod(y, y);
+        let beta = self.mul_mod(x, &gamma);
+        // alpha = 3 * (x - delta) * (x + delta), because a = -3
+        let alpha = self.mul_mod(
+            &BigUint::from(3u32),
+            &self.mul_mod(&self.sub_mod(x, &delta), &self.add_mod(x, &delta)),
+        );
+        let beta4 = self.mul_mod(&BigUint::from(4u32), &beta);
+        let x3 = self.sub_mod(
+            &self.mul_mod(&alpha, &alpha),
+            &self.add_mod(&beta4, &beta4),
+        );
+        let y_z = self.add_mod(y, z);
+        let z3 = self.sub_mod(
+            &self.sub_mod(&self.mul_mod(&y_z, &y_z), &gamma),
+            &delta,
+        );
+        let gamma2 = self.mul_mod(&gamma, &gamma);
+        let y3 = self.sub_mod(
+            &self.mul_mod(&alpha, &self.sub_mod(&beta4, &x3)),
+            &self.mul_mod(&BigUint::from(8u32), &gamma2),
+        );
+        (x3, y3, z3)
+    }
+
+    fn add(&self, p1: &Point, p2: &Point) -> Point {
+        if p1.2.is_zero() {
+            return p2.clone();
+        }
+        if p2.2.is_zero() {
+            return p1.clone();
+        }
+        let (ref x1, ref y1, ref z1) = *p1;
+        let (ref x2, ref y2, ref z2) = *p2;
+        let z1z1 = self.mul_mod(z1, z1);
+        let z2z2 = self.mul_mod(z2, z2);
+        let u1 = self.mul_mod(x1, &z2z2);
+        let u2 = self.mul_mod(x2, &z1z1);
+        let s1 = self.mul_mod(&self.mul_mod(y1, z2), &z2z2);
+        let s2 = self.mul_mod(&self.mul_mod(y2, z1), &z1z1);
+        let h = self.sub_mod(&u2, &u1);
+        let s_diff = self.sub_mod(&s2, &s1);
+        if h.is_zero() {
+            if s_diff.is_zero() {
+                return self.double(p1);
+            }
+            return (BigUint::one(), BigUint::one(), BigUint::zero());
+        }
+        let r = self.add_mod(&s_diff, &s_diff);
+        let h2 = self.add_mod(&h, &h);
+        let i = self.mul_mod(&h2, &h2);
+        let j = self.mul_mod(&h, &i);
+        let v = self.mul_mod(&u1, &i);
+        let x3 = self.sub_mod(
+            &self.sub_mod(&self.mul_mod(&r, &r), &j),
+            &self.add_mod(&v, &v),
+        );
+        let s1_j = self.mul_mod(&s1, &j);
+        let y3 = self.sub_mod(
+            &self.mul_mod(&r, &self.sub_mod(&v, &x3)),
+            &self.add_mod(&s1_j, &s1_j),
+        );
+        let z1_z2 = self.add_mod(z1, z2);
+        let z3 = self.mul_mod(
+            &self.sub_mod(
+                &self.sub_mod(&self.mul_mod(&z1_z2, &z1_z2), &z1z1),
+                &z2z2,
+            ),
+            &h,
+        );
+        (x3, y3, z3)
+    }
+
+    /// Multiply a point with a scalar.
+    fn mul(&self, point: &Point, k: &BigUint) -> Point {
+        let mut res = (BigUint::one(), BigUint::one(), BigUint::zero());
+        for byte in k.to_bytes_be() {
+            for i in (0..8).rev() {
+                res = self.double(&res);
+                if byte & (1 << i) != 0 {
+                    res = self.add(&res, point);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64;
+    use num::BigUint;
+
+    use crypto::CryptoProvider;
+    #[cfg(feature = "tomcrypt")]
+    use crypto::TomcryptProvider;
+    use rust_crypto::*;
+
+    const KEY: &str = "MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
+        k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITsC/50CIA8M5nm\
+        DBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI";
+
+    /// Parse a hex string, leading zero bytes are kept.
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2)
+            .map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn to_array(data: &[u8]) -> [u8; 16] {
+        let mut res = [0; 16];
+        res.copy_from_slice(data);
+        res
+    }
+
+    #[test]
+    fn test_eax_vectors() {
+        let c = RustCryptoProvider;
+        // Test vectors from the EAX paper, truncated to 8 byte macs
+        let key = to_array(&hex("233952DEE4D5ED5F9B9C6D6FF80FF478"));
+        let nonce = to_array(&hex("62EC67F9C3A4A407FCB2A8C49031A8B3"));
+        let mac = c.eax_encrypt(&key, &nonce, &hex("6BFB914FD07EAE6B"), &mut [])
+            .unwrap();
+        assert_eq!(&mac[..], &hex("E037830E8389F27B")[..]);
+
+        let key = to_array(&hex("91945D3F4DCBEE0BF45EF52255F095A4"));
+        let nonce = to_array(&hex("BECAF043B0A23D843194BA972C66DEBD"));
+        let header = hex("FA3BFD4806EB53FA");
+        let mut data = hex("F7FB");
+        let mac = c.eax_encrypt(&key, &nonce, &header, &mut data).unwrap();
+        assert_eq!(data, hex("19DD"));
+        assert_eq!(&mac[..], &hex("5C4C9331049D0BDA")[..]);
+        c.eax_decrypt(&key, &nonce, &header, &mut data, &mac).unwrap();
+        assert_eq!(data, hex("F7FB"));
+    }
+
+    #[test]
+    fn test_eax_fake_encrypt() {
+        // The same packet as in algorithms::tests::test_fake_encrypt
+        let c = RustCryptoProvider;
+        let key = to_array(b"c:\\windows\\syste");
+        let nonce = to_array(b"m\\firewall32.cpl");
+        let mut data = [0, 0];
+        let mac = c.eax_encrypt(&key, &nonce, &[0, 0, 0, 0, 6], &mut data)
+            .unwrap();
+        assert_eq!(data, [0xfe, 0x18]);
+        assert_eq!(mac, [0xa4, 0x7b, 0x47, 0x94, 0xdb, 0xa9, 0x6a, 0xc5]);
+    }
+
+    #[test]
+    fn test_eax_wrong_mac() {
+        let c = RustCryptoProvider;
+        let key = [1; 16];
+        let nonce = [2; 16];
+        let orig = (0..100).collect::<Vec<u8>>();
+        let mut data = orig.clone();
+        let mut mac = c.eax_encrypt(&key, &nonce, b"meta", &mut data).unwrap();
+        let mut dec = data.clone();
+        c.eax_decrypt(&key, &nonce, b"meta", &mut dec, &mac).unwrap();
+        assert_eq!(dec, orig);
+
+        mac[0] ^= 1;
+        assert!(c.eax_decrypt(&key, &nonce, b"meta", &mut data, &mac).is_err());
+    }
+
+    #[test]
+    fn test_import_export() {
+        let c = RustCryptoProvider;
+        let data = base64::decode(KEY).unwrap();
+        let mut key = c.import_key(&data).unwrap();
+        assert!(key.has_private_key());
+        assert_eq!(c.export_private_key(&mut key).unwrap(), data);
+
+        // The public key is the private key times the generator
+        let curve = Curve::p256();
+        let computed = curve.create_key(key.private.clone().unwrap()).unwrap();
+        assert!(computed.public == key.public);
+
+        let public = c.export_public_key(&mut key).unwrap();
+        let mut public_key = c.import_key(&public).unwrap();
+        assert!(!public_key.has_private_key());
+        assert!(c.export_private_key(&mut public_key).is_err());
+        assert_eq!(c.export_public_key(&mut public_key).unwrap(), public);
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let c = RustCryptoProvider;
+        let mut a = c.generate_key().unwrap();
+        let mut b = c.import_key(&base64::decode(KEY).unwrap()).unwrap();
+        let mut a_pub = c.import_key(&c.export_public_key(&mut a).unwrap())
+            .unwrap();
+        let mut b_pub = c.import_key(&c.export_public_key(&mut b).unwrap())
+            .unwrap();
+        let secret = c.shared_secret(&mut a, &mut b_pub).unwrap();
+        assert_eq!(secret, c.shared_secret(&mut b, &mut a_pub).unwrap());
+        assert!(c.shared_secret(&mut a_pub, &mut b_pub).is_err());
+    }
+
+    /// The private keys of a key pair, where the x coordinate of the shared
+    /// point starts with a zero byte.
+    fn leading_zero_keys() -> (RustEccKey, RustEccKey) {
+        let curve = Curve::p256();
+        let a = curve
+            .create_key(BigUint::from_bytes_be(&(1..33).collect::<Vec<u8>>()))
+            .unwrap();
+        let b = curve.create_key(BigUint::from(242u32)).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn test_shared_secret_leading_zero() {
+        let c = RustCryptoProvider;
+        let (mut a, mut b) = leading_zero_keys();
+        let mut a_pub = c.import_key(&c.export_public_key(&mut a).unwrap())
+            .unwrap();
+        let mut b_pub = c.import_key(&c.export_public_key(&mut b).unwrap())
+            .unwrap();
+        let expected = hex(
+            "007ce8a22340d59f095497c0f1eb5ebb03d9b846e21ba05f7c8793152aa3c202",
+        );
+        assert_eq!(c.shared_secret(&mut a, &mut b_pub).unwrap(), expected);
+        assert_eq!(c.shared_secret(&mut b, &mut a_pub).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_ecdsa() {
+        let c = RustCryptoProvider;
+        let mut key = c.import_key(&base64::decode(KEY).unwrap()).unwrap();
+        let signature = c.sign(&mut key, b"data").unwrap();
+        c.verify(&mut key, b"data", &signature).unwrap();
+        assert!(c.verify(&mut key, b"other data", &signature).is_err());
+
+        // The P-256 and SHA-256 test vector of RFC 6979 with the message
+        // "sample"
+        let curve = Curve::p256();
+        let mut key = curve.create_key(BigUint::from_bytes_be(&hex(
+            "C9AFA9D845BA75166B5C215767B1D6934E50C3DB36E89B127B8A622B120F6721",
+        ))).unwrap();
+        let mut content = Vec::new();
+        write_der_integer(&mut content, &BigUint::from_bytes_be(&hex(
+            "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716",
+        )));
+        write_der_integer(&mut content, &BigUint::from_bytes_be(&hex(
+            "F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8",
+        )));
+        let mut signature = Vec::new();
+        write_der(&mut signature, 0x30, &content);
+        c.verify(&mut key, b"sample", &signature).unwrap();
+    }
+
+    #[test]
+    fn test_sha() {
+        let c = RustCryptoProvider;
+        assert_eq!(
+            &c.sha1(b"password")[..],
+            &hex("5baa61e4c9b93f3f0682250b6cf8331b7ee68fd8")[..]
+        );
+        assert_eq!(
+            &c.sha256(b"abc")[..],
+            &hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")[..]
+        );
+    }
+
+    #[cfg(feature = "tomcrypt")]
+    #[test]
+    fn test_same_as_tomcrypt() {
+        ::init().unwrap();
+        let r = RustCryptoProvider;
+        let t = TomcryptProvider;
+        let key = base64::decode(KEY).unwrap();
+        let mut r_priv = r.import_key(&key).unwrap();
+        let mut t_priv = t.import_key(&key).unwrap();
+        let mut t_other = t.generate_key().unwrap();
+        let other = t.export_private_key(&mut t_other).unwrap();
+        let mut r_other = r.import_key(&other).unwrap();
+        assert_eq!(r.export_private_key(&mut r_other).unwrap(), other);
+        assert_eq!(
+            r.export_public_key(&mut r_other).unwrap(),
+            t.export_public_key(&mut t_other).unwrap()
+        );
+
+        let other_pub = t.export_public_key(&mut t_other).unwrap();
+        let mut r_pub = r.import_key(&other_pub).unwrap();
+        let mut t_pub = t.import_key(&other_pub).unwrap();
+        assert_eq!(
+            r.shared_secret(&mut r_priv, &mut r_pub).unwrap(),
+            t.shared_secret(&mut t_priv, &mut t_pub).unwrap()
+        );
+
+        // The secret keeps a leading zero byte
+        let (mut r_a, mut r_b) = leading_zero_keys();
+        let mut t_a = t.import_key(&r.export_private_key(&mut r_a).unwrap())
+            .unwrap();
+        let mut t_b_pub = t.import_key(&r.export_public_key(&mut r_b).unwrap())
+            .unwrap();
+        let mut r_b_pub = r.import_key(&r.export_public_key(&mut r_b).unwrap())
+            .unwrap();
+        assert_eq!(
+            r.shared_secret(&mut r_a, &mut r_b_pub).unwrap(),
+            t.shared_secret(&mut t_a, &mut t_b_pub).unwrap()
+        );
+
+        let mut r_data = (0..50).collect::<Vec<u8>>();
+        let mut t_data = r_data.clone();
+        let r_mac = r.eax_encrypt(&[3; 16], &[4; 16], b"h", &mut r_data)
+            .unwrap();
+        let t_mac = t.eax_encrypt(&[3; 16], &[4; 16], b"h", &mut t_data)
+            .unwrap();
+        assert_eq!(r_data, t_data);
+        assert_eq!(r_mac, t_mac);
+
+        // Signatures of one backend are accepted by the other
+        let t_sig = t.sign(&mut t_priv, b"data").unwrap();
+        let r_sig = r.sign(&mut r_priv, b"data").unwrap();
+        let mut r_key = r.import_key(&t.export_public_key(&mut t_priv).unwrap())
+            .unwrap();
+        r.verify(&mut r_key, b"data", &t_sig).unwrap();
+        t.verify(&mut t_priv, b"data", &r_sig).unwrap();
+        assert!(t.verify(&mut t_priv, b"other", &r_sig).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use chrono::{DateTime, Duration, Utc};
+
+use packets::PacketType;
+use ping::PingStats;
+
+/// The traffic classes, which are distinguished in the connection statistics.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketClass {
+    /// `Voice` and `VoiceWhisper` packets.
+    Speech,
+    /// `Ping` and `Pong` packets.
+    Keepalive,
+    /// All other packets, that are commands, acks and init packets.
+    Control,
+}
+
+impl PacketClass {
+    pub fn from_type(p_type: PacketType) -> Self {
+        match p_type {
+            PacketType::Voice | PacketType::VoiceWhisper => PacketClass::Speech,
+            PacketType::Ping | PacketType::Pong => PacketClass::Keepalive,
+            _ => PacketClass::Control,
+        }
+    }
+}
+
+/// A value for each [`PacketClass`].
+///
+/// [`PacketClass`]: enum.PacketClass.html
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClassStats<T> {
+    pub speech: T,
+    pub keepalive: T,
+    pub control: T,
+}
+
+impl<T> ClassStats<T> {
+    pub fn get(&self, class: PacketClass) -> &T {
+        match class {
+            PacketClass::Speech => &self.speech,
+            PacketClass::Keepalive => &self.keepalive,
+            PacketClass::Control => &self.control,
+        }
+    }
+
+    pub fn get_mut(&mut self, class: PacketClass) -> &mut T {
+        match class {
+            PacketClass::Speech => &mut self.speech,
+            PacketClass::Keepalive => &mut self.keepalive,
+            PacketClass::Control => &mut self.control,
+        }
+    }
+}
+
+impl ClassStats<TrafficCounter> {
+    /// The sum over all classes.
+    pub fn total(&self) -> TrafficCounter {
+        TrafficCounter {
+            packets: self.speech.packets + self.keepalive.packets
+                + self.control.packets,
+            bytes: self.speech.bytes + self.keepalive.bytes
+                + self.control.bytes,
+        }
+    }
+}
+
+/// Counts packets and their size in bytes, including the header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl TrafficCounter {
+    fn add(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// The traffic statistics of a connection.
+///
+/// Use [`Connection::stats_snapshot`] to get the current values together with
+/// the loss estimations.
+///
+/// [`Connection::stats_snapshot`]: ../handler_data/struct.Connection.html#method.stats_snapshot
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionStats {
+    /// Sent packets, resent packets are counted again.
+    pub sent: ClassStats<TrafficCounter>,
+    pub received: ClassStats<TrafficCounter>,
+    /// Packets from the other side, which were detected as lost.
+    ///
+    /// Skipped packet ids count as lost, as well as commands, which were
+    /// received again because the other side resent them.
+    pub received_lost: ClassStats<u64>,
+    /// How often one of our command packets was resent.
+    pub resent: u64,
+}
+
+impl ConnectionStats {
+    pub(crate) fn packet_sent(&mut self, p_type: PacketType, bytes: usize) {
+        self.sent.get_mut(PacketClass::from_type(p_type)).add(bytes);
+    }
+
+    pub(crate) fn packet_received(&mut self, p_type: PacketType, bytes: usize) {
+        self.received.get_mut(PacketClass::from_type(p_type)).add(bytes);
+    }
+
+    pub(crate) fn packets_lost(&mut self, p_type: PacketType, count: u64) {
+        *self.received_lost.get_mut(PacketClass::from_type(p_type)) += count;
+    }
+
+    pub(crate) fn packet_resent(&mut self, p_type: PacketType, bytes: usize) {
+        self.packet_sent(p_type, bytes);
+        self.resent += 1;
+    }
+}
+
+/// The statistics of a connection at a certain point of time.
+#[derive(Debug, Clone)]
+pub struct StatsSnapshot {
+    /// When the snapshot was taken.
+    pub time: DateTime<Utc>,
+    pub ping: Duration,
+    pub ping_deviation: Duration,
+    pub sent: ClassStats<TrafficCounter>,
+    pub received: ClassStats<TrafficCounter>,
+    /// The estimated fraction of lost packets, which were sent by the other
+    /// side, between `0` and `1`.
+    pub incoming_loss: ClassStats<f64>,
+    pub incoming_loss_total: f64,
+    /// The estimated fraction of our packets, which got lost, between `0`
+    /// and `1`.
+    ///
+    /// The loss of speech packets cannot be estimated and is always `0`.
+    pub outgoing_loss: ClassStats<f64>,
+    pub outgoing_loss_total: f64,
+}
+
+impl StatsSnapshot {
+    pub(crate) fn new(stats: &ConnectionStats, ping: &PingStats) -> Self {
+        let incoming_loss = |class| {
+            let lost = *stats.received_lost.get(class);
+            loss(lost, stats.received.get(class).packets + lost)
+        };
+        let incoming_lost = stats.received_lost.speech
+            + stats.received_lost.keepalive
+            + stats.received_lost.control;
+
+        // Resent packets are counted as sent, so count only the first tries
+        let control_sent =
+            stats.sent.control.packets.saturating_sub(stats.resent);
+        Self {
+            time: Utc::now(),
+            ping: ping.ping,
+            ping_deviation: ping.ping_deviation,
+            sent: stats.sent,
+            received: stats.received,
+            incoming_loss: ClassStats {
+                speech: incoming_loss(PacketClass::Speech),
+                keepalive: incoming_loss(PacketClass::Keepalive),
+                control: incoming_loss(PacketClass::Control),
+            },
+            incoming_loss_total: loss(
+                incoming_lost,
+                stats.received.total().packets + incoming_lost,
+            ),
+            outgoing_loss: ClassStats {
+                speech: 0.0,
+                keepalive: ping.loss(),
+                control: loss(stats.resent, control_sent),
+            },
+            outgoing_loss_total: loss(
+                stats.resent + ping.lost,
+                control_sent + ping.received + ping.lost,
+            ),
+        }
+    }
+}
+
+/// The number of packets, which were skipped when `id` is received while
+/// `next_id` was expected.
+pub(crate) fn skipped_packets(next_id: u16, id: u16) -> u64 {
+    u64::from(id.wrapping_sub(next_id))
+}
+
+/// The fraction of lost packets, capped at `1`.
+fn loss(lost: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        (lost as f64 / total as f64).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packets::PacketType;
+    use ping::PingStats;
+    use stats::*;
+
+    #[test]
+    fn loss_fraction() {
+        assert_eq!(loss(0, 0), 0.0);
+        assert_eq!(loss(1, 4), 0.25);
+        assert_eq!(loss(4, 4), 1.0);
+        // More resent than sent packets are possible
+        assert_eq!(loss(5, 2), 1.0);
+    }
+
+    #[test]
+    fn count_skipped_packets() {
+        assert_eq!(skipped_packets(5, 5), 0);
+        assert_eq!(skipped_packets(5, 8), 3);
+        // Skip over the generation change
+        assert_eq!(skipped_packets(65534, 1), 3);
+    }
+
+    #[test]
+    fn packet_classes() {
+        let speech = [PacketType::Voice, PacketType::VoiceWhisper];
+        let keepalive = [PacketType::Ping, PacketType::Pong];
+        let control = [
+            PacketType::Command,
+            PacketType::CommandLow,
+            PacketType::Ack,
+            PacketType::AckLow,
+            PacketType::Init,
+        ];
+        for &t in &speech {
+            assert_eq!(PacketClass::from_type(t), PacketClass::Speech);
+        }
+        for &t in &keepalive {
+            assert_eq!(PacketClass::from_type(t), PacketClass::Keepalive);
+        }
+        for &t in &control {
+            assert_eq!(PacketClass::from_type(t), PacketClass::Control);
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut stats = ConnectionStats::default();
+        for _ in 0..90 {
+            stats.packet_received(PacketType::Voice, 100);
+        }
+        stats.packets_lost(PacketType::VoiceWhisper, 10);
+        for _ in 0..4 {
+            stats.packet_received(PacketType::Ping, 20);
+        }
+        for _ in 0..6 {
+            stats.packet_received(PacketType::Ack, 30);
+        }
+
+        for _ in 0..8 {
+            stats.packet_sent(PacketType::Command, 50);
+        }
+        stats.packet_resent(PacketType::Command, 50);
+        stats.packet_resent(PacketType::Command, 50);
+        stats.packet_sent(PacketType::Voice, 200);
+
+        let mut ping = PingStats::default();
+        ping.sent = 5;
+        ping.received = 3;
+        ping.lost = 1;
+
+        let snap = StatsSnapshot::new(&stats, &ping);
+        assert_eq!(
+            snap.received.speech,
+            TrafficCounter { packets: 90, bytes: 9000 }
+        );
+        assert_eq!(
+            snap.received.total(),
+            TrafficCounter { packets: 100, bytes: 9260 }
+        );
+        assert_eq!(
+            snap.sent.control,
+            TrafficCounter { packets: 10, bytes: 500 }
+        );
+        assert_eq!(snap.sent.speech.packets, 1);
+
+        assert_eq!(snap.incoming_loss.speech, 0.1);
+        assert_eq!(snap.incoming_loss.keepalive, 0.0);
+        assert_eq!(snap.incoming_loss.control, 0.0);
+        assert_eq!(snap.incoming_loss_total, 10.0 / 110.0);
+
+        // Resent packets are not counted as first tries
+        assert_eq!(snap.outgoing_loss.control, 0.25);
+        assert_eq!(snap.outgoing_loss.keepalive, 0.25);
+        assert_eq!(snap.outgoing_loss.speech, 0.0);
+        assert_eq!(snap.outgoing_loss_total, 0.25);
+    }
+}
@@ -0,0 +1,328 @@
+//! Send voice packets.
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+
+use futures::{future, Future, Sink};
+
+use {packets, BoxFuture, Error, Result};
+use handler_data::Data;
+use packets::*;
+
+/// Sends encoded voice frames to a connection.
+///
+/// The sender assigns the voice packet ids, which are counted per connection,
+/// so multiple senders for the same connection share the ids. Voice packets
+/// are encrypted if the [`voice_encryption`] of the connection is set.
+///
+/// When the last frame was sent, [`stop`] has to be called to tell the
+/// receivers that we stopped talking.
+///
+/// # Example
+///
+/// ```no_run
+/// # extern crate futures;
+/// # extern crate tsproto;
+/// # use std::cell::RefCell;
+/// # use std::rc::Rc;
+/// # use futures::Future;
+/// # use tsproto::client::ClientData;
+/// # use tsproto::packets::CodecType;
+/// # use tsproto::voice::VoiceSender;
+/// # fn main() {}
+/// # fn send(client: Rc<RefCell<ClientData>>, frames: Vec<Vec<u8>>) {
+/// let mut sender = VoiceSender::new(client, "127.0.0.1:9987".parse().unwrap());
+/// for frame in frames {
+///     sender.send(CodecType::OpusMusic, frame).wait().unwrap();
+/// }
+/// sender.stop().wait().unwrap();
+/// # }
+/// ```
+///
+/// [`voice_encryption`]: ../handler_data/struct.ConnectedParams.html#structfield.voice_encryption
+/// [`stop`]: #method.stop
+pub struct VoiceSender<CS> {
+    data: Weak<RefCell<Data<CS>>>,
+    addr: SocketAddr,
+    /// The codec and the whisper targets of the last packet, `None` if we are
+    /// not talking.
+    talking: Option<(CodecType, Option<WhisperBuilder>)>,
+}
+
+impl<CS: 'static> VoiceSender<CS> {
+    pub fn new(data: Rc<RefCell<Data<CS>>>, addr: SocketAddr) -> Self {
+        Self {
+            data: Rc::downgrade(&data),
+            addr,
+            talking: None,
+        }
+    }
+
+    /// If a frame was sent and [`stop`] was not called yet.
+    ///
+    /// [`stop`]: #method.stop
+    pub fn is_talking(&self) -> bool {
+        self.talking.is_some()
+    }
+
+    /// Send a frame to the channel of our client.
+    ///
+    /// Fails if the connection does not exist, is not yet connected or if it
+    /// is stalling.
+    pub fn send(
+        &mut self,
+        codec_type: CodecType,
+        frame: Vec<u8>,
+    ) -> BoxFuture<(), Error> {
+        match self.create_packet(codec_type, None, frame) {
+            Ok((data, packet)) => {
+                self.talking = Some((codec_type, None));
+                self.send_packet(data, packet)
+            }
+            Err(error) => Box::new(future::err(error)),
+        }
+    }
+
+    /// Send a frame as whisper to the targets of the `whisper` builder.
+    pub fn whisper(
+        &mut self,
+        whisper: WhisperBuilder,
+        codec_type: CodecType,
+        frame: Vec<u8>,
+    ) -> BoxFuture<(), Error> {
+        match self.create_packet(codec_type, Some(whisper.clone()), frame) {
+            Ok((data, packet)) => {
+                self.talking = Some((codec_type, Some(whisper)));
+                self.send_packet(data, packet)
+            }
+            Err(error) => Box::new(future::err(error)),
+        }
+    }
+
+    /// Send an empty packet, which marks the end of the current transmission.
+    ///
+    /// Does nothing if we are not talking.
+    pub fn stop(&mut self) -> BoxFuture<(), Error> {
+        match self.talking.take() {
+            Some((codec_type, whisper)) => {
+                match self.create_packet(codec_type, whisper, Vec::new()) {
+                    Ok((data, packet)) => self.send_packet(data, packet),
+                    Err(error) => Box::new(future::err(error)),
+                }
+            }
+            None => Box::new(future::ok(())),
+        }
+    }
+
+    /// Create a voice packet with the next voice packet id.
+    ///
+    /// The id is only used up if the packet can be created.
+    fn create_packet(
+        &self,
+        codec_type: CodecType,
+        whisper: Option<WhisperBuilder>,
+        voice_data: Vec<u8>,
+    ) -> Result<(Rc<RefCell<Data<CS>>>, Packet)> {
+        let data = match self.data.upgrade() {
+            Some(data) => data,
+            None => bail!("Connection is gone"),
+        };
+        let packet = {
+            let mut data = data.borrow_mut();
+            let con = match data.connections.get_mut(&self.addr) {
+                Some(con) => con,
+                None => bail!("Connection does not exist"),
+            };
+            if con.resend_state.is_stalled() {
+                bail!("Voice is not sent while the connection is stalling");
+            }
+            let params = match con.params.as_mut() {
+                Some(params) => params,
+                None => bail!("The connection is not yet established"),
+            };
+            let id = params.voice_id;
+            let packet = if let Some(whisper) = whisper {
+                whisper.build(id, codec_type, voice_data)?
+            } else {
+                Packet::new(
+                    Header::new(PacketType::Voice),
+                    packets::Data::C2SVoice(C2SVoice::Voice {
+                        id,
+                        codec_type,
+                        voice_data,
+                    }),
+                )
+            };
+            params.voice_id = id.wrapping_add(1);
+            packet
+        };
+        Ok((data, packet))
+    }
+
+    fn send_packet(
+        &self,
+        data: Rc<RefCell<Data<CS>>>,
+        packet: Packet,
+    ) -> BoxFuture<(), Error> {
+        Box::new(
+            Data::get_packets(data)
+                .send((self.addr, packet))
+                .map(|_| ()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    use futures::{Future, Sink, Stream};
+    use futures::unsync::mpsc;
+    use slog;
+    use tokio_core::reactor::Core;
+
+    use crypto::{CryptoProvider, Provider};
+    use handler_data::{ConnectedParams, Connection, Data};
+    use identity::Identity;
+    use packets::{self, *};
+    use resend::ResendState;
+    use voice::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:9987".parse().unwrap()
+    }
+
+    /// Create a connection and return the stream of sent packets.
+    fn setup(
+        core: &Core,
+    ) -> (Rc<RefCell<Data<()>>>, mpsc::UnboundedReceiver<(SocketAddr, Packet)>)
+    {
+        ::init().unwrap();
+        let (udp_send, udp_recv) = mpsc::unbounded();
+        let data = Data::with_transport(
+            "127.0.0.1:0".parse().unwrap(),
+            Identity::create().unwrap(),
+            core.handle(),
+            true,
+            slog::Logger::root(slog::Discard, o!()),
+            udp_recv.map_err(|_| "Channel closed".into()),
+            udp_send.sink_map_err(|_| "Channel closed".into()),
+        );
+        let (send, recv) = mpsc::unbounded();
+        {
+            let mut data = data.borrow_mut();
+            data.packet_sink =
+                Some(Box::new(send.sink_map_err(|_| "Channel closed".into())));
+            let mut con = Connection::new(());
+            let key = Provider.generate_key().unwrap();
+            con.params = Some(ConnectedParams::new(key, vec![0; 20], [0; 8]));
+            data.connections.insert(addr(), con);
+        }
+        (data, recv)
+    }
+
+    #[test]
+    fn send_voice() {
+        let core = Core::new().unwrap();
+        let (data, recv) = setup(&core);
+        let mut sender = VoiceSender::new(data.clone(), addr());
+        assert!(sender.stop().wait().is_ok());
+
+        sender.send(CodecType::OpusVoice, vec![1]).wait().unwrap();
+        sender.send(CodecType::OpusVoice, vec![2]).wait().unwrap();
+        assert!(sender.is_talking());
+        sender.stop().wait().unwrap();
+        assert!(!sender.is_talking());
+
+        let whisper = WhisperBuilder::new().client(ClientId(2));
+        sender
+            .whisper(whisper, CodecType::OpusMusic, vec![3])
+            .wait()
+            .unwrap();
+        sender.stop().wait().unwrap();
+        drop(sender);
+        drop(data);
+
+        let packets = recv.collect().wait().unwrap();
+        let packets = packets
+            .into_iter()
+            .map(|(a, p)| {
+                assert_eq!(a, addr());
+                match p.data {
+                    packets::Data::C2SVoice(C2SVoice::Voice {
+                        id,
+                        voice_data,
+                        ..
+                    }) => (id, voice_data, false),
+                    packets::Data::C2SVoice(C2SVoice::VoiceWhisper {
+                        id,
+                        voice_data,
+                        ..
+                    }) => (id, voice_data, true),
+                    data => panic!("Unexpected packet {:?}", data),
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            packets,
+            vec![
+                (0, vec![1], false),
+                (1, vec![2], false),
+                (2, vec![], false),
+                (3, vec![3], true),
+                (4, vec![], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_whisper() {
+        let core = Core::new().unwrap();
+        let (data, recv) = setup(&core);
+        let mut sender = VoiceSender::new(data.clone(), addr());
+        // A group whisper cannot have other targets
+        let whisper = WhisperBuilder::new()
+            .client(ClientId(2))
+            .server_group(1, GroupWhisperTarget::AllChannels);
+        assert!(
+            sender
+                .whisper(whisper, CodecType::OpusVoice, vec![1])
+                .wait()
+                .is_err()
+        );
+        assert!(!sender.is_talking());
+
+        // The id was not used
+        sender.send(CodecType::OpusVoice, vec![2]).wait().unwrap();
+        drop(sender);
+        drop(data);
+        let packets = recv.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        match packets[0].1.data {
+            packets::Data::C2SVoice(C2SVoice::Voice { id, .. }) => {
+                assert_eq!(id, 0);
+            }
+            ref data => panic!("Unexpected packet {:?}", data),
+        }
+    }
+
+    #[test]
+    fn refuse_when_stalling() {
+        let core = Core::new().unwrap();
+        let (data, _recv) = setup(&core);
+        let mut sender = VoiceSender::new(data.clone(), addr());
+        data.borrow_mut()
+            .connections
+            .get_mut(&addr())
+            .unwrap()
+            .resend_state = ResendState::Stalling;
+        assert!(sender.send(CodecType::OpusVoice, vec![1]).wait().is_err());
+        assert!(!sender.is_talking());
+
+        let mut sender = VoiceSender::new(data, "127.0.0.1:1".parse().unwrap());
+        assert!(sender.send(CodecType::OpusVoice, vec![1]).wait().is_err());
+        assert!(!sender.is_talking());
+    }
+}